@@ -0,0 +1,39 @@
+use nalgebra_glm::{self as glm, Vec3};
+
+// World up axis. The simulation itself always works Y-up (waves on XZ, height on Y),
+// anything moving between simulation space and world space goes through to_world/to_sim.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UpAxis {
+    #[default]
+    Y,
+    Z,
+}
+
+impl UpAxis {
+    pub fn up(&self) -> Vec3 {
+        self.to_world(Vec3::y())
+    }
+
+    // Y-up -> Z-up is a +90 degree rotation around X, so handedness (and normals) survive
+    pub fn to_world(self, v: Vec3) -> Vec3 {
+        match self {
+            UpAxis::Y => v,
+            UpAxis::Z => glm::vec3(v.x, -v.z, v.y),
+        }
+    }
+
+    pub fn to_sim(self, v: Vec3) -> Vec3 {
+        match self {
+            UpAxis::Y => v,
+            UpAxis::Z => glm::vec3(v.x, v.z, -v.y),
+        }
+    }
+
+    // Matches upAxis in the water shaders
+    pub fn shader_id(&self) -> u32 {
+        match self {
+            UpAxis::Y => 0,
+            UpAxis::Z => 1,
+        }
+    }
+}
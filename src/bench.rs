@@ -0,0 +1,173 @@
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+use vulkano::{
+    descriptor_set::allocator::StandardDescriptorSetAllocator,
+    sampler::{Sampler, SamplerCreateInfo},
+};
+
+use crate::{
+    gpu_context::{ContextError, GpuContext, ValidationConfig},
+    simulation::{
+        DEFAULT_CASCADE_LENGTH_SCALES, DEFAULT_TEXTURE_SIZE, FIXED_TIMESTEP, NoiseSource,
+        SimTimings, Simulation,
+    },
+};
+
+const DEFAULT_STEPS: u32 = 500;
+// Same ocean every run, so numbers from different builds compare
+const BENCH_NOISE_SEED: u64 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BenchOptions {
+    pub texture_size: u32,
+    // The first this many of DEFAULT_CASCADE_LENGTH_SCALES
+    pub cascades: usize,
+    pub steps: u32,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        BenchOptions {
+            texture_size: DEFAULT_TEXTURE_SIZE,
+            cascades: DEFAULT_CASCADE_LENGTH_SCALES.len(),
+            steps: DEFAULT_STEPS,
+        }
+    }
+}
+
+impl BenchOptions {
+    // --size N, --cascades N and --steps N in any order, left out ones keep their default
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = BenchOptions::default();
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("{} needs a value", flag))?
+                .parse::<u32>()
+                .map_err(|e| format!("Bad value for {}: {}", flag, e))?;
+            match flag.as_str() {
+                "--size" => options.texture_size = value,
+                "--cascades" => options.cascades = value as usize,
+                "--steps" => options.steps = value,
+                _ => return Err(format!("Unknown option {}", flag)),
+            }
+        }
+
+        if !options.texture_size.is_power_of_two() || options.texture_size < 8 {
+            return Err(format!(
+                "--size has to be a power of two of at least 8, got {}",
+                options.texture_size
+            ));
+        }
+        if !(1..=DEFAULT_CASCADE_LENGTH_SCALES.len()).contains(&options.cascades) {
+            return Err(format!(
+                "--cascades has to be between 1 and {}, got {}",
+                DEFAULT_CASCADE_LENGTH_SCALES.len(),
+                options.cascades
+            ));
+        }
+        if options.steps == 0 {
+            return Err("--steps has to be at least 1".to_owned());
+        }
+        Ok(options)
+    }
+}
+
+// Runs options.steps simulation steps with no window and logs the wall time per step and the
+// GPU time per stage, totals and averages. The warmup step (pipeline creation, h0) isn't
+// counted. Every step waits for the GPU, like in the demo.
+pub fn run(options: BenchOptions, validation: ValidationConfig) -> Result<(), ContextError> {
+    let context = GpuContext::headless(validation)?;
+    let sampler = Sampler::new(
+        context.device.clone(),
+        SamplerCreateInfo::simple_repeat_linear(),
+    )
+    .unwrap();
+    let descriptor_set_allocator = StandardDescriptorSetAllocator::new(context.device.clone());
+    let mut simulation = Simulation::new(
+        &context,
+        &sampler,
+        NoiseSource::Cpu,
+        Some(BENCH_NOISE_SEED),
+        &DEFAULT_CASCADE_LENGTH_SCALES[..options.cascades],
+        options.texture_size,
+    );
+    if !simulation.has_compute() {
+        error!("Compute pipelines unavailable, nothing to benchmark");
+        return Ok(());
+    }
+    simulation.warmup(
+        &context.command_buffer_allocator,
+        &descriptor_set_allocator,
+        context.queue.clone(),
+    );
+
+    info!(
+        "Benchmarking {} steps, {}x{} maps, {} cascades",
+        options.steps, options.texture_size, options.texture_size, options.cascades
+    );
+    let mut wall = Duration::ZERO;
+    let mut gpu = SimTimings {
+        ifft: vec![0.0; options.cascades],
+        ..Default::default()
+    };
+    let mut timed_steps = 0;
+    for _ in 0..options.steps {
+        simulation.set_time(simulation.time + FIXED_TIMESTEP);
+        let start = Instant::now();
+        simulation.run(
+            &context.command_buffer_allocator,
+            &descriptor_set_allocator,
+            context.queue.clone(),
+        );
+        wall += start.elapsed();
+
+        if let Some(timings) = simulation.last_timings() {
+            gpu.init += timings.init;
+            gpu.time_spec += timings.time_spec;
+            for (total, ifft) in gpu.ifft.iter_mut().zip(&timings.ifft) {
+                *total += ifft;
+            }
+            gpu.merger += timings.merger;
+            timed_steps += 1;
+        }
+    }
+
+    let wall_ms = wall.as_secs_f32() * 1000.0;
+    info!(
+        "Wall: {:.1} ms total, {:.3} ms per step",
+        wall_ms,
+        wall_ms / options.steps as f32
+    );
+    if timed_steps == 0 {
+        warn!("The device can't time the simulation, no GPU timings");
+        return Ok(());
+    }
+    let steps = timed_steps as f32;
+    let ifft_total: f32 = gpu.ifft.iter().sum();
+    let gpu_total = gpu.init + gpu.time_spec + ifft_total + gpu.merger;
+    let mut rows = vec![
+        ("init".to_owned(), gpu.init),
+        ("time_spec".to_owned(), gpu.time_spec),
+        ("ifft".to_owned(), ifft_total),
+    ];
+    rows.extend(
+        gpu.ifft
+            .iter()
+            .enumerate()
+            .map(|(cascade, &total)| (format!("  cascade {}", cascade), total)),
+    );
+    rows.push(("merger".to_owned(), gpu.merger));
+    rows.push(("all".to_owned(), gpu_total));
+
+    info!(
+        "{:<12} {:>12} {:>13}",
+        "GPU stage", "total ms", "ms per step"
+    );
+    for (stage, total) in rows {
+        info!("{:<12} {:>12.1} {:>13.3}", stage, total, total / steps);
+    }
+    Ok(())
+}
@@ -1,16 +1,60 @@
-use nalgebra_glm::{self as glm, IVec3};
+use std::{fs, io, path::Path};
 
-const MOVE_SPEED: f32 = 5.0;
-const ROTATE_SPEED: f32 = 0.005;
+use nalgebra_glm as glm;
+
+use crate::axis::UpAxis;
+
+const DEFAULT_MOVE_SPEED: f32 = 5.0;
+pub const DEFAULT_ROTATE_SPEED: f32 = 0.005;
+// Longest step tick moves for, a stall (alt-tab, window drag) shouldn't fling the camera
+const MAX_TICK_DELTA: f32 = 0.1;
 const PITCH_LIMIT: f32 = std::f32::consts::FRAC_PI_2 - 0.01;
+const DEFAULT_ORBIT_RADIUS: f32 = 20.0;
+const MIN_ORBIT_RADIUS: f32 = 1.0;
+// Radians per second at full stick deflection
+const STICK_LOOK_SPEED: f32 = 2.0;
+// Fraction of the radius one scroll line zooms by
+const ZOOM_STEP: f32 = 0.1;
+// Clip planes in meters, the water shaders get them along with the matrices
+pub const DEFAULT_NEAR_PLANE: f32 = 0.1;
+pub const DEFAULT_FAR_PLANE: f32 = 1000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CameraMode {
+    Free,
+    // Looks at target from radius away, yaw/pitch move the camera around it and position
+    // follows from those. Movement keys are ignored.
+    Orbit { target: [f32; 3], radius: f32 },
+}
+
+// What save_to_path writes and load_from_path reads back, everything else about the camera
+// (mode, speeds, floor, clip planes) is runtime state that stays as it is
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CameraPose {
+    pub position: glm::Vec3,
+    pub yaw: f32,
+    pub pitch: f32,
+    pub fov: f32,
+}
 
 pub struct Camera {
     pub position: glm::Vec3,
     yaw: f32,
     pitch: f32,
     fov: f32,
+    near: f32,
+    far: f32,
+    mode: CameraMode,
+    // Meters per second, vertical is for up/down
+    move_speed: f32,
+    vertical_move_speed: f32,
+    // Radians per pixel of mouse movement
+    rotate_speed: f32,
+    // Lowest height along the up axis free flight can reach
+    floor: Option<f32>,
 
     aspect_ratio: f32,
+    up_axis: UpAxis,
     proj: glm::Mat4,
     view: glm::Mat4,
     is_dirty: bool,
@@ -23,33 +67,142 @@ impl Camera {
             yaw: 0.0,
             pitch: 0.0,
             fov: 70.0_f32.to_radians(),
+            near: DEFAULT_NEAR_PLANE,
+            far: DEFAULT_FAR_PLANE,
+            mode: CameraMode::Free,
+            move_speed: DEFAULT_MOVE_SPEED,
+            vertical_move_speed: DEFAULT_MOVE_SPEED,
+            rotate_speed: DEFAULT_ROTATE_SPEED,
+            floor: None,
             aspect_ratio: 0.0,
+            up_axis: UpAxis::default(),
             proj: glm::Mat4::identity(),
             view: glm::Mat4::identity(),
             is_dirty: true,
         }
     }
 
+    // Yaw/pitch are always relative to the simulation's Y-up frame, then moved into world space
+    pub fn set_up_axis(&mut self, up_axis: UpAxis) {
+        self.up_axis = up_axis;
+        self.is_dirty = true;
+    }
+
+    pub fn set_mode(&mut self, mode: CameraMode) {
+        self.mode = mode;
+        self.update_orbit_position();
+        self.is_dirty = true;
+    }
+
+    pub fn mode(&self) -> CameraMode {
+        self.mode
+    }
+
+    // Orbit around whatever is DEFAULT_ORBIT_RADIUS ahead, so the view doesn't jump.
+    // Going back to free flight keeps the current position.
+    pub fn toggle_orbit(&mut self) {
+        let mode = match self.mode {
+            CameraMode::Free => CameraMode::Orbit {
+                target: (self.position + self.forward() * DEFAULT_ORBIT_RADIUS).into(),
+                radius: DEFAULT_ORBIT_RADIUS,
+            },
+            CameraMode::Orbit { .. } => CameraMode::Free,
+        };
+        self.set_mode(mode);
+    }
+
+    // Scroll lines, positive moves closer. Only does something in orbit mode.
+    pub fn zoom(&mut self, lines: f32) {
+        if let CameraMode::Orbit { radius, .. } = &mut self.mode {
+            *radius = (*radius * (1.0 - lines * ZOOM_STEP)).max(MIN_ORBIT_RADIUS);
+            self.update_orbit_position();
+        }
+    }
+
+    fn update_orbit_position(&mut self) {
+        if let CameraMode::Orbit { target, radius } = self.mode {
+            self.position = glm::Vec3::from(target) - self.forward() * radius;
+            self.is_dirty = true;
+        }
+    }
+
+    // Far is kept past near, depth precision suffers the further apart they are
+    pub fn set_clip_planes(&mut self, near: f32, far: f32) {
+        self.near = near.max(f32::EPSILON);
+        self.far = far.max(self.near * 2.0);
+        self.is_dirty = true;
+    }
+
+    pub fn clip_planes(&self) -> (f32, f32) {
+        (self.near, self.far)
+    }
+
+    pub fn set_move_speed(&mut self, speed: f32) {
+        self.move_speed = speed.max(0.0);
+    }
+
+    pub fn move_speed(&self) -> f32 {
+        self.move_speed
+    }
+
+    pub fn set_vertical_move_speed(&mut self, speed: f32) {
+        self.vertical_move_speed = speed.max(0.0);
+    }
+
+    pub fn vertical_move_speed(&self) -> f32 {
+        self.vertical_move_speed
+    }
+
+    pub fn set_rotate_speed(&mut self, speed: f32) {
+        self.rotate_speed = speed;
+    }
+
+    // Keeps free flight above the given height (0 is the water plane), None flies anywhere.
+    // Moving into the floor slides along it since only the part going down is removed.
+    pub fn set_floor(&mut self, floor: Option<f32>) {
+        self.floor = floor;
+        self.clamp_to_floor();
+    }
+
+    pub fn floor(&self) -> Option<f32> {
+        self.floor
+    }
+
+    fn clamp_to_floor(&mut self) {
+        let Some(floor) = self.floor else {
+            return;
+        };
+        let up = self.up_axis.up();
+        let height = glm::dot(&self.position, &up);
+        if height < floor {
+            self.position += up * (floor - height);
+            self.is_dirty = true;
+        }
+    }
+
+    pub fn yaw(&self) -> f32 {
+        self.yaw
+    }
+
     pub fn forward(&self) -> glm::Vec3 {
-        glm::vec3(
+        self.up_axis.to_world(glm::vec3(
             self.yaw.cos() * self.pitch.cos(),
             self.pitch.sin(),
             self.yaw.sin() * self.pitch.cos(),
-        )
+        ))
     }
 
     pub fn right(&self) -> glm::Vec3 {
-        glm::vec3(
+        self.up_axis.to_world(glm::vec3(
             (self.yaw + std::f32::consts::FRAC_PI_2).cos(),
             0.0,
             (self.yaw + std::f32::consts::FRAC_PI_2).sin(),
-        )
+        ))
     }
 
-    /* Don't need this for now
     pub fn up(&self) -> glm::Vec3 {
         glm::cross(&self.right(), &self.forward())
-    }*/
+    }
 
     pub fn move_forward(&mut self, distance: f32) {
         self.position += self.forward() * distance;
@@ -70,7 +223,7 @@ impl Camera {
     }
 
     pub fn move_up(&mut self, distance: f32) {
-        self.position.y += distance;
+        self.position += self.up_axis.up() * distance;
         self.is_dirty = true;
     }
 
@@ -82,6 +235,7 @@ impl Camera {
         self.yaw += delta_yaw;
         self.pitch = (self.pitch + delta_pitch).clamp(-PITCH_LIMIT, PITCH_LIMIT);
         self.is_dirty = true;
+        self.update_orbit_position();
     }
 
     pub fn update_matrices(&mut self) {
@@ -89,50 +243,150 @@ impl Camera {
             return;
         }
 
-        // Perspective projection for Vulkan (reverse Z for better depth precision)
-        self.proj = glm::perspective_rh_zo(self.aspect_ratio, self.fov, 0.1, 1000.0);
+        // Vulkan clip space: depth 0..1 from near to far, and Y pointing down
+        self.proj = glm::perspective_rh_zo(self.aspect_ratio, self.fov, self.near, self.far);
         self.proj[(1, 1)] *= -1.0;
 
-        // View matrix: look from position in the direction we're facing
-        let target = self.position + self.forward();
-        self.view = glm::look_at_rh(&self.position, &target, &glm::Vec3::y());
+        // View matrix (world -> camera): rotation is the transposed camera basis,
+        // translation is the position moved into that basis and negated.
+        // Same result as glm::look_at_rh(position, position + forward, up_axis.up())
+        let forward = self.forward();
+        let right = self.right();
+        let up = self.up();
+        #[rustfmt::skip]
+        let view = glm::mat4(
+            right.x, right.y, right.z, -glm::dot(&right, &self.position),
+            up.x, up.y, up.z, -glm::dot(&up, &self.position),
+            -forward.x, -forward.y, -forward.z, glm::dot(&forward, &self.position),
+            0.0, 0.0, 0.0, 1.0,
+        );
+        self.view = view;
 
         self.is_dirty = false;
     }
 
+    // World point where the ray through ndc (Vulkan clip space, -1..1 with Y down) meets the
+    // flat water plane at height 0, None if it doesn't before the far plane
+    pub fn pick_water_plane(&self, ndc: glm::Vec2) -> Option<glm::Vec3> {
+        let inverse = (self.proj * self.view).try_inverse()?;
+        let unproject = |depth: f32| {
+            let p = inverse * glm::vec4(ndc.x, ndc.y, depth, 1.0);
+            p.xyz() / p.w
+        };
+        let near = unproject(0.0);
+        let ray = unproject(1.0) - near;
+
+        let up = self.up_axis.up();
+        let t = -glm::dot(&near, &up) / glm::dot(&ray, &up);
+        (0.0..=1.0).contains(&t).then(|| near + ray * t)
+    }
+
+    // Pose as plain "name values" lines, angles in radians. Aspect ratio and up axis aren't
+    // part of it, they come from the window and the app.
+    pub fn save_to_path(&self, path: &Path) -> io::Result<()> {
+        let text = format!(
+            "position {} {} {}\nyaw {}\npitch {}\nfov {}\n",
+            self.position.x, self.position.y, self.position.z, self.yaw, self.pitch, self.fov
+        );
+        fs::write(path, text)
+    }
+
+    // Orbit mode keeps orbiting, around whatever is in front of the new pose at the same radius
+    pub fn apply_pose(&mut self, pose: CameraPose) {
+        self.position = pose.position;
+        self.yaw = pose.yaw;
+        self.pitch = pose.pitch.clamp(-PITCH_LIMIT, PITCH_LIMIT);
+        self.fov = pose.fov;
+        let forward = self.forward();
+        if let CameraMode::Orbit { target, radius } = &mut self.mode {
+            *target = (pose.position + forward * *radius).into();
+        }
+        self.clamp_to_floor();
+        self.is_dirty = true;
+    }
+
+    pub fn load_from_path(path: &Path) -> io::Result<CameraPose> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let text = fs::read_to_string(path)?;
+
+        let (mut position, mut yaw, mut pitch, mut fov) = (None, None, None, None);
+        for line in text.lines() {
+            let mut parts = line.split_whitespace();
+            let Some(name) = parts.next() else {
+                continue;
+            };
+            let values = parts
+                .map(str::parse::<f32>)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| invalid(format!("Bad number in '{}': {}", line, e)))?;
+            match (name, values.as_slice()) {
+                ("position", &[x, y, z]) => position = Some(glm::vec3(x, y, z)),
+                ("yaw", &[value]) => yaw = Some(value),
+                ("pitch", &[value]) => pitch = Some(value),
+                ("fov", &[value]) => fov = Some(value),
+                _ => return Err(invalid(format!("Unexpected line '{}'", line))),
+            }
+        }
+
+        let (Some(position), Some(yaw), Some(pitch), Some(fov)) = (position, yaw, pitch, fov)
+        else {
+            return Err(invalid("Camera file is missing fields".to_owned()));
+        };
+        Ok(CameraPose {
+            position,
+            yaw,
+            pitch,
+            fov,
+        })
+    }
+
     pub fn on_mouse_dlta(&mut self, delta_x: f32, delta_y: f32) {
-        self.rotate(delta_x * ROTATE_SPEED, -delta_y * ROTATE_SPEED);
+        self.rotate(delta_x * self.rotate_speed, -delta_y * self.rotate_speed);
+    }
+
+    // look is the right stick after the deadzone, x = right, y = up
+    pub fn on_stick_look(&mut self, look: [f32; 2], delta_time: f32) {
+        if look == [0.0; 2] {
+            return;
+        }
+        let dt = delta_time.min(MAX_TICK_DELTA);
+        self.rotate(
+            look[0] * STICK_LOOK_SPEED * dt,
+            look[1] * STICK_LOOK_SPEED * dt,
+        );
     }
 
-    pub fn tick(&mut self, move_dir: &IVec3, delta_time: f32, aspect_ratio: f32) -> bool {
-        if move_dir.x == 0
-            && move_dir.y == 0
-            && move_dir.z == 0
-            && aspect_ratio != self.aspect_ratio
-        {
+    // move_dir components go from -1 to 1, keys give the ends and a stick anything in between
+    pub fn tick(&mut self, move_dir: &glm::Vec3, delta_time: f32, aspect_ratio: f32) -> bool {
+        if *move_dir == glm::Vec3::zeros() && aspect_ratio != self.aspect_ratio {
             return false;
         }
         self.aspect_ratio = aspect_ratio;
 
-        let dt = delta_time;
-        if move_dir.y > 0 {
-            self.move_forward(MOVE_SPEED * dt);
+        let dt = delta_time.min(MAX_TICK_DELTA);
+        if let CameraMode::Orbit { .. } = self.mode {
+            self.update_matrices();
+            return true;
         }
-        if move_dir.y < 0 {
-            self.move_backward(MOVE_SPEED * dt);
+        if move_dir.y > 0.0 {
+            self.move_forward(move_dir.y * self.move_speed * dt);
         }
-        if move_dir.x < 0 {
-            self.move_left(MOVE_SPEED * dt);
+        if move_dir.y < 0.0 {
+            self.move_backward(-move_dir.y * self.move_speed * dt);
         }
-        if move_dir.x > 0 {
-            self.move_right(MOVE_SPEED * dt);
+        if move_dir.x < 0.0 {
+            self.move_left(-move_dir.x * self.move_speed * dt);
         }
-        if move_dir.z > 0 {
-            self.move_up(MOVE_SPEED * dt);
+        if move_dir.x > 0.0 {
+            self.move_right(move_dir.x * self.move_speed * dt);
         }
-        if move_dir.z < 0 {
-            self.move_down(MOVE_SPEED * dt);
+        if move_dir.z > 0.0 {
+            self.move_up(move_dir.z * self.vertical_move_speed * dt);
         }
+        if move_dir.z < 0.0 {
+            self.move_down(-move_dir.z * self.vertical_move_speed * dt);
+        }
+        self.clamp_to_floor();
 
         self.update_matrices();
         true
@@ -142,28 +396,28 @@ impl Camera {
     pub fn tick(&mut self, input: &mut InputManager, delta_time: f64) {
         let dt = delta_time as f32;
 
-        if input.is_action_active(&super::input::Action::MoveForward) {
-            self.move_forward(MOVE_SPEED * dt);
+        if input.is_action_active(&super::input::Action::Forward) {
+            self.move_forward(self.move_speed * dt);
         }
-        if input.is_action_active(&super::input::Action::MoveBackward) {
-            self.move_backward(MOVE_SPEED * dt);
+        if input.is_action_active(&super::input::Action::Backward) {
+            self.move_backward(self.move_speed * dt);
         }
-        if input.is_action_active(&super::input::Action::MoveLeft) {
-            self.move_left(MOVE_SPEED * dt);
+        if input.is_action_active(&super::input::Action::Left) {
+            self.move_left(self.move_speed * dt);
         }
-        if input.is_action_active(&super::input::Action::MoveRight) {
-            self.move_right(MOVE_SPEED * dt);
+        if input.is_action_active(&super::input::Action::Right) {
+            self.move_right(self.move_speed * dt);
         }
-        if input.is_action_active(&super::input::Action::MoveUp) {
-            self.move_up(MOVE_SPEED * dt);
+        if input.is_action_active(&super::input::Action::Up) {
+            self.move_up(self.vertical_move_speed * dt);
         }
-        if input.is_action_active(&super::input::Action::MoveDown) {
-            self.move_down(MOVE_SPEED * dt);
+        if input.is_action_active(&super::input::Action::Down) {
+            self.move_down(self.vertical_move_speed * dt);
         }
 
         let (delta_x, delta_y) = input.take_mouse_delta();
         if delta_x != 0.0 || delta_y != 0.0 {
-            self.rotate(delta_x as f32 * ROTATE_SPEED, delta_y as f32 * ROTATE_SPEED);
+            self.rotate(delta_x as f32 * self.rotate_speed, delta_y as f32 * self.rotate_speed);
         }
     }*/
 }
@@ -178,4 +432,96 @@ impl Camera {
         //glm::transpose(&self.proj).into()
         self.proj.into()
     }
+
+    pub fn view_projection_raw(&self) -> [[f32; 4]; 4] {
+        (self.proj * self.view).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn looking_along_x(near: f32, far: f32) -> Camera {
+        let mut camera = Camera::new(glm::Vec3::zeros());
+        camera.apply_pose(CameraPose {
+            position: glm::Vec3::zeros(),
+            yaw: 0.0,
+            pitch: 0.0,
+            fov: 90.0_f32.to_radians(),
+        });
+        camera.set_clip_planes(near, far);
+        camera.aspect_ratio = 2.0;
+        camera.update_matrices();
+        camera
+    }
+
+    fn project(camera: &Camera, point: glm::Vec3) -> glm::Vec3 {
+        let clip = glm::Mat4::from(camera.view_projection_raw()) * point.push(1.0);
+        clip.xyz() / clip.w
+    }
+
+    #[test]
+    fn projection_maps_into_vulkan_ndc() {
+        let (near, far) = (0.5, 100.0);
+        let camera = looking_along_x(near, far);
+        let close = |a: glm::Vec3, b: glm::Vec3| (a - b).abs().max() < 1e-5;
+
+        // Straight ahead lands in the middle, depth grows from 0 at near to 1 at far
+        let depth = far * (10.0 - near) / (10.0 * (far - near));
+        assert!(close(
+            project(&camera, glm::vec3(10.0, 0.0, 0.0)),
+            glm::vec3(0.0, 0.0, depth)
+        ));
+        assert!(close(
+            project(&camera, glm::vec3(near, 0.0, 0.0)),
+            glm::vec3(0.0, 0.0, 0.0)
+        ));
+        assert!(close(
+            project(&camera, glm::vec3(far, 0.0, 0.0)),
+            glm::vec3(0.0, 0.0, 1.0)
+        ));
+
+        // Right of the camera is +x, squeezed by the aspect ratio. Up is -y in Vulkan.
+        let right = project(&camera, glm::vec3(10.0, 0.0, 5.0));
+        assert!((right.x - 0.25).abs() < 1e-5 && right.y.abs() < 1e-5);
+        let above = project(&camera, glm::vec3(10.0, 5.0, 0.0));
+        assert!(above.x.abs() < 1e-5 && (above.y + 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn view_matches_glm_look_at() {
+        let poses = [
+            (glm::vec3(0.0, 0.0, 0.0), 0.0, 0.0),
+            (glm::vec3(3.0, 2.0, -7.0), 1.2, -0.4),
+            (glm::vec3(-40.0, 15.0, 8.0), -2.5, 1.1),
+        ];
+        for up_axis in [UpAxis::Y, UpAxis::Z] {
+            for (position, yaw, pitch) in poses {
+                let mut camera = Camera::new(position);
+                camera.set_up_axis(up_axis);
+                camera.apply_pose(CameraPose {
+                    position,
+                    yaw,
+                    pitch,
+                    fov: 1.0,
+                });
+                camera.aspect_ratio = 1.5;
+                camera.update_matrices();
+
+                let target = position + camera.forward();
+                let reference = glm::look_at_rh(&position, &target, &up_axis.up());
+                let view = glm::Mat4::from(camera.view_matrix_raw());
+                // Looser than the 1e-5 of the projection checks, the translation column is tens
+                // of meters and the bound covers the whole matrix
+                assert!(
+                    (view - reference).abs().max() < 1e-4,
+                    "{:?} {} {}",
+                    up_axis,
+                    yaw,
+                    pitch
+                );
+            }
+        }
+    }
 }
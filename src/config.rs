@@ -0,0 +1,438 @@
+use std::{collections::BTreeMap, fmt, fs, io, path::Path, time::SystemTime};
+
+use serde::Deserialize;
+use winit::event::VirtualKeyCode;
+
+use crate::{
+    axis::UpAxis,
+    camera::{Camera, DEFAULT_FAR_PLANE, DEFAULT_NEAR_PLANE, DEFAULT_ROTATE_SPEED},
+    input::{self, Action, InputManager},
+    renderer::{
+        DEFAULT_CASCADE_FOAM_WEIGHTS, DEFAULT_CLEAR_COLOR, DEFAULT_DEEP_WATER_DISTANCE,
+        DEFAULT_DISTANCE_FOG, DEFAULT_DITHER_STRENGTH, DEFAULT_FOAM_BIAS, DEFAULT_FOAM_GRADIENT,
+        DEFAULT_FPS_SMOOTHING, DEFAULT_UNDERWATER_FOG, DEFAULT_WATER_COLOR, PresentPreference,
+        RenderMode, Renderer,
+    },
+    simulation::{DEFAULT_TEXTURE_SIZE, Disturbance, NoiseSource, SpectrumParams, SwellParams},
+    water::DEFAULT_WATER_RINGS,
+};
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    // Simulation::new needs a power of two of at least 8
+    TextureSize(u32),
+    // A [keys] entry with an unknown key or action
+    Binding(String, String),
+    // [render] present isn't one of PresentPreference's names
+    Present(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "{}", e),
+            ConfigError::Parse(e) => write!(f, "{}", e),
+            ConfigError::TextureSize(size) => {
+                write!(
+                    f,
+                    "texture_size {} isn't a power of two of at least 8",
+                    size
+                )
+            }
+            ConfigError::Binding(key, action) => {
+                write!(f, "can't bind key '{}' to '{}'", key, action)
+            }
+            ConfigError::Present(name) => {
+                write!(
+                    f,
+                    "unknown present mode '{}', use vsync, mailbox or immediate",
+                    name
+                )
+            }
+        }
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> Self {
+        ConfigError::Io(e)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(e: toml::de::Error) -> Self {
+        ConfigError::Parse(e)
+    }
+}
+
+// Ocean and look the demo starts with, read from a TOML file with [simulation], [material],
+// [render], [camera] and [keys] tables. Anything left out keeps the built in default, unknown
+// keys are an error so typos don't go unnoticed.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub simulation: SimulationConfig,
+    pub material: MaterialConfig,
+    pub render: RenderConfig,
+    pub camera: CameraConfig,
+    // Key name to action name, e.g. Up = "move_forward". "none" unbinds the key, keys not
+    // listed keep their default. Removing an entry only takes effect after a restart.
+    pub keys: BTreeMap<String, String>,
+}
+
+// Units as in SpectrumParams, except the wind angle which is in degrees
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimulationConfig {
+    // Only read at startup, the maps can't be resized while running
+    pub texture_size: u32,
+    // Generate the initial noise in a compute shader instead of on the CPU, startup only too
+    pub gpu_noise: bool,
+    // Starts every run from the same ocean, handy for comparing tuning changes. Left out
+    // draws a fresh one. See Simulation::set_noise_seed.
+    pub noise_seed: Option<u64>,
+    pub wind_speed: f32,
+    pub fetch: f32,
+    pub wind_angle: f32,
+    pub swell: f32,
+    pub depth: f32,
+    pub choppiness: f32,
+    // Horizontal displacement cap in meters, 0 leaves it unclamped
+    pub max_displacement: f32,
+    // See Simulation::set_crest_stretch, 1 leaves the spectrum as is
+    pub crest_stretch: f32,
+    // Shape of the crossing sea, see SwellParams. Its angle and strength are on the keyboard.
+    pub secondary_wind_speed: f32,
+    pub secondary_fetch: f32,
+    pub secondary_swell: f32,
+    pub secondary_gamma: f32,
+    pub secondary_short_waves_fade: f32,
+    pub foam_threshold: f32,
+    pub foam_injection: f32,
+    pub foam_decay: f32,
+    // Per cascade, largest patch first. Thresholds replace foam_threshold for the first
+    // cascades, weights are Renderer::set_cascade_foam_weight. Cascades past the end of either
+    // list keep the default.
+    pub cascade_foam_thresholds: Vec<f32>,
+    pub cascade_foam_weights: Vec<f32>,
+    // Simulation meters per world meter, see Renderer::set_world_uv_scale
+    pub world_uv_scale: f32,
+    // Fixed pushes on the surface (moored boats, a fountain) as [x, z, strength, radius] in
+    // simulation meters, see Disturbance
+    pub disturbances: Vec<[f32; 4]>,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        let spectrum = SpectrumParams::default();
+        let secondary = SwellParams::default();
+        SimulationConfig {
+            texture_size: DEFAULT_TEXTURE_SIZE,
+            gpu_noise: false,
+            noise_seed: None,
+            wind_speed: spectrum.wind_speed,
+            fetch: spectrum.fetch,
+            wind_angle: spectrum.angle.to_degrees(),
+            swell: spectrum.swell,
+            depth: spectrum.depth,
+            choppiness: 1.0,
+            max_displacement: 0.0,
+            crest_stretch: 1.0,
+            secondary_wind_speed: secondary.wind_speed,
+            secondary_fetch: secondary.fetch,
+            secondary_swell: secondary.swell,
+            secondary_gamma: secondary.gamma,
+            secondary_short_waves_fade: secondary.short_waves_fade,
+            foam_threshold: 0.0,
+            foam_injection: 0.0,
+            foam_decay: 1.0,
+            cascade_foam_thresholds: Vec::new(),
+            cascade_foam_weights: Vec::new(),
+            world_uv_scale: 1.0,
+            disturbances: Vec::new(),
+        }
+    }
+}
+
+impl SimulationConfig {
+    pub fn noise_source(&self) -> NoiseSource {
+        if self.gpu_noise {
+            NoiseSource::Gpu
+        } else {
+            NoiseSource::Cpu
+        }
+    }
+}
+
+// Colors are linear RGB
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaterialConfig {
+    pub base_water_color: [f32; 3],
+    pub deep_water_color: [f32; 3],
+    pub deep_water_distance: f32,
+    pub sun_direction: [f32; 3],
+    pub sun_color: [f32; 3],
+    pub sun_intensity: f32,
+    pub glitter_strength: f32,
+    pub shore_foam_width: f32,
+    pub shore_foam_color: [f32; 3],
+    // [[r, g, b], amount] pairs, see Renderer::set_foam_gradient
+    pub foam_gradient: Vec<([f32; 3], f32)>,
+    pub exposure: f32,
+    // Jacobian below which foam shows, see Renderer::set_foam_bias
+    pub foam_bias: f32,
+    // Linear RGBA behind the skybox, forward mode only
+    pub clear_color: [f32; 4],
+    // Murk seen with the camera below the surface, density per meter
+    pub underwater_fog_color: [f32; 3],
+    pub underwater_fog_density: f32,
+    // Fog towards the horizon, best matched to the sky there. Density 0 turns it off.
+    pub distance_fog_color: [f32; 3],
+    pub distance_fog_density: f32,
+}
+
+impl Default for MaterialConfig {
+    fn default() -> Self {
+        let [r, g, b, _] = DEFAULT_WATER_COLOR;
+        MaterialConfig {
+            base_water_color: [r, g, b],
+            deep_water_color: [r, g, b],
+            deep_water_distance: DEFAULT_DEEP_WATER_DISTANCE,
+            sun_direction: [0.0, 1.0, 0.0],
+            sun_color: [1.0, 1.0, 1.0],
+            sun_intensity: 1.0,
+            glitter_strength: 0.5,
+            shore_foam_width: 0.0,
+            shore_foam_color: [1.0, 1.0, 1.0],
+            foam_gradient: DEFAULT_FOAM_GRADIENT.to_vec(),
+            exposure: 1.0,
+            foam_bias: DEFAULT_FOAM_BIAS,
+            clear_color: DEFAULT_CLEAR_COLOR,
+            underwater_fog_color: [
+                DEFAULT_UNDERWATER_FOG[0],
+                DEFAULT_UNDERWATER_FOG[1],
+                DEFAULT_UNDERWATER_FOG[2],
+            ],
+            underwater_fog_density: DEFAULT_UNDERWATER_FOG[3],
+            distance_fog_color: [
+                DEFAULT_DISTANCE_FOG[0],
+                DEFAULT_DISTANCE_FOG[1],
+                DEFAULT_DISTANCE_FOG[2],
+            ],
+            distance_fog_density: DEFAULT_DISTANCE_FOG[3],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RenderConfig {
+    // Fraction of the window resolution the scene is drawn at, see Renderer::set_render_scale
+    pub render_scale: f32,
+    // Write the G-buffer instead of the lit water, see RenderMode
+    pub deferred: bool,
+    // Ordered dither against banding in 8 bit steps, 0 turns it off. Forward mode only.
+    pub dither_strength: f32,
+    // Weight of the newest frame in the FPS shown by the stats overlay
+    pub fps_smoothing: f32,
+    // Height of a flat seabed along the up axis, left out for open ocean. See
+    // Renderer::set_seabed.
+    pub seabed_height: Option<f32>,
+    // FPS adaptive quality holds by turning the other render settings down, left out keeps
+    // them as they are. See Renderer::set_adaptive_quality.
+    pub target_fps: Option<f32>,
+    // "vsync", "mailbox" or "immediate", see PresentPreference. Only read at startup.
+    pub present: String,
+    // World Z up instead of Y up, see UpAxis. Only read at startup, the water mesh is built
+    // for it.
+    pub z_up: bool,
+    // Rings of water tiles around the one under the camera, see Water::tiles_around
+    pub water_rings: u32,
+}
+
+impl Default for RenderConfig {
+    fn default() -> Self {
+        RenderConfig {
+            render_scale: 1.0,
+            deferred: false,
+            dither_strength: DEFAULT_DITHER_STRENGTH,
+            fps_smoothing: DEFAULT_FPS_SMOOTHING,
+            seabed_height: None,
+            target_fps: None,
+            present: "mailbox".to_string(),
+            z_up: false,
+            water_rings: DEFAULT_WATER_RINGS,
+        }
+    }
+}
+
+impl RenderConfig {
+    // Checked by load
+    pub fn present_preference(&self) -> PresentPreference {
+        PresentPreference::from_name(&self.present).unwrap()
+    }
+
+    pub fn up_axis(&self) -> UpAxis {
+        if self.z_up { UpAxis::Z } else { UpAxis::Y }
+    }
+}
+
+// Clip planes in meters. Move speeds stay on the keyboard.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CameraConfig {
+    pub near_plane: f32,
+    pub far_plane: f32,
+    // Radians per pixel of mouse movement, negative inverts
+    pub rotate_speed: f32,
+}
+
+impl Default for CameraConfig {
+    fn default() -> Self {
+        CameraConfig {
+            near_plane: DEFAULT_NEAR_PLANE,
+            far_plane: DEFAULT_FAR_PLANE,
+            rotate_speed: DEFAULT_ROTATE_SPEED,
+        }
+    }
+}
+
+impl Config {
+    // A missing file is the same as an empty one, every value at its default
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let config: Config = toml::from_str(&text)?;
+
+        let texture_size = config.simulation.texture_size;
+        if !texture_size.is_power_of_two() || texture_size < 8 {
+            return Err(ConfigError::TextureSize(texture_size));
+        }
+        if PresentPreference::from_name(&config.render.present).is_none() {
+            return Err(ConfigError::Present(config.render.present.clone()));
+        }
+        for (key, action) in &config.keys {
+            if parse_binding(key, action).is_none() {
+                return Err(ConfigError::Binding(key.clone(), action.clone()));
+            }
+        }
+        Ok(config)
+    }
+
+    // When the file was last written, None if it doesn't exist (yet)
+    pub fn modified(path: &Path) -> Option<SystemTime> {
+        fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    // Everything but the texture size, noise source and present mode, which go to
+    // Renderer::new, and the up axis. The wind snaps to the configured angle instead of turning.
+    pub fn apply(&self, renderer: &mut Renderer) {
+        let sim = &self.simulation;
+        let simulation = &mut renderer.simulation;
+        simulation.set_spectrum_params(SpectrumParams {
+            wind_speed: sim.wind_speed,
+            fetch: sim.fetch,
+            angle: sim.wind_angle.to_radians(),
+            swell: sim.swell,
+            ..simulation.spectrum_params()
+        });
+        if let Some(seed) = sim.noise_seed {
+            simulation.set_noise_seed(seed);
+        }
+        simulation.set_depth(sim.depth);
+        simulation.set_choppiness(sim.choppiness);
+        simulation.set_max_displacement(sim.max_displacement);
+        simulation.set_crest_stretch(sim.crest_stretch);
+        simulation.set_secondary_swell(SwellParams {
+            wind_speed: sim.secondary_wind_speed,
+            fetch: sim.secondary_fetch,
+            swell: sim.secondary_swell,
+            gamma: sim.secondary_gamma,
+            short_waves_fade: sim.secondary_short_waves_fade,
+        });
+        simulation.set_foam_generation(sim.foam_threshold, sim.foam_injection);
+        simulation.set_foam_decay(sim.foam_decay);
+        for cascade in 0..simulation.cascade_count() {
+            let threshold = sim.cascade_foam_thresholds.get(cascade).copied();
+            simulation.set_cascade_foam_threshold(cascade, threshold);
+        }
+        let disturbances: Vec<_> = sim
+            .disturbances
+            .iter()
+            .map(|&[x, z, strength, radius]| Disturbance {
+                position: [x, z],
+                strength,
+                radius,
+            })
+            .collect();
+        simulation.set_disturbances(&disturbances);
+        let cascades = renderer.simulation.cascade_count();
+        for (cascade, default) in DEFAULT_CASCADE_FOAM_WEIGHTS
+            .into_iter()
+            .enumerate()
+            .take(cascades)
+        {
+            let weight = sim.cascade_foam_weights.get(cascade).copied();
+            renderer.set_cascade_foam_weight(cascade, weight.unwrap_or(default));
+        }
+        renderer.set_world_uv_scale(sim.world_uv_scale);
+
+        let mat = &self.material;
+        renderer.set_water_colors(
+            mat.base_water_color,
+            mat.deep_water_color,
+            mat.deep_water_distance,
+        );
+        renderer.set_sun(mat.sun_direction, mat.sun_color, mat.sun_intensity);
+        renderer.set_glitter_strength(mat.glitter_strength);
+        renderer.set_shore_foam(mat.shore_foam_width, mat.shore_foam_color);
+        renderer.set_foam_gradient(&mat.foam_gradient);
+        renderer.set_exposure(mat.exposure);
+        renderer.set_foam_bias(mat.foam_bias);
+        renderer.set_clear_color(mat.clear_color);
+        renderer.set_underwater_fog(mat.underwater_fog_color, mat.underwater_fog_density);
+        renderer.set_distance_fog(mat.distance_fog_color, mat.distance_fog_density);
+
+        let render = &self.render;
+        renderer.set_render_scale(render.render_scale);
+        renderer.set_render_mode(if render.deferred {
+            RenderMode::Deferred
+        } else {
+            RenderMode::Forward
+        });
+        renderer.set_dither_strength(render.dither_strength);
+        renderer.set_fps_smoothing(render.fps_smoothing);
+        renderer.set_seabed(render.seabed_height);
+        renderer.set_adaptive_quality(render.target_fps);
+    }
+
+    pub fn apply_camera(&self, camera: &mut Camera) {
+        let cam = &self.camera;
+        camera.set_clip_planes(cam.near_plane, cam.far_plane);
+        camera.set_rotate_speed(cam.rotate_speed);
+    }
+
+    pub fn apply_keys(&self, input: &mut InputManager) {
+        // Checked by load
+        for (key, action) in self.keys.iter().filter_map(|(k, a)| parse_binding(k, a)) {
+            match action {
+                Some(action) => input.rebind(key, action),
+                None => input.unbind(key),
+            }
+        }
+    }
+}
+
+fn parse_binding(key: &str, action: &str) -> Option<(VirtualKeyCode, Option<Action>)> {
+    let action = match action {
+        "none" => None,
+        name => Some(Action::from_name(name)?),
+    };
+    Some((input::key_from_name(key)?, action))
+}
@@ -1,70 +1,88 @@
 use std::sync::Arc;
 
+use bytemuck::Pod;
 use vulkano::{
     buffer::{BufferUsage, CpuAccessibleBuffer},
     descriptor_set::{
         PersistentDescriptorSet, WriteDescriptorSet, allocator::StandardDescriptorSetAllocator,
     },
+    device::DeviceOwned,
     memory::allocator::StandardMemoryAllocator,
     pipeline::{GraphicsPipeline, Pipeline},
 };
 
-use crate::instance::{Instance, Mesh, Vertex};
+use crate::instance::{Instance, Mesh, MeshError, MeshTopology, Vertex};
+
+// Zero sized buffers can't be created, so empty content just leaves the buffer out
+fn create_buffer<T: Pod + Send + Sync>(
+    memory_allocator: &StandardMemoryAllocator,
+    usage: BufferUsage,
+    data: &[T],
+) -> Result<Option<Arc<CpuAccessibleBuffer<[T]>>>, MeshError> {
+    if data.is_empty() {
+        return Ok(None);
+    }
+
+    CpuAccessibleBuffer::from_iter(memory_allocator, usage, false, data.iter().cloned())
+        .map(Some)
+        .map_err(MeshError::BufferAllocation)
+}
 
 pub struct DrawCache {
-    pub vertex_buffer: Arc<CpuAccessibleBuffer<[Vertex]>>,
-    pub inst_buffer: Arc<CpuAccessibleBuffer<[Instance]>>,
-    pub index_buffer: Arc<CpuAccessibleBuffer<[u32]>>,
+    pub vertex_buffer: Option<Arc<CpuAccessibleBuffer<[Vertex]>>>,
+    pub inst_buffer: Option<Arc<CpuAccessibleBuffer<[Instance]>>>,
+    pub index_buffer: Option<Arc<CpuAccessibleBuffer<[u32]>>>,
     pub geometry_sets: Vec<Arc<PersistentDescriptorSet>>,
+    pub topology: MeshTopology,
 }
 
 impl DrawCache {
     pub fn new(
         mesh: &Mesh,
-        instances: &Vec<Instance>,
+        instances: &[Instance],
         memory_allocator: &StandardMemoryAllocator,
         descriptor_set_allocator: &StandardDescriptorSetAllocator,
         geometry_pipeline: &Arc<GraphicsPipeline>,
         descriptor_writes: Vec<impl IntoIterator<Item = WriteDescriptorSet>>,
-    ) -> Self {
-        let inst_buffer = CpuAccessibleBuffer::from_iter(
+    ) -> Result<Self, MeshError> {
+        let max_index = memory_allocator
+            .device()
+            .physical_device()
+            .properties()
+            .max_draw_indexed_index_value;
+        mesh.validate(max_index)?;
+
+        let inst_buffer = create_buffer(
             memory_allocator,
             BufferUsage {
                 vertex_buffer: true,
                 ..BufferUsage::empty()
             },
-            false,
-            instances.iter().cloned(),
-        )
-        .unwrap();
-
-        let vertex_buffer = CpuAccessibleBuffer::from_iter(
+            instances,
+        )?;
+        let vertex_buffer = create_buffer(
             memory_allocator,
             BufferUsage {
                 vertex_buffer: true,
                 ..BufferUsage::empty()
             },
-            false,
-            mesh.vertices.iter().cloned(),
-        )
-        .unwrap();
-        let index_buffer = CpuAccessibleBuffer::from_iter(
+            &mesh.vertices,
+        )?;
+        let index_buffer = create_buffer(
             memory_allocator,
             BufferUsage {
                 index_buffer: true,
                 ..BufferUsage::empty()
             },
-            false,
-            mesh.indices.iter().cloned(),
-        )
-        .unwrap();
+            &mesh.indices,
+        )?;
 
         let mut geometry_sets = Vec::new();
         for writes in descriptor_writes {
             let geometry_layout = geometry_pipeline
                 .layout()
                 .set_layouts()
-                .get(geometry_sets.len() as usize)
+                .get(geometry_sets.len())
                 .unwrap();
             let geometry_set = PersistentDescriptorSet::new(
                 descriptor_set_allocator,
@@ -75,11 +93,29 @@ impl DrawCache {
             geometry_sets.push(geometry_set);
         }
 
-        DrawCache {
+        Ok(DrawCache {
             geometry_sets,
             index_buffer,
             vertex_buffer,
             inst_buffer,
-        }
+            topology: mesh.topology,
+        })
+    }
+
+    // Swaps only the instance buffer, the mesh and descriptor sets stay
+    pub fn set_instances(
+        &mut self,
+        memory_allocator: &StandardMemoryAllocator,
+        instances: &[Instance],
+    ) -> Result<(), MeshError> {
+        self.inst_buffer = create_buffer(
+            memory_allocator,
+            BufferUsage {
+                vertex_buffer: true,
+                ..BufferUsage::empty()
+            },
+            instances,
+        )?;
+        Ok(())
     }
 }
@@ -0,0 +1,309 @@
+use std::{fmt, sync::Arc};
+
+use log::{Level, log};
+use vulkano::{
+    LoadingError, VulkanError, VulkanLibrary,
+    command_buffer::allocator::StandardCommandBufferAllocator,
+    device::{
+        Device, DeviceCreateInfo, DeviceExtensions, Features, Queue, QueueCreateInfo,
+        physical::PhysicalDeviceType,
+    },
+    instance::{
+        Instance, InstanceCreateInfo, InstanceCreationError, InstanceExtensions,
+        debug::{
+            DebugUtilsMessageSeverity, DebugUtilsMessageType, DebugUtilsMessenger,
+            DebugUtilsMessengerCreateInfo,
+        },
+    },
+    memory::allocator::StandardMemoryAllocator,
+    swapchain::Surface,
+};
+use vulkano_win::VkSurfaceBuild;
+use winit::{event_loop::EventLoop, window::WindowBuilder};
+
+const VALIDATION_LAYER: &str = "VK_LAYER_KHRONOS_validation";
+
+// What the validation layer reports through the log, when it's installed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationConfig {
+    Off,
+    // Errors and warnings
+    Warnings,
+    // Everything down to verbose, a lot of output
+    All,
+}
+
+impl Default for ValidationConfig {
+    // Release builds leave the layer out, it slows every call down
+    fn default() -> Self {
+        if cfg!(debug_assertions) {
+            ValidationConfig::Warnings
+        } else {
+            ValidationConfig::Off
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ContextError {
+    // No Vulkan loader or driver installed
+    NoVulkan(LoadingError),
+    // The loader is there but the instance can't be made, IncompatibleDriver when no driver
+    // (ICD) is installed for it
+    Instance(InstanceCreationError),
+    Enumerate(VulkanError),
+    // Every device seen, as "name (type)", none had what's required
+    NoSuitableDevice {
+        required: &'static str,
+        devices: Vec<String>,
+    },
+}
+
+impl From<LoadingError> for ContextError {
+    fn from(e: LoadingError) -> Self {
+        ContextError::NoVulkan(e)
+    }
+}
+
+impl From<InstanceCreationError> for ContextError {
+    fn from(e: InstanceCreationError) -> Self {
+        ContextError::Instance(e)
+    }
+}
+
+impl From<VulkanError> for ContextError {
+    fn from(e: VulkanError) -> Self {
+        ContextError::Enumerate(e)
+    }
+}
+
+// Meant for the user, not just the log
+impl fmt::Display for ContextError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ContextError::NoVulkan(e) => {
+                write!(f, "Vulkan isn't available ({}), is a driver installed?", e)
+            }
+            ContextError::Instance(InstanceCreationError::IncompatibleDriver) => {
+                write!(f, "No Vulkan driver found, is one installed for the GPU?")
+            }
+            ContextError::Instance(e) => write!(f, "Couldn't create a Vulkan instance: {}", e),
+            ContextError::Enumerate(e) => write!(f, "Couldn't list the Vulkan devices: {}", e),
+            ContextError::NoSuitableDevice { required, devices } if devices.is_empty() => {
+                write!(f, "No Vulkan devices found, {} is required", required)
+            }
+            ContextError::NoSuitableDevice { required, devices } => write!(
+                f,
+                "None of the Vulkan devices has {}. Found: {}",
+                required,
+                devices.join(", ")
+            ),
+        }
+    }
+}
+
+fn debug_message_level(severity: DebugUtilsMessageSeverity) -> Level {
+    if severity.error {
+        Level::Error
+    } else if severity.warning {
+        Level::Warn
+    } else if severity.information {
+        Level::Info
+    } else {
+        Level::Trace
+    }
+}
+
+// Hooks up the validation layer unless it's off or not installed, the messenger only
+// exists along with the layer
+fn create_instance(
+    library: Arc<VulkanLibrary>,
+    mut extensions: InstanceExtensions,
+    validation: ValidationConfig,
+) -> Result<(Arc<Instance>, Option<DebugUtilsMessenger>), InstanceCreationError> {
+    let enabled_layers: Vec<String> = if validation != ValidationConfig::Off {
+        library
+            .layer_properties()
+            .unwrap()
+            .filter(|layer| layer.name() == VALIDATION_LAYER)
+            .map(|layer| layer.name().to_owned())
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let debug_utils = !enabled_layers.is_empty() && library.supported_extensions().ext_debug_utils;
+    extensions.ext_debug_utils = debug_utils;
+    let message_severity = match validation {
+        ValidationConfig::All => DebugUtilsMessageSeverity {
+            error: true,
+            warning: true,
+            information: true,
+            verbose: true,
+            ..DebugUtilsMessageSeverity::empty()
+        },
+        _ => DebugUtilsMessageSeverity {
+            error: true,
+            warning: true,
+            ..DebugUtilsMessageSeverity::empty()
+        },
+    };
+
+    let instance = Instance::new(
+        library,
+        InstanceCreateInfo {
+            enabled_extensions: extensions,
+            enabled_layers,
+            enumerate_portability: true,
+            max_api_version: Some(vulkano::Version::V1_1),
+            ..Default::default()
+        },
+    )?;
+
+    let debug_messenger = debug_utils.then(|| unsafe {
+        DebugUtilsMessenger::new(
+            instance.clone(),
+            DebugUtilsMessengerCreateInfo {
+                message_severity,
+                message_type: DebugUtilsMessageType {
+                    general: true,
+                    validation: true,
+                    performance: true,
+                    ..DebugUtilsMessageType::empty()
+                },
+                ..DebugUtilsMessengerCreateInfo::user_callback(Arc::new(|msg| {
+                    log!(
+                        debug_message_level(msg.severity),
+                        "{}: {}",
+                        msg.layer_prefix.unwrap_or("vulkan"),
+                        msg.description
+                    );
+                }))
+            },
+        )
+        .unwrap()
+    });
+
+    Ok((instance, debug_messenger))
+}
+
+// Device, queue and allocators shared by the renderer and the simulation. The headless one has
+// no surface or swapchain support, enough to run the simulation and read its maps back.
+pub struct GpuContext {
+    pub device: Arc<Device>,
+    pub queue: Arc<Queue>,
+    pub memory_allocator: Arc<StandardMemoryAllocator>,
+    pub command_buffer_allocator: StandardCommandBufferAllocator,
+    // Has to stay alive for the callback to keep firing
+    _debug_messenger: Option<DebugUtilsMessenger>,
+}
+
+impl GpuContext {
+    pub fn headless(validation: ValidationConfig) -> Result<Self, ContextError> {
+        let library = VulkanLibrary::new()?;
+        let (instance, debug_messenger) =
+            create_instance(library, InstanceExtensions::empty(), validation)?;
+        Self::new(&instance, debug_messenger, None)
+    }
+
+    // Opens the window on the way, the device has to be able to present to its surface
+    pub fn windowed(
+        event_loop: &EventLoop<()>,
+        validation: ValidationConfig,
+    ) -> Result<(Self, Arc<Surface>), ContextError> {
+        let library = VulkanLibrary::new()?;
+        let mut extensions = vulkano_win::required_extensions(&library);
+        extensions.khr_get_surface_capabilities2 = true;
+        let (instance, debug_messenger) = create_instance(library, extensions, validation)?;
+
+        let surface = WindowBuilder::new()
+            .build_vk_surface(event_loop, instance.clone())
+            .unwrap();
+        let context = Self::new(&instance, debug_messenger, Some(&surface))?;
+        Ok((context, surface))
+    }
+
+    fn new(
+        instance: &Arc<Instance>,
+        debug_messenger: Option<DebugUtilsMessenger>,
+        surface: Option<&Surface>,
+    ) -> Result<Self, ContextError> {
+        let device_extensions = DeviceExtensions {
+            khr_swapchain: surface.is_some(),
+            ..DeviceExtensions::empty()
+        };
+
+        let physical_devices: Vec<_> = instance.enumerate_physical_devices()?.collect();
+        let (physical_device, queue_family_index) = physical_devices
+            .iter()
+            .filter(|p| p.supported_extensions().contains(&device_extensions))
+            .filter_map(|p| {
+                p.queue_family_properties()
+                    .iter()
+                    .enumerate()
+                    .position(|(i, q)| {
+                        q.queue_flags.graphics
+                            && surface.is_none_or(|surface| {
+                                p.surface_support(i as u32, surface).unwrap_or(false)
+                            })
+                    })
+                    .map(|i| (p.clone(), i as u32))
+            })
+            .min_by_key(|(p, _)| match p.properties().device_type {
+                PhysicalDeviceType::DiscreteGpu => 0,
+                PhysicalDeviceType::IntegratedGpu => 1,
+                PhysicalDeviceType::VirtualGpu => 2,
+                PhysicalDeviceType::Cpu => 3,
+                PhysicalDeviceType::Other => 4,
+                _ => 5,
+            })
+            .ok_or_else(|| ContextError::NoSuitableDevice {
+                required: if surface.is_some() {
+                    "VK_KHR_swapchain and a graphics queue that can present to the window"
+                } else {
+                    "a graphics queue"
+                },
+                devices: physical_devices
+                    .iter()
+                    .map(|p| {
+                        let properties = p.properties();
+                        format!("{} ({:?})", properties.device_name, properties.device_type)
+                    })
+                    .collect(),
+            })?;
+
+        // Optional, the renderer leaves out the wireframe view and the tessellated water
+        // without them
+        let supported_features = physical_device.supported_features();
+        let enabled_features = Features {
+            fill_mode_non_solid: supported_features.fill_mode_non_solid,
+            tessellation_shader: supported_features.tessellation_shader,
+            ..Features::empty()
+        };
+        let (device, mut queues) = Device::new(
+            physical_device,
+            DeviceCreateInfo {
+                enabled_extensions: device_extensions,
+                enabled_features,
+                queue_create_infos: vec![QueueCreateInfo {
+                    queue_family_index,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let queue = queues.next().unwrap();
+
+        let memory_allocator = Arc::new(StandardMemoryAllocator::new_default(device.clone()));
+        let command_buffer_allocator =
+            StandardCommandBufferAllocator::new(device.clone(), Default::default());
+
+        Ok(GpuContext {
+            device,
+            queue,
+            memory_allocator,
+            command_buffer_allocator,
+            _debug_messenger: debug_messenger,
+        })
+    }
+}
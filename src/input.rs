@@ -0,0 +1,290 @@
+use std::collections::{HashMap, HashSet};
+
+#[cfg(feature = "gamepad")]
+use gilrs::{Axis, Button, Gilrs};
+#[cfg(feature = "gamepad")]
+use log::{info, warn};
+use nalgebra_glm::Vec3;
+use winit::event::{ElementState, KeyboardInput, VirtualKeyCode, WindowEvent};
+
+// Stick deflection up to this reads as exactly zero, sticks never quite rest at the center
+#[cfg(feature = "gamepad")]
+const STICK_DEADZONE: f32 = 0.15;
+// Triggers, which rest at 0, for moving up and down
+#[cfg(feature = "gamepad")]
+const PAD_BINDINGS: [(Button, Action); 2] = [
+    (Button::RightTrigger2, Action::Up),
+    (Button::LeftTrigger2, Action::Down),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    Forward,
+    Backward,
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+impl Action {
+    // Names the [keys] table of the config uses
+    pub fn from_name(name: &str) -> Option<Action> {
+        match name {
+            "move_forward" => Some(Action::Forward),
+            "move_backward" => Some(Action::Backward),
+            "move_left" => Some(Action::Left),
+            "move_right" => Some(Action::Right),
+            "move_up" => Some(Action::Up),
+            "move_down" => Some(Action::Down),
+            _ => None,
+        }
+    }
+}
+
+// Keys that can be bound from the config, by their VirtualKeyCode name
+const KEY_NAMES: [(&str, VirtualKeyCode); 66] = [
+    ("A", VirtualKeyCode::A),
+    ("B", VirtualKeyCode::B),
+    ("C", VirtualKeyCode::C),
+    ("D", VirtualKeyCode::D),
+    ("E", VirtualKeyCode::E),
+    ("F", VirtualKeyCode::F),
+    ("G", VirtualKeyCode::G),
+    ("H", VirtualKeyCode::H),
+    ("I", VirtualKeyCode::I),
+    ("J", VirtualKeyCode::J),
+    ("K", VirtualKeyCode::K),
+    ("L", VirtualKeyCode::L),
+    ("M", VirtualKeyCode::M),
+    ("N", VirtualKeyCode::N),
+    ("O", VirtualKeyCode::O),
+    ("P", VirtualKeyCode::P),
+    ("Q", VirtualKeyCode::Q),
+    ("R", VirtualKeyCode::R),
+    ("S", VirtualKeyCode::S),
+    ("T", VirtualKeyCode::T),
+    ("U", VirtualKeyCode::U),
+    ("V", VirtualKeyCode::V),
+    ("W", VirtualKeyCode::W),
+    ("X", VirtualKeyCode::X),
+    ("Y", VirtualKeyCode::Y),
+    ("Z", VirtualKeyCode::Z),
+    ("Key0", VirtualKeyCode::Key0),
+    ("Key1", VirtualKeyCode::Key1),
+    ("Key2", VirtualKeyCode::Key2),
+    ("Key3", VirtualKeyCode::Key3),
+    ("Key4", VirtualKeyCode::Key4),
+    ("Key5", VirtualKeyCode::Key5),
+    ("Key6", VirtualKeyCode::Key6),
+    ("Key7", VirtualKeyCode::Key7),
+    ("Key8", VirtualKeyCode::Key8),
+    ("Key9", VirtualKeyCode::Key9),
+    ("Space", VirtualKeyCode::Space),
+    ("Tab", VirtualKeyCode::Tab),
+    ("Return", VirtualKeyCode::Return),
+    ("Back", VirtualKeyCode::Back),
+    ("Insert", VirtualKeyCode::Insert),
+    ("Delete", VirtualKeyCode::Delete),
+    ("Home", VirtualKeyCode::Home),
+    ("End", VirtualKeyCode::End),
+    ("PageUp", VirtualKeyCode::PageUp),
+    ("PageDown", VirtualKeyCode::PageDown),
+    ("Up", VirtualKeyCode::Up),
+    ("Down", VirtualKeyCode::Down),
+    ("Left", VirtualKeyCode::Left),
+    ("Right", VirtualKeyCode::Right),
+    ("LShift", VirtualKeyCode::LShift),
+    ("RShift", VirtualKeyCode::RShift),
+    ("LControl", VirtualKeyCode::LControl),
+    ("RControl", VirtualKeyCode::RControl),
+    ("LAlt", VirtualKeyCode::LAlt),
+    ("RAlt", VirtualKeyCode::RAlt),
+    ("Numpad0", VirtualKeyCode::Numpad0),
+    ("Numpad1", VirtualKeyCode::Numpad1),
+    ("Numpad2", VirtualKeyCode::Numpad2),
+    ("Numpad3", VirtualKeyCode::Numpad3),
+    ("Numpad4", VirtualKeyCode::Numpad4),
+    ("Numpad5", VirtualKeyCode::Numpad5),
+    ("Numpad6", VirtualKeyCode::Numpad6),
+    ("Numpad7", VirtualKeyCode::Numpad7),
+    ("Numpad8", VirtualKeyCode::Numpad8),
+    ("Numpad9", VirtualKeyCode::Numpad9),
+];
+
+pub fn key_from_name(name: &str) -> Option<VirtualKeyCode> {
+    KEY_NAMES
+        .iter()
+        .find(|(key_name, _)| *key_name == name)
+        .map(|(_, key)| *key)
+}
+
+// Radial deadzone, rescaled so the output starts from 0 at its edge instead of jumping
+#[cfg(feature = "gamepad")]
+fn apply_deadzone(stick: [f32; 2]) -> [f32; 2] {
+    let length = stick[0].hypot(stick[1]);
+    if length <= STICK_DEADZONE {
+        return [0.0; 2];
+    }
+    let scale = ((length - STICK_DEADZONE) / (1.0 - STICK_DEADZONE)).min(1.0) / length;
+    stick.map(|v| v * scale)
+}
+
+// Held state of the bound keys. Several keys can share an action, it stays active while any
+// of them is down. With the gamepad feature the first connected pad adds its sticks and
+// triggers on top.
+pub struct InputManager {
+    bindings: HashMap<VirtualKeyCode, Action>,
+    held: HashSet<VirtualKeyCode>,
+    // None when no gamepad backend is available on this system
+    #[cfg(feature = "gamepad")]
+    gilrs: Option<Gilrs>,
+    pad_actions: HashSet<Action>,
+    // Left and right stick after the deadzone, x = right, y = forward/up
+    analog_move: [f32; 2],
+    analog_look: [f32; 2],
+}
+
+impl InputManager {
+    // WASD, Space up, left Shift down
+    pub fn default_bindings() -> Self {
+        let bindings = HashMap::from([
+            (VirtualKeyCode::W, Action::Forward),
+            (VirtualKeyCode::S, Action::Backward),
+            (VirtualKeyCode::A, Action::Left),
+            (VirtualKeyCode::D, Action::Right),
+            (VirtualKeyCode::Space, Action::Up),
+            (VirtualKeyCode::LShift, Action::Down),
+        ]);
+        Self {
+            bindings,
+            held: HashSet::new(),
+            #[cfg(feature = "gamepad")]
+            gilrs: Gilrs::new()
+                .inspect_err(|e| warn!("Gamepads unavailable: {}", e))
+                .ok(),
+            pad_actions: HashSet::new(),
+            analog_move: [0.0; 2],
+            analog_look: [0.0; 2],
+        }
+    }
+
+    // Replaces whatever the key did before. A held key is released so the old action
+    // doesn't get stuck on.
+    pub fn rebind(&mut self, key: VirtualKeyCode, action: Action) {
+        self.held.remove(&key);
+        self.bindings.insert(key, action);
+    }
+
+    pub fn unbind(&mut self, key: VirtualKeyCode) {
+        self.held.remove(&key);
+        self.bindings.remove(&key);
+    }
+
+    // Key releases that happen while the window is unfocused never arrive, call this when
+    // focus is lost so nothing stays held until the key is pressed again
+    pub fn release_all(&mut self) {
+        self.held.clear();
+    }
+
+    // Once per frame, before reading the input. Without a connected pad the analog input
+    // and pad actions are all zero/off.
+    #[cfg(feature = "gamepad")]
+    pub fn poll_gamepad(&mut self) {
+        let Some(gilrs) = &mut self.gilrs else {
+            return;
+        };
+        // The state below is cached by gilrs, the events only have to be drained
+        while let Some(event) = gilrs.next_event() {
+            match event.event {
+                gilrs::EventType::Connected => {
+                    info!("Gamepad connected: {}", gilrs.gamepad(event.id).name())
+                }
+                gilrs::EventType::Disconnected => info!("Gamepad disconnected"),
+                _ => {}
+            }
+        }
+
+        self.pad_actions.clear();
+        self.analog_move = [0.0; 2];
+        self.analog_look = [0.0; 2];
+        let Some((_, pad)) = gilrs.gamepads().find(|(_, pad)| pad.is_connected()) else {
+            return;
+        };
+        let stick = |x, y| apply_deadzone([pad.value(x), pad.value(y)]);
+        self.analog_move = stick(Axis::LeftStickX, Axis::LeftStickY);
+        self.analog_look = stick(Axis::RightStickX, Axis::RightStickY);
+        for (button, action) in PAD_BINDINGS {
+            if pad.is_pressed(button) {
+                self.pad_actions.insert(action);
+            }
+        }
+    }
+
+    pub fn analog_look(&self) -> [f32; 2] {
+        self.analog_look
+    }
+
+    pub fn on_event(&mut self, event: &WindowEvent) {
+        let WindowEvent::KeyboardInput {
+            input:
+                KeyboardInput {
+                    state,
+                    virtual_keycode: Some(key),
+                    ..
+                },
+            ..
+        } = event
+        else {
+            return;
+        };
+        if !self.bindings.contains_key(key) {
+            return;
+        }
+
+        match state {
+            ElementState::Pressed => self.held.insert(*key),
+            ElementState::Released => self.held.remove(key),
+        };
+    }
+
+    pub fn is_action_active(&self, action: Action) -> bool {
+        self.pad_actions.contains(&action)
+            || self
+                .held
+                .iter()
+                .any(|key| self.bindings.get(key) == Some(&action))
+    }
+
+    // x = right, y = forward, z = up, opposite actions cancel out. The left stick adds to the
+    // keys, each axis stays within -1..1.
+    pub fn move_dir(&self) -> Vec3 {
+        let axis = |positive, negative, analog: f32| {
+            let keys =
+                self.is_action_active(positive) as i32 - self.is_action_active(negative) as i32;
+            (keys as f32 + analog).clamp(-1.0, 1.0)
+        };
+        Vec3::new(
+            axis(Action::Right, Action::Left, self.analog_move[0]),
+            axis(Action::Forward, Action::Backward, self.analog_move[1]),
+            axis(Action::Up, Action::Down, 0.0),
+        )
+    }
+}
+
+#[cfg(all(test, feature = "gamepad"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resting_sticks_read_exactly_zero() {
+        assert_eq!(apply_deadzone([0.05, -0.1]), [0.0; 2]);
+        assert_eq!(apply_deadzone([0.0, STICK_DEADZONE]), [0.0; 2]);
+
+        // Continuous past the edge, and full deflection still reaches 1
+        let [_, y] = apply_deadzone([0.0, STICK_DEADZONE + 0.001]);
+        assert!(y > 0.0 && y < 0.01, "Got {}", y);
+        let [x, y] = apply_deadzone([1.0, 0.0]);
+        assert!((x - 1.0).abs() < 1e-6 && y == 0.0);
+    }
+}
@@ -1,4 +1,8 @@
+use std::fmt;
+
 use bytemuck::{Pod, Zeroable};
+use nalgebra_glm as glm;
+use vulkano::memory::allocator::AllocationCreationError;
 
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Default, Zeroable, Pod)]
@@ -7,15 +11,100 @@ pub struct Vertex {
     pub uv: [f32; 2],
 }
 
+// Index value that ends the current strip, primitive restart is on for strip meshes
+pub const PRIMITIVE_RESTART_INDEX: u32 = u32::MAX;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MeshTopology {
+    #[default]
+    TriangleList,
+    // Rows separated by PRIMITIVE_RESTART_INDEX, about half the indices of a list
+    TriangleStrip,
+    // Same indices as TriangleList, drawn as 3 control point patches that the tessellation
+    // shaders subdivide near the camera. Only drawn when Renderer::supports_tessellation.
+    Patches,
+}
+
 #[derive(Clone)]
 pub struct Mesh {
     pub vertices: Vec<Vertex>,
     pub indices: Vec<u32>,
+    pub topology: MeshTopology,
+}
+
+#[derive(Debug)]
+pub enum MeshError {
+    // More vertices than the device can index with 32 bit indices
+    TooManyVertices { count: usize, max: u64 },
+    IndexOutOfBounds { index: u32, vertex_count: usize },
+    BufferAllocation(AllocationCreationError),
+}
+
+impl fmt::Display for MeshError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MeshError::TooManyVertices { count, max } => {
+                write!(f, "{} vertices, the device can index {}", count, max)
+            }
+            MeshError::IndexOutOfBounds {
+                index,
+                vertex_count,
+            } => write!(f, "Index {} past the {} vertices", index, vertex_count),
+            MeshError::BufferAllocation(e) => write!(f, "Buffer allocation failed: {}", e),
+        }
+    }
 }
 
+impl Mesh {
+    // max_index is the device's max_draw_indexed_index_value
+    pub fn validate(&self, max_index: u32) -> Result<(), MeshError> {
+        let max = (max_index as u64).min(u32::MAX as u64) + 1;
+        if self.vertices.len() as u64 > max {
+            return Err(MeshError::TooManyVertices {
+                count: self.vertices.len(),
+                max,
+            });
+        }
+
+        if let Some(&index) = self
+            .indices
+            .iter()
+            .filter(|&&i| {
+                self.topology != MeshTopology::TriangleStrip || i != PRIMITIVE_RESTART_INDEX
+            })
+            .find(|&&i| i as usize >= self.vertices.len())
+        {
+            return Err(MeshError::IndexOutOfBounds {
+                index,
+                vertex_count: self.vertices.len(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+// Instance::uv_transform that samples the maps right at the tile's world position
+pub const IDENTITY_UV_TRANSFORM: [[f32; 4]; 2] = [[1.0, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0]];
+
 #[repr(C)]
-#[derive(Clone, Copy, Debug, Default, Zeroable, Pod)]
+#[derive(Clone, Copy, Debug, Zeroable, Pod)]
 pub struct Instance {
     pub instance_normal: [[f32; 4]; 4],
     pub instance_model: [[f32; 4]; 4],
+    // Moves where the tile samples the displacement maps, in simulation meters. Rows are
+    // (rotation row, offset, tile center) for x and z, the rotation turns about the center.
+    // The shader fades it out towards the tile edges, so neighbours still meet.
+    pub uv_transform: [[f32; 4]; 2],
+}
+
+impl Default for Instance {
+    fn default() -> Self {
+        let identity = glm::Mat4::identity().into();
+        Instance {
+            instance_normal: identity,
+            instance_model: identity,
+            uv_transform: IDENTITY_UV_TRANSFORM,
+        }
+    }
 }
@@ -1,187 +1,885 @@
+mod axis;
+mod bench;
 mod camera;
+mod config;
 mod draw_cache;
+mod gpu_context;
+mod input;
 mod instance;
+mod overlay;
 mod renderer;
+#[cfg(feature = "shader-reload")]
+mod shader_reload;
+mod share_code;
 mod simulation;
+mod skybox;
 mod water;
 
-use nalgebra_glm::{IVec3, Vec3};
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    process,
+    time::{Duration, Instant},
+};
+
+use log::{LevelFilter, Log, Metadata, Record, error, info, trace, warn};
+use nalgebra_glm::{Vec2, Vec3};
 use vulkano::{
-    descriptor_set::WriteDescriptorSet,
+    descriptor_set::{WriteDescriptorSet, allocator::StandardDescriptorSetAllocator},
+    image::SampleCount,
+    sampler::{Sampler, SamplerCreateInfo},
     sync::{self, GpuFuture},
 };
 use winit::{
-    event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent},
+    event::{
+        ElementState, Event, KeyboardInput, ModifiersState, MouseButton, MouseScrollDelta,
+        VirtualKeyCode, WindowEvent,
+    },
     event_loop::{ControlFlow, EventLoop},
 };
 
-use crate::{camera::Camera, renderer::Renderer, water::Water};
+use crate::{
+    bench::BenchOptions,
+    camera::{Camera, CameraMode},
+    config::Config,
+    gpu_context::{ContextError, GpuContext, ValidationConfig},
+    input::InputManager,
+    instance::MeshTopology,
+    renderer::{Renderer, write_png},
+    simulation::{
+        DEFAULT_CASCADE_LENGTH_SCALES, FrameData, FrameDataCallback, NoiseSource, SimMap,
+        Simulation, SpectrumPreset,
+    },
+    water::Water,
+};
 
-fn main() {
-    let event_loop = EventLoop::new();
-    let mut renderer = Renderer::new(&event_loop);
-    renderer.init();
+// Fixed grid used when the device can't tessellate, patches are picked otherwise
+const MESH_TOPOLOGY: MeshTopology = MeshTopology::TriangleStrip;
+// Falls back to no MSAA on devices that can't do it
+const MSAA_SAMPLES: SampleCount = SampleCount::Sample4;
+const CROSSING_ANGLE_STEP: f32 = 0.1745; // 10 degrees
+const SECONDARY_STRENGTH_STEP: f32 = 0.1;
+const WIND_TURN_STEP: f32 = 0.2618; // 15 degrees
+const TIME_SCRUB_STEP: f32 = 0.1;
+// With Ctrl held, Shift is taken by moving down
+const TIME_SCRUB_STEP_LARGE: f32 = 2.0;
+const PROFILE_LENGTH: f32 = 200.0;
+const PROFILE_SAMPLES: usize = 1024;
+const CAMERA_FILE: &str = "camera.txt";
+// Simulation and material settings, optional. Changes are picked up while running, except
+// for the ones marked as startup only in config.rs.
+const CONFIG_FILE: &str = "config.toml";
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const SCREENSHOT_FILE: &str = "screenshot.png";
+// F11 renders the ocean offscreen at this size, independent of the window
+const CAPTURE_FILE: &str = "capture.png";
+const CAPTURE_SIZE: [u32; 2] = [3840, 2160];
+// F8 toggles writing a summary of every simulated frame in here
+const WAVE_RECORDING_FILE: &str = "waves.csv";
+// Loaded at startup when the folder exists, faces in the order Renderer::load_skybox takes
+const SKYBOX_DIR: &str = "skybox";
+const SKYBOX_FACES: [&str; 6] = ["px.png", "nx.png", "py.png", "ny.png", "pz.png", "nz.png"];
+// Written by --headless, the first cascade's height as grayscale
+const HEADLESS_DISPLACEMENT_FILE: &str = "displacement.png";
+// Depths H switches between, open ocean and coastal water
+const DEEP_WATER_DEPTH: f32 = 500.0;
+const SHALLOW_WATER_DEPTH: f32 = 10.0;
+// Time scale T toggles to
+const SLOW_MOTION_SCALE: f32 = 0.25;
+// Factor per +/- press, vertical speed follows along
+const MOVE_SPEED_STEP: f32 = 1.25;
+// Factor per Page Up/Down press
+const EXPOSURE_STEP: f32 = 1.25;
+// Meters the water rises under a right click
+const RIPPLE_STRENGTH: f32 = 0.5;
+
+// Bare bones stdout logger for running the demo on its own, an app embedding the
+// renderer installs its own logger instead. LOG_LEVEL picks the filter (e.g. trace)
+struct StdoutLogger;
+
+impl Log for StdoutLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            println!("[{}] {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
 
-    let mut camera = Camera::new(Vec3::new(-2.0, -0.5, 0.0));
-    let mut move_dir = IVec3::new(0, 0, 0);
+static LOGGER: StdoutLogger = StdoutLogger;
 
-    // TODO: Use multiple cascedes for more detail(Like 3 lower and lower frequency waves stacked)
-    let water = Water::new();
-    let water_cache = renderer.get_draw_cache(
-        &water.mesh,
-        &water.instances,
+// Descriptor writes shared by every water draw cache
+fn water_descriptor_writes(renderer: &Renderer) -> Vec<Vec<WriteDescriptorSet>> {
+    vec![
+        vec![
+            WriteDescriptorSet::image_view_sampler(
+                0,
+                renderer.simulation.displacement_map.clone(),
+                renderer.texture_sampler.clone(),
+            ),
+            WriteDescriptorSet::image_view_sampler(
+                1,
+                renderer.simulation.derivatives_map.clone(),
+                renderer.texture_sampler.clone(),
+            ),
+            WriteDescriptorSet::image_view_sampler(
+                2,
+                renderer.simulation.turbulence_map.clone(),
+                renderer.texture_sampler.clone(),
+            ),
+            WriteDescriptorSet::image_view_sampler(
+                4,
+                renderer.simulation.foam_map.clone(),
+                renderer.texture_sampler.clone(),
+            ),
+            WriteDescriptorSet::image_view_sampler(
+                5,
+                renderer.simulation.flow_map.clone(),
+                renderer.texture_sampler.clone(),
+            ),
+        ],
         vec![
-            vec![
-                WriteDescriptorSet::image_view_sampler(
-                    0,
-                    renderer.simulation.displacement_map.clone(),
-                    renderer.texture_sampler.clone(),
-                ),
-                WriteDescriptorSet::image_view_sampler(
-                    1,
-                    renderer.simulation.derivatives_map.clone(),
-                    renderer.texture_sampler.clone(),
-                ),
-                WriteDescriptorSet::image_view_sampler(
-                    2,
-                    renderer.simulation.turbulence_map.clone(),
-                    renderer.texture_sampler.clone(),
-                ),
-                WriteDescriptorSet::image_view_sampler(
-                    3,
-                    renderer.simulation.camera_depth_map.clone(),
-                    renderer.texture_sampler.clone(),
-                ),
-                WriteDescriptorSet::image_view_sampler(
-                    4,
-                    renderer.simulation.foam_map.clone(),
-                    renderer.texture_sampler.clone(),
-                ),
-            ],
-            vec![
-                WriteDescriptorSet::buffer(0, renderer.ocean_params_buffer.clone()),
-                WriteDescriptorSet::buffer(1, renderer.mat_params_buffer.clone()),
-            ],
+            WriteDescriptorSet::buffer(0, renderer.ocean_params_buffer.clone()),
+            WriteDescriptorSet::buffer(1, renderer.mat_params_buffer.clone()),
+            WriteDescriptorSet::buffer(2, renderer.gerstner_buffer.clone()),
         ],
+    ]
+}
+
+// VALIDATION=off|warnings|all overrides the build's default (warnings in debug, off in release)
+fn validation_config() -> ValidationConfig {
+    match std::env::var("VALIDATION").as_deref() {
+        Ok("off") => ValidationConfig::Off,
+        Ok("warnings") => ValidationConfig::Warnings,
+        Ok("all") => ValidationConfig::All,
+        _ => ValidationConfig::default(),
+    }
+}
+
+// One row per cascade per simulated frame: wave height range, steepest slope and the share of
+// the patch that is folding over. Flushed once the recording stops and the callback is dropped.
+fn wave_recorder(path: &Path) -> io::Result<FrameDataCallback> {
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, "time,cascade,min_height,max_height,max_slope,folded")?;
+    Ok(Box::new(move |frame: &FrameData| {
+        for cascade in 0..frame.cascades {
+            let (Some(displacement), Some(derivatives), Some(turbulence)) = (
+                frame.cascade(SimMap::Displacement, cascade),
+                frame.cascade(SimMap::Derivatives, cascade),
+                frame.cascade(SimMap::Turbulence, cascade),
+            ) else {
+                return;
+            };
+            let (min, max) = displacement
+                .iter()
+                .fold((f32::MAX, f32::MIN), |(min, max), t| {
+                    (min.min(t[1]), max.max(t[1]))
+                });
+            let max_slope = derivatives
+                .iter()
+                .map(|d| d[0].hypot(d[1]))
+                .fold(0.0, f32::max);
+            let folded =
+                turbulence.iter().filter(|t| t[0] < 0.0).count() as f32 / turbulence.len() as f32;
+            let row = writeln!(
+                file,
+                "{},{},{},{},{},{}",
+                frame.time, cascade, min, max, max_slope, folded
+            );
+            if let Err(e) = row {
+                warn!("Failed to record waves: {}", e);
+                return;
+            }
+        }
+    }))
+}
+
+// One simulation step with no window or swapchain, works on machines without a display
+fn run_headless(
+    texture_size: u32,
+    noise_source: NoiseSource,
+    noise_seed: Option<u64>,
+    path: &Path,
+) -> Result<(), ContextError> {
+    let context = GpuContext::headless(validation_config())?;
+    let sampler = Sampler::new(
+        context.device.clone(),
+        SamplerCreateInfo::simple_repeat_linear(),
+    )
+    .unwrap();
+    let descriptor_set_allocator = StandardDescriptorSetAllocator::new(context.device.clone());
+    let mut simulation = Simulation::new(
+        &context,
+        &sampler,
+        noise_source,
+        noise_seed,
+        &DEFAULT_CASCADE_LENGTH_SCALES,
+        texture_size,
+    );
+    simulation.warmup(
+        &context.command_buffer_allocator,
+        &descriptor_set_allocator,
+        context.queue.clone(),
     );
 
+    let texels = simulation.read_map(SimMap::Displacement);
+    let layer = &texels[..(texture_size * texture_size) as usize];
+    let (min, max) = layer.iter().fold((f32::MAX, f32::MIN), |(min, max), t| {
+        (min.min(t[1]), max.max(t[1]))
+    });
+    let range = (max - min).max(f32::EPSILON);
+    let rgba: Vec<u8> = layer
+        .iter()
+        .flat_map(|t| {
+            let gray = ((t[1] - min) / range * 255.0) as u8;
+            [gray, gray, gray, 255]
+        })
+        .collect();
+
+    match write_png(path, [texture_size; 2], &rgba) {
+        Ok(()) => info!("Wrote {} ({:.2} to {:.2} m)", path.display(), min, max),
+        Err(e) => error!("Failed to write {}: {:?}", path.display(), e),
+    }
+    Ok(())
+}
+
+fn main() {
+    let level = std::env::var("LOG_LEVEL")
+        .ok()
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(LevelFilter::Info);
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(level);
+
+    // --bench [--size N] [--cascades N] [--steps N] times the simulation on its own
+    if std::env::args().nth(1).as_deref() == Some("--bench") {
+        let args: Vec<String> = std::env::args().skip(2).collect();
+        match BenchOptions::parse(&args) {
+            Ok(options) => {
+                if let Err(e) = bench::run(options, validation_config()) {
+                    error!("{}", e);
+                    process::exit(1);
+                }
+            }
+            Err(e) => {
+                error!("{}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let config_path = Path::new(CONFIG_FILE);
+    let mut config_modified = Config::modified(config_path);
+    let config = match Config::load(config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Failed to load {}: {}", config_path.display(), e);
+            return;
+        }
+    };
+
+    if std::env::args().nth(1).as_deref() == Some("--headless") {
+        if let Err(e) = run_headless(
+            config.simulation.texture_size,
+            config.simulation.noise_source(),
+            config.simulation.noise_seed,
+            Path::new(HEADLESS_DISPLACEMENT_FILE),
+        ) {
+            error!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let event_loop = EventLoop::new();
+    // No Vulkan or no usable GPU is the user's setup, not a bug, so no panic and backtrace
+    let mut renderer = match Renderer::new(
+        &event_loop,
+        MSAA_SAMPLES,
+        config.render.present_preference(),
+        validation_config(),
+        config.simulation.texture_size,
+        config.simulation.noise_source(),
+        config.simulation.noise_seed,
+    ) {
+        Ok(renderer) => renderer,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+    let up_axis = config.render.up_axis();
+    renderer.set_up_axis(up_axis);
+    config.apply(&mut renderer);
+    let mut config_polled = Instant::now();
+    // Built with --features shader-reload the water shaders get recompiled when anything in
+    // src/shaders changes, or with F6
+    #[cfg(feature = "shader-reload")]
+    let mut shaders_modified = shader_reload::sources_modified();
+
+    // A share code printed with C can be passed as the first argument to reproduce the frame
+    if let Some(code) = std::env::args().nth(1)
+        && let Err(e) = renderer.apply_share_code(&code)
+    {
+        warn!("Ignoring invalid share code: {}", e);
+    }
+    renderer
+        .simulation
+        .set_output_validation(cfg!(debug_assertions));
+    // The underwater look and ripple picking follow the real surface height
+    renderer.simulation.set_displacement_queries(true);
+    renderer.warmup();
+
+    let skybox_dir = Path::new(SKYBOX_DIR);
+    if skybox_dir.is_dir()
+        && let Err(e) = renderer.load_skybox(SKYBOX_FACES.map(|face| skybox_dir.join(face)))
+    {
+        warn!("Failed to load the skybox: {}", e);
+    }
+
+    let mut camera = Camera::new(up_axis.to_world(Vec3::new(-2.0, -0.5, 0.0)));
+    camera.set_up_axis(up_axis);
+    config.apply_camera(&mut camera);
+    let mut input = InputManager::default_bindings();
+    config.apply_keys(&mut input);
+    let mut modifiers = ModifiersState::empty();
+    // Orbit mode only turns while the left mouse button is held
+    let mut mouse_held = false;
+    // Window pixels, right clicks drop a ripple on the water under it
+    let mut cursor_position = Vec2::zeros();
+    // Frame time, camera and simulation stats in the top left corner
+    let mut show_stats = false;
+    let mut recording_waves = false;
+
+    let topology = if renderer.supports_tessellation() {
+        MeshTopology::Patches
+    } else {
+        MESH_TOPOLOGY
+    };
+    info!("Water mesh topology: {:?}", topology);
+    let mut water = Water::new(up_axis, topology);
+    renderer.simulation.set_phase_offset(water.phase_offset);
+    // Tiles follow the camera, the caches get rebuilt whenever it moves onto another tile
+    let mut water_tile = None;
+    let mut water_caches = Vec::new();
+    let mut water_rings = config.render.water_rings;
+
     let mut previous_frame_end =
-        Some(Box::new(sync::now(renderer.device.clone())) as Box<dyn GpuFuture>);
-    let mut last_frame_time = std::time::Instant::now();
+        Some(Box::new(sync::now(renderer.context.device.clone())) as Box<dyn GpuFuture>);
     event_loop.run(move |event, _, control_flow| match event {
-        Event::WindowEvent { event, .. } => match event {
-            WindowEvent::KeyboardInput {
-                input:
-                    KeyboardInput {
-                        state,
-                        virtual_keycode: Some(keycode),
-                        ..
-                    },
-                ..
-            } => match (keycode, state) {
-                (VirtualKeyCode::Escape, _) => {
-                    *control_flow = ControlFlow::Exit;
-                }
-                (VirtualKeyCode::W, x) => {
-                    if x == ElementState::Pressed {
-                        move_dir.y = 1;
-                    } else {
-                        move_dir.y = 0;
+        Event::WindowEvent { event, .. } => {
+            input.on_event(&event);
+            match event {
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state,
+                            virtual_keycode: Some(keycode),
+                            ..
+                        },
+                    ..
+                } => match (keycode, state) {
+                    (VirtualKeyCode::Escape, _) => {
+                        *control_flow = ControlFlow::Exit;
                     }
-                }
-                (VirtualKeyCode::S, x) => {
-                    if x == ElementState::Pressed {
-                        move_dir.y = -1;
-                    } else {
-                        move_dir.y = 0;
+                    (VirtualKeyCode::Comma | VirtualKeyCode::Period, ElementState::Pressed) => {
+                        let step = if modifiers.ctrl() {
+                            TIME_SCRUB_STEP_LARGE
+                        } else {
+                            TIME_SCRUB_STEP
+                        };
+                        let step = if keycode == VirtualKeyCode::Comma {
+                            -step
+                        } else {
+                            step
+                        };
+                        let time = renderer.simulation.time + step;
+                        renderer.simulation.set_time(time);
                     }
-                }
-                (VirtualKeyCode::A, x) => {
-                    if x == ElementState::Pressed {
-                        move_dir.x = -1;
-                    } else {
-                        move_dir.x = 0;
+                    (VirtualKeyCode::V, ElementState::Pressed) => {
+                        renderer.set_debug_view(renderer.debug_view().next());
+                        info!("Debug view: {:?}", renderer.debug_view());
                     }
-                }
-                (VirtualKeyCode::D, x) => {
-                    if x == ElementState::Pressed {
-                        move_dir.x = 1;
-                    } else {
-                        move_dir.x = 0;
+                    (VirtualKeyCode::B, ElementState::Pressed) => {
+                        renderer.set_gbuffer_view(renderer.gbuffer_view().next());
+                        info!("G-buffer view: {:?}", renderer.gbuffer_view());
                     }
-                }
-                (VirtualKeyCode::Space, x) => {
-                    if x == ElementState::Pressed {
-                        move_dir.z = 1;
-                    } else {
-                        move_dir.z = 0;
+                    (VirtualKeyCode::K, ElementState::Pressed) => {
+                        renderer.freeze_depth(!renderer.depth_frozen());
+                        info!("Scene depth frozen: {}", renderer.depth_frozen());
                     }
-                }
-                (VirtualKeyCode::LShift, x) => {
-                    if x == ElementState::Pressed {
-                        move_dir.z = -1;
+                    (VirtualKeyCode::F, ElementState::Pressed) => {
+                        renderer.set_wireframe(!renderer.wireframe());
+                    }
+                    (
+                        VirtualKeyCode::Equals
+                        | VirtualKeyCode::Plus
+                        | VirtualKeyCode::NumpadAdd
+                        | VirtualKeyCode::Minus
+                        | VirtualKeyCode::NumpadSubtract,
+                        ElementState::Pressed,
+                    ) => {
+                        let factor = match keycode {
+                            VirtualKeyCode::Minus | VirtualKeyCode::NumpadSubtract => {
+                                1.0 / MOVE_SPEED_STEP
+                            }
+                            _ => MOVE_SPEED_STEP,
+                        };
+                        camera.set_move_speed(camera.move_speed() * factor);
+                        camera.set_vertical_move_speed(camera.vertical_move_speed() * factor);
+                        info!("Move speed: {:.1} m/s", camera.move_speed());
+                    }
+                    (VirtualKeyCode::H, ElementState::Pressed) => {
+                        let depth = if renderer.simulation.depth() > SHALLOW_WATER_DEPTH {
+                            SHALLOW_WATER_DEPTH
+                        } else {
+                            DEEP_WATER_DEPTH
+                        };
+                        renderer.simulation.set_depth(depth);
+                        info!("Water depth: {}m", depth);
+                    }
+                    (VirtualKeyCode::P, ElementState::Pressed) => {
+                        let paused = !renderer.simulation.paused();
+                        renderer.simulation.set_paused(paused);
+                        info!("Simulation {}", if paused { "paused" } else { "resumed" });
+                    }
+                    (VirtualKeyCode::T, ElementState::Pressed) => {
+                        let scale = if renderer.simulation.time_scale() < 1.0 {
+                            1.0
+                        } else {
+                            SLOW_MOTION_SCALE
+                        };
+                        renderer.simulation.set_time_scale(scale);
+                        info!("Time scale: {}", scale);
+                    }
+                    (VirtualKeyCode::G, ElementState::Pressed) => {
+                        // Keep the camera out of the water
+                        let floor = camera.floor().xor(Some(0.0));
+                        camera.set_floor(floor);
+                        info!("Camera floor: {:?}", floor);
+                    }
+                    (VirtualKeyCode::O, ElementState::Pressed) => {
+                        camera.toggle_orbit();
+                        info!("Camera mode: {:?}", camera.mode());
+                    }
+                    (VirtualKeyCode::N, ElementState::Pressed) => {
+                        renderer.set_show_compass(!renderer.show_compass());
+                    }
+                    (VirtualKeyCode::F1, ElementState::Pressed) => {
+                        show_stats = !show_stats;
+                        if !show_stats {
+                            renderer.set_overlay_text(&[]);
+                        }
+                    }
+                    (VirtualKeyCode::F3, ElementState::Pressed) => {
+                        // Height profile along the view direction, for checking wave lengths.
+                        // Distances are in simulation meters.
+                        let start = renderer.world_to_sim(camera.position).xz();
+                        let forward = renderer.world_to_sim(camera.forward()).xz();
+                        let end = start + forward.normalize() * PROFILE_LENGTH;
+                        match renderer.simulation.dump_profile(
+                            (start, end),
+                            PROFILE_SAMPLES,
+                            "profile.csv",
+                        ) {
+                            Ok(()) => info!("Wrote profile.csv"),
+                            Err(e) => error!("Failed to write profile: {:?}", e),
+                        }
+                    }
+                    (VirtualKeyCode::F4, ElementState::Pressed) => {
+                        // The world uv scale stretches the ray, its distance is scaled back
+                        let dir = renderer.world_to_sim(camera.forward());
+                        let hit = renderer
+                            .simulation
+                            .nearest_crest(renderer.world_to_sim(camera.position), dir)
+                            .map(|t| t / dir.norm());
+                        match hit {
+                            Some(distance) => info!("Water surface at {:.2}m", distance),
+                            None => info!("No water surface in view"),
+                        }
+                    }
+                    (VirtualKeyCode::F5, ElementState::Pressed) => {
+                        match camera.save_to_path(Path::new(CAMERA_FILE)) {
+                            Ok(()) => info!("Saved camera to {}", CAMERA_FILE),
+                            Err(e) => error!("Failed to save camera: {:?}", e),
+                        }
+                    }
+                    #[cfg(feature = "shader-reload")]
+                    (VirtualKeyCode::F6, ElementState::Pressed) => {
+                        match renderer.reload_shaders() {
+                            Ok(()) => info!("Reloaded shaders"),
+                            Err(e) => error!("Failed to reload shaders: {}", e),
+                        }
+                    }
+                    (VirtualKeyCode::F8, ElementState::Pressed) => {
+                        if recording_waves {
+                            renderer.simulation.clear_frame_data();
+                            recording_waves = false;
+                            info!("Stopped recording to {}", WAVE_RECORDING_FILE);
+                        } else {
+                            match wave_recorder(Path::new(WAVE_RECORDING_FILE)) {
+                                Ok(recorder) => {
+                                    renderer.simulation.on_frame_data(
+                                        &[
+                                            SimMap::Displacement,
+                                            SimMap::Derivatives,
+                                            SimMap::Turbulence,
+                                        ],
+                                        recorder,
+                                    );
+                                    recording_waves = true;
+                                    info!("Recording waves to {}", WAVE_RECORDING_FILE);
+                                }
+                                Err(e) => error!("Failed to start recording: {}", e),
+                            }
+                        }
+                    }
+                    (VirtualKeyCode::F9, ElementState::Pressed) => {
+                        match Camera::load_from_path(Path::new(CAMERA_FILE)) {
+                            Ok(pose) => {
+                                camera.apply_pose(pose);
+                                info!("Loaded camera from {}", CAMERA_FILE);
+                            }
+                            Err(e) => error!("Failed to load camera: {:?}", e),
+                        }
+                    }
+                    (VirtualKeyCode::F11, ElementState::Pressed) => {
+                        let [width, height] = CAPTURE_SIZE;
+                        renderer.capture_offscreen(
+                            Path::new(CAPTURE_FILE),
+                            width,
+                            height,
+                            water_caches.iter().map(|(_, cache)| cache),
+                        );
+                    }
+                    (VirtualKeyCode::F12, ElementState::Pressed) => {
+                        renderer.capture_screenshot(Path::new(SCREENSHOT_FILE));
+                    }
+                    (VirtualKeyCode::C, ElementState::Pressed) => {
+                        info!("Share code: {}", renderer.share_code());
+                    }
+                    (
+                        VirtualKeyCode::LBracket | VirtualKeyCode::RBracket,
+                        ElementState::Pressed,
+                    ) => {
+                        let (angle, strength) = renderer.simulation.crossing_sea();
+                        let step = if keycode == VirtualKeyCode::LBracket {
+                            -CROSSING_ANGLE_STEP
+                        } else {
+                            CROSSING_ANGLE_STEP
+                        };
+                        renderer.simulation.set_crossing_sea(angle + step, strength);
+                    }
+                    (
+                        VirtualKeyCode::Semicolon | VirtualKeyCode::Apostrophe,
+                        ElementState::Pressed,
+                    ) => {
+                        let (angle, strength) = renderer.simulation.crossing_sea();
+                        let step = if keycode == VirtualKeyCode::Semicolon {
+                            -SECONDARY_STRENGTH_STEP
+                        } else {
+                            SECONDARY_STRENGTH_STEP
+                        };
+                        renderer.simulation.set_crossing_sea(angle, strength + step);
+                    }
+                    (
+                        VirtualKeyCode::Key1
+                        | VirtualKeyCode::Key2
+                        | VirtualKeyCode::Key3
+                        | VirtualKeyCode::Key4,
+                        ElementState::Pressed,
+                    ) => {
+                        let preset = match keycode {
+                            VirtualKeyCode::Key1 => SpectrumPreset::Calm,
+                            VirtualKeyCode::Key2 => SpectrumPreset::Breeze,
+                            VirtualKeyCode::Key3 => SpectrumPreset::Choppy,
+                            _ => SpectrumPreset::Storm,
+                        };
+                        renderer.simulation.apply_preset(preset);
+                        info!("Sea state: {:?}", preset);
+                    }
+                    (VirtualKeyCode::Left | VirtualKeyCode::Right, ElementState::Pressed) => {
+                        let step = if keycode == VirtualKeyCode::Left {
+                            -WIND_TURN_STEP
+                        } else {
+                            WIND_TURN_STEP
+                        };
+                        let target = renderer.simulation.target_wind_angle() + step;
+                        renderer.simulation.turn_wind_to(target);
+                        info!("Wind turning to {:.0} degrees", target.to_degrees());
+                    }
+                    (VirtualKeyCode::PageUp | VirtualKeyCode::PageDown, ElementState::Pressed) => {
+                        let factor = if keycode == VirtualKeyCode::PageUp {
+                            EXPOSURE_STEP
+                        } else {
+                            1.0 / EXPOSURE_STEP
+                        };
+                        renderer.set_exposure(renderer.exposure() * factor);
+                        info!("Exposure: {:.2}", renderer.exposure());
+                    }
+                    _ => {}
+                },
+
+                WindowEvent::Focused(focused) => {
+                    if focused {
+                        renderer
+                            .window()
+                            .set_cursor_grab(winit::window::CursorGrabMode::Confined)
+                            .unwrap();
                     } else {
-                        move_dir.z = 0;
+                        // Releases while unfocused are lost, don't keep moving or dragging
+                        input.release_all();
+                        modifiers = ModifiersState::empty();
+                        mouse_held = false;
                     }
                 }
-                _ => {}
-            },
-
-            WindowEvent::Focused(focused) => {
-                if focused {
-                    renderer
-                        .window()
-                        .set_cursor_grab(winit::window::CursorGrabMode::Confined)
-                        .unwrap();
+
+                WindowEvent::ModifiersChanged(state) => {
+                    modifiers = state;
                 }
-            }
 
-            WindowEvent::CloseRequested => {
-                *control_flow = ControlFlow::Exit;
-            }
+                WindowEvent::CloseRequested => {
+                    *control_flow = ControlFlow::Exit;
+                }
 
-            WindowEvent::Resized(_) => {
-                renderer.recreate_swapchain();
-            }
+                WindowEvent::Resized(_) => {
+                    renderer.recreate_swapchain();
+                }
+
+                WindowEvent::MouseInput {
+                    state,
+                    button: MouseButton::Left,
+                    ..
+                } => {
+                    mouse_held = state == ElementState::Pressed;
+                }
 
-            _ => {}
-        },
+                WindowEvent::CursorMoved { position, .. } => {
+                    cursor_position = Vec2::new(position.x as f32, position.y as f32);
+                }
+
+                WindowEvent::MouseInput {
+                    state: ElementState::Pressed,
+                    button: MouseButton::Right,
+                    ..
+                } => {
+                    let size = renderer.window().inner_size();
+                    let ndc = cursor_position
+                        .component_div(&Vec2::new(size.width as f32, size.height as f32))
+                        * 2.0
+                        - Vec2::new(1.0, 1.0);
+                    if let Some(hit) = camera.pick_water_plane(ndc) {
+                        let p = up_axis.to_sim(hit).xz();
+                        let samples = water::map_samples(p, renderer.world_uv_scale());
+                        let [x, z]: [f32; 2] = samples.dominant().into();
+                        renderer.simulation.add_ripple(x, z, RIPPLE_STRENGTH);
+                    }
+                }
 
-        Event::DeviceEvent { event, .. } => match event {
-            winit::event::DeviceEvent::MouseMotion { delta } => {
-                camera.on_mouse_dlta(delta.0 as f32, delta.1 as f32);
+                WindowEvent::MouseWheel { delta, .. } => {
+                    let lines = match delta {
+                        MouseScrollDelta::LineDelta(_, y) => y,
+                        // Roughly one line per 20 pixels on touchpads
+                        MouseScrollDelta::PixelDelta(p) => p.y as f32 / 20.0,
+                    };
+                    camera.zoom(lines);
+                }
+
+                _ => {}
             }
+        }
 
-            _ => {}
-        },
+        Event::DeviceEvent {
+            event: winit::event::DeviceEvent::MouseMotion { delta },
+            ..
+        } if camera.mode() == CameraMode::Free || mouse_held => {
+            camera.on_mouse_dlta(delta.0 as f32, delta.1 as f32);
+        }
 
         Event::RedrawEventsCleared => {
             renderer.window().request_redraw();
         }
 
         Event::RedrawRequested(_) => {
-            let curr_time = std::time::Instant::now();
-            let delta_time = curr_time.duration_since(last_frame_time).as_secs_f32();
-            last_frame_time = curr_time;
+            if config_polled.elapsed() >= CONFIG_POLL_INTERVAL {
+                config_polled = Instant::now();
+                let modified = Config::modified(config_path);
+                if modified != config_modified {
+                    config_modified = modified;
+                    // A broken edit keeps the running settings, the file is read again on save
+                    match Config::load(config_path) {
+                        Ok(reloaded) => {
+                            if reloaded.simulation.texture_size != config.simulation.texture_size
+                                || reloaded.simulation.gpu_noise != config.simulation.gpu_noise
+                            {
+                                warn!("Texture size and noise changes only apply after a restart");
+                            }
+                            reloaded.apply(&mut renderer);
+                            reloaded.apply_camera(&mut camera);
+                            reloaded.apply_keys(&mut input);
+                            if reloaded.render.water_rings != water_rings {
+                                water_rings = reloaded.render.water_rings;
+                                // Fewer rings would keep the outer resolutions' meshes around
+                                water.clear_mesh_cache();
+                                water_tile = None;
+                            }
+                            info!("Reloaded {}", config_path.display());
+                        }
+                        Err(e) => warn!("Failed to reload {}: {}", config_path.display(), e),
+                    }
+                }
 
-            println!("Frame Rate: {:.2}", 1.0 / delta_time);
-            renderer.run_sim(delta_time);
+                #[cfg(feature = "shader-reload")]
+                {
+                    let modified = shader_reload::sources_modified();
+                    if modified != shaders_modified {
+                        shaders_modified = modified;
+                        match renderer.reload_shaders() {
+                            Ok(()) => info!("Reloaded shaders"),
+                            Err(e) => error!("Failed to reload shaders: {}", e),
+                        }
+                    }
+                }
+            }
 
-            previous_frame_end
-                .as_mut()
-                .take()
-                .unwrap()
-                .cleanup_finished();
+            let delta_time = renderer.advance_frame();
+            if let Some(timings) = renderer.simulation.last_timings() {
+                trace!(
+                    "Simulation GPU ms: init {:.3}, time {:.3}, ifft {:.3?}, merger {:.3}",
+                    timings.init, timings.time_spec, timings.ifft, timings.merger
+                );
+            }
+            previous_frame_end.as_mut().unwrap().cleanup_finished();
 
-            let updated = camera.tick(&move_dir, delta_time, renderer.aspect_ratio);
+            #[cfg(feature = "gamepad")]
+            input.poll_gamepad();
+            camera.on_stick_look(input.analog_look(), delta_time);
+            let updated = camera.tick(&input.move_dir(), delta_time, renderer.aspect_ratio);
             if updated {
                 renderer.set_camera(&camera);
             }
 
+            let tile = water.tile_at(camera.position);
+            let new_tile = water_tile != Some(tile);
+            if new_tile {
+                water_caches = water
+                    .tiles_around(camera.position, water_rings)
+                    .into_iter()
+                    .map(|(res, instances)| {
+                        let cache = renderer
+                            .get_draw_cache(
+                                &water.get_mesh_for_res(res),
+                                &instances,
+                                water_descriptor_writes(&renderer),
+                            )
+                            .unwrap_or_else(|e| panic!("Failed to create water draw cache: {}", e));
+                        (instances, cache)
+                    })
+                    .collect();
+                water_tile = Some(tile);
+            }
+            // Only the tiles in view get drawn, looked at again whenever the camera changes
+            if updated || new_tile {
+                let view_proj = camera.view_projection_raw();
+                for (instances, cache) in &mut water_caches {
+                    cache
+                        .set_instances(
+                            &renderer.context.memory_allocator,
+                            &water.visible_instances(instances, &view_proj),
+                        )
+                        .expect("Failed to update water instances");
+                }
+            }
+
+            if show_stats {
+                let pos = camera.position;
+                renderer.set_overlay_text(&[
+                    format!(
+                        "{:.0} FPS  {:.2} MS",
+                        renderer.frame_stats().smoothed_fps,
+                        delta_time * 1000.0
+                    ),
+                    format!(
+                        "CAMERA {:.1} {:.1} {:.1}{}",
+                        pos.x,
+                        pos.y,
+                        pos.z,
+                        if renderer.is_underwater() {
+                            "  UNDERWATER"
+                        } else {
+                            ""
+                        }
+                    ),
+                    format!(
+                        "WIND {:.1} M/S",
+                        renderer.simulation.spectrum_params().wind_speed
+                    ),
+                    format!(
+                        "TIME {:.1} S{}",
+                        renderer.simulation.time,
+                        if renderer.simulation.check_output_sane() {
+                            ""
+                        } else {
+                            "  NAN"
+                        }
+                    ),
+                    format!("WAVES {:?}", renderer.wave_backend()),
+                    {
+                        let peak = renderer.simulation.peak_wavelength();
+                        let mapping = renderer.simulation.world_mapping();
+                        format!(
+                            "PEAK {:.1} M  {:.0} TEXELS",
+                            peak,
+                            mapping.wavelength_to_texels(peak)
+                        )
+                    },
+                    {
+                        let (threshold, injection) = renderer.simulation.foam_generation();
+                        format!("FOAM {:.2} {:.1}/S", threshold, injection)
+                    },
+                ]);
+            }
+
             renderer.start();
-            renderer.render(&water_cache);
+            for (_, cache) in &water_caches {
+                renderer.render(cache);
+            }
             renderer.finish(&mut previous_frame_end);
         }
         _ => (),
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::*;
+
+    // The --headless path end to end, no window involved
+    #[test]
+    fn headless_run_writes_displacement_png() {
+        let path = std::env::temp_dir().join("headless_displacement_test.png");
+        let _ = std::fs::remove_file(&path);
+        if let Err(e) = run_headless(64, NoiseSource::Cpu, None, &path) {
+            eprintln!("Skipping, no Vulkan device: {}", e);
+            return;
+        }
+
+        let decoder = png::Decoder::new(File::open(&path).unwrap());
+        let reader = decoder.read_info().unwrap();
+        assert_eq!(
+            (reader.info().width, reader.info().height),
+            (64, 64),
+            "PNG should be one texel per displacement texel"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
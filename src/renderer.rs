@@ -1,49 +1,78 @@
-use std::{mem, sync::Arc};
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufWriter},
+    mem,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
 
+use log::{error, info, warn};
+use nalgebra_glm::{Vec2, Vec3};
+#[cfg(feature = "shader-reload")]
+use shaderc::ShaderKind;
 use vulkano::{
-    VulkanLibrary,
     buffer::{BufferUsage, CpuAccessibleBuffer, TypedBufferAccess},
     command_buffer::{
-        AutoCommandBufferBuilder, CommandBufferUsage, PrimaryAutoCommandBuffer,
-        RenderPassBeginInfo, SubpassContents, allocator::StandardCommandBufferAllocator,
+        AutoCommandBufferBuilder, CommandBufferUsage, CopyImageToBufferInfo,
+        PrimaryAutoCommandBuffer, PrimaryCommandBufferAbstract, RenderPassBeginInfo,
+        SubpassContents,
+    },
+    descriptor_set::{
+        PersistentDescriptorSet, WriteDescriptorSet, allocator::StandardDescriptorSetAllocator,
+        layout::DescriptorSetLayoutCreateInfo,
     },
-    descriptor_set::{WriteDescriptorSet, allocator::StandardDescriptorSetAllocator},
-    device::{
-        self, Device, DeviceCreateInfo, Queue, QueueCreateInfo, physical::PhysicalDeviceType,
+    device::Device,
+    format::{ClearValue, Format, NumericType},
+    image::{
+        AttachmentImage, ImageAccess, ImageCreateFlags, ImageDimensions, ImageUsage,
+        ImageViewAbstract, SampleCount, StorageImage, SwapchainImage, view::ImageView,
     },
-    format::Format,
-    image::{AttachmentImage, ImageAccess, SwapchainImage, view::ImageView},
     memory::allocator::StandardMemoryAllocator,
     pipeline::{
         GraphicsPipeline, Pipeline, PipelineBindPoint,
         graphics::{
             depth_stencil::DepthStencilState,
-            input_assembly::InputAssemblyState,
-            rasterization::{CullMode, RasterizationState},
+            input_assembly::{InputAssemblyState, PrimitiveTopology},
+            multisample::MultisampleState,
+            rasterization::{CullMode, PolygonMode, RasterizationState},
+            tessellation::TessellationState,
             vertex_input::BuffersDefinition,
             viewport::{Viewport, ViewportState},
         },
     },
     render_pass::{Framebuffer, FramebufferCreateInfo, RenderPass, Subpass},
     sampler::{Filter, Sampler, SamplerAddressMode, SamplerCreateInfo},
+    shader::{ShaderModule, ShaderStages},
     swapchain::{
         self, AcquireError, PresentMode, Surface, Swapchain, SwapchainAcquireFuture,
         SwapchainCreateInfo, SwapchainCreationError, SwapchainPresentInfo,
     },
     sync::{self, FlushError, GpuFuture},
 };
-use vulkano_win::VkSurfaceBuild;
-use winit::window::{Window, WindowBuilder};
+use winit::window::Window;
 
+#[cfg(feature = "shader-reload")]
+use crate::shader_reload::{self, ShaderReloadError};
 use crate::{
-    camera::Camera,
+    axis::UpAxis,
+    camera::{Camera, DEFAULT_FAR_PLANE, DEFAULT_NEAR_PLANE},
     draw_cache::DrawCache,
-    instance::{Instance, Mesh, Vertex},
-    simulation::Simulation,
+    gpu_context::{ContextError, GpuContext, ValidationConfig},
+    instance::{Instance, Mesh, MeshError, MeshTopology, Vertex},
+    overlay::{self, GlyphInstance},
+    share_code::{ShareCodeError, ShareState},
+    simulation::{
+        DEFAULT_CASCADE_LENGTH_SCALES, FLOW_MAP_EXTENT, MAX_CASCADES, NoiseSource, Simulation,
+    },
+    skybox::{self, DEFAULT_SKY_COLOR, SkyboxError},
+    water,
 };
 
 vulkano::impl_vertex!(Vertex, position, uv);
-vulkano::impl_vertex!(Instance, instance_model, instance_normal);
+vulkano::impl_vertex!(Instance, instance_model, instance_normal, uv_transform);
+vulkano::impl_vertex!(GlyphInstance, cell, glyph);
 
 mod water_vert {
     vulkano_shaders::shader! {
@@ -67,11 +96,819 @@ mod water_frag {
         },
     }
 }
+mod water_patch_vert {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        path: "src/shaders/water_patch.vert",
+    }
+}
+mod water_tesc {
+    vulkano_shaders::shader! {
+        ty: "tess_ctrl",
+        path: "src/shaders/water.tesc",
+    }
+}
+mod water_tese {
+    vulkano_shaders::shader! {
+        ty: "tess_eval",
+        path: "src/shaders/water.tese",
+    }
+}
+mod water_gbuffer_frag {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/shaders/water.frag",
+        define: [("DEFERRED", "1")],
+        types_meta: {
+            use bytemuck::{Pod, Zeroable};
+
+            #[derive(Clone, Copy, Zeroable, Pod)]
+        },
+    }
+}
+mod compass_vert {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        path: "src/shaders/compass.vert",
+        types_meta: {
+            use bytemuck::{Pod, Zeroable};
+
+            #[derive(Clone, Copy, Zeroable, Pod)]
+        },
+    }
+}
+mod compass_frag {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/shaders/compass.frag",
+    }
+}
+mod seabed_vert {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        path: "src/shaders/seabed.vert",
+        types_meta: {
+            use bytemuck::{Pod, Zeroable};
+
+            #[derive(Clone, Copy, Zeroable, Pod)]
+        },
+    }
+}
+mod seabed_frag {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/shaders/seabed.frag",
+    }
+}
+mod skybox_vert {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        path: "src/shaders/skybox.vert",
+        types_meta: {
+            use bytemuck::{Pod, Zeroable};
+
+            #[derive(Clone, Copy, Zeroable, Pod)]
+        },
+    }
+}
+mod skybox_frag {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/shaders/skybox.frag",
+    }
+}
+mod upscale_vert {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        path: "src/shaders/upscale.vert",
+    }
+}
+mod upscale_frag {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/shaders/upscale.frag",
+    }
+}
+mod tonemap_frag {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/shaders/tonemap.frag",
+        types_meta: {
+            use bytemuck::{Pod, Zeroable};
+
+            #[derive(Clone, Copy, Zeroable, Pod)]
+        },
+    }
+}
+mod text_vert {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        path: "src/shaders/text.vert",
+        types_meta: {
+            use bytemuck::{Pod, Zeroable};
+
+            #[derive(Clone, Copy, Zeroable, Pod)]
+        },
+    }
+}
+mod text_frag {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/shaders/text.frag",
+    }
+}
+
+// Some compositors advertise Mailbox but keep failing with it, after this many
+// consecutive failed frames we drop it from the list and fall back
+const MAX_PRESENT_FAILURES: u32 = 5;
+const MIN_RENDER_SCALE: f32 = 0.1;
+// Forward scene color, lit values above 1 survive until the tone map pass
+const HDR_FORMAT: Format = Format::R16G16B16A16_SFLOAT;
+const GBUFFER_NORMAL_FORMAT: Format = Format::R16G16B16A16_SFLOAT;
+const GBUFFER_ALBEDO_FORMAT: Format = Format::R8G8B8A8_UNORM;
+const GBUFFER_ROUGHNESS_FORMAT: Format = Format::R8_UNORM;
+// Screen pixels per font pixel of the overlay text
+const OVERLAY_TEXT_SCALE: f32 = 2.0;
+// Forward scene background, only visible where the skybox doesn't cover (linear RGBA)
+pub const DEFAULT_CLEAR_COLOR: [f32; 4] = [0.1, 0.7, 0.9, 1.0];
+// Water tint up close and far away, linear RGB. The same by default, one flat color.
+pub const DEFAULT_WATER_COLOR: [f32; 4] = [0.03457636, 0.12297464, 0.1981132, 1.0];
+// Meters from the camera over which the tint goes most of the way to the deep color
+pub const DEFAULT_DEEP_WATER_DISTANCE: f32 = 200.0;
+// Size of MaterialParams.foamGradient in water.frag
+pub const MAX_FOAM_STOPS: usize = 4;
+// Jacobian below which foam shows, calm water sits around 1
+pub const DEFAULT_FOAM_BIAS: f32 = 0.84;
+// See set_cascade_foam_weight, largest patch first. The swell cascade only folds on its
+// steepest crests and then over a wide area, at full weight that shows as smeared patches.
+pub const DEFAULT_CASCADE_FOAM_WEIGHTS: [f32; MAX_CASCADES] = [0.5, 1.0, 1.0, 1.0];
+// Foam color by foam amount (0 = none, 1 = fully churned), linear RGB: thin foam over the
+// breaking crests stays light blue, the thick churn in the troughs goes white
+pub const DEFAULT_FOAM_GRADIENT: [([f32; 3], f32); 2] =
+    [([0.62, 0.85, 0.95], 0.0), ([1.0, 1.0, 1.0], 0.6)];
+// Horizon color (linear RGB, the default sky's) and density per meter of the fog over the far
+// water tiles. Mostly fogged by the edge of the demo's grid, around 700m out.
+pub const DEFAULT_DISTANCE_FOG: [f32; 4] = [0.1, 0.7, 0.9, 0.0025];
+// Below the surface: fog color (linear RGB) and density per meter, and the post tint
+pub const DEFAULT_UNDERWATER_FOG: [f32; 4] = [0.01, 0.08, 0.09, 0.05];
+const UNDERWATER_TINT: [f32; 3] = [0.55, 0.85, 0.8];
+// Meters on either side of the surface the underwater look fades in over
+const UNDERWATER_BLEND: f32 = 0.3;
+// Weight of the newest frame in FrameStats::smoothed_fps
+pub const DEFAULT_FPS_SMOOTHING: f32 = 0.05;
+// Adaptive quality steps down below this fraction of the target FPS and back up above the
+// other one, anything in between holds the current level
+const ADAPTIVE_DOWN_MARGIN: f32 = 0.9;
+const ADAPTIVE_UP_MARGIN: f32 = 1.25;
+// Seconds the smoothed FPS has to stay past a margin before a step. Going up waits longer,
+// a step that doesn't fit gets taken back quickly but is retried only slowly.
+const ADAPTIVE_DOWN_DELAY: f32 = 1.0;
+const ADAPTIVE_UP_DELAY: f32 = 4.0;
+// Floor of the adaptive steps, the smallest cascades and render scales it goes down to
+const ADAPTIVE_MIN_CASCADES: usize = 2;
+const ADAPTIVE_MIN_RENDER_SCALE: f32 = 0.5;
+const ADAPTIVE_RENDER_SCALE_STEP: f32 = 0.1;
+// Ordered dither on the tone mapped output, in 8 bit steps
+pub const DEFAULT_DITHER_STRENGTH: f32 = 1.0;
 
 fn get_window(surface: &Arc<Surface>) -> &Window {
     surface.object().unwrap().downcast_ref::<Window>().unwrap()
 }
 
+fn choose_present_mode(
+    device: &Arc<Device>,
+    surface: &Surface,
+    priority: &[PresentMode],
+) -> PresentMode {
+    let supported: Vec<PresentMode> = device
+        .physical_device()
+        .surface_present_modes(surface)
+        .unwrap()
+        .collect();
+
+    priority
+        .iter()
+        .copied()
+        .find(|mode| supported.contains(mode))
+        .unwrap_or(PresentMode::Fifo)
+}
+
+// Raw data views written by water.frag in place of the lit color. They skip lighting and
+// undo the sRGB encode, so what's on screen is the value itself (0.5 shows as mid gray).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DebugView {
+    #[default]
+    Shaded,
+    // World normal remapped from -1..1 to 0..1, the one the lighting uses: rebuilt per pixel
+    // from all cascades of the derivatives map (see Simulation::derivatives_map)
+    Normals,
+    // Foam amount from the jacobian, contact and shore foam, 0..1
+    Foam,
+    // Height of the displaced surface, dark blue at -DEBUG_HEIGHT_RANGE up to white at
+    // +DEBUG_HEIGHT_RANGE meters (water.frag)
+    Height,
+    // Slope angle, green when flat through yellow to red at 45 degrees and steeper
+    Steepness,
+    // Position on the largest cascade's patch, red/green 0..1 per repeat
+    Uv,
+}
+
+impl DebugView {
+    pub fn next(self) -> Self {
+        match self {
+            DebugView::Shaded => DebugView::Normals,
+            DebugView::Normals => DebugView::Foam,
+            DebugView::Foam => DebugView::Height,
+            DebugView::Height => DebugView::Steepness,
+            DebugView::Steepness => DebugView::Uv,
+            DebugView::Uv => DebugView::Shaded,
+        }
+    }
+
+    fn shader_id(self) -> u32 {
+        match self {
+            DebugView::Shaded => 0,
+            DebugView::Normals => 1,
+            DebugView::Foam => 2,
+            DebugView::Height => 3,
+            DebugView::Steepness => 4,
+            DebugView::Uv => 5,
+        }
+    }
+}
+
+// How the water surface moves. Fft is the compute simulation, Gerstner is a handful of
+// analytic waves summed in water.vert for devices that can't create the compute pipelines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaveBackend {
+    Fft,
+    Gerstner,
+}
+
+impl WaveBackend {
+    fn shader_id(self) -> u32 {
+        match self {
+            WaveBackend::Fft => 0,
+            WaveBackend::Gerstner => 1,
+        }
+    }
+}
+
+// Direction x/z, steepness (sum stays below 1 so crests don't loop), wavelength in meters
+const GERSTNER_WAVES: [[f32; 4]; 4] = [
+    [1.0, 0.3, 0.25, 60.0],
+    [1.0, 0.6, 0.2, 31.0],
+    [1.0, 1.3, 0.15, 18.0],
+    [0.7, -0.4, 0.15, 9.0],
+];
+
+#[derive(Debug)]
+pub enum RendererError {
+    // No Vulkan, or no device that can draw to the window
+    Context(ContextError),
+}
+
+impl From<ContextError> for RendererError {
+    fn from(e: ContextError) -> Self {
+        RendererError::Context(e)
+    }
+}
+
+impl fmt::Display for RendererError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RendererError::Context(e) => e.fmt(f),
+        }
+    }
+}
+
+// What Renderer::new picks the present mode from, the closest one the surface supports wins
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PresentPreference {
+    // Fifo, capped to the refresh rate, the one to use on battery
+    VSync,
+    // No tearing but not capped, Fifo where it's missing
+    #[default]
+    Mailbox,
+    // Uncapped and tearing, for benchmarking. Mailbox is the next closest, then Fifo.
+    Immediate,
+}
+
+impl PresentPreference {
+    // Names the [render] table of the config uses
+    pub fn from_name(name: &str) -> Option<PresentPreference> {
+        match name {
+            "vsync" => Some(PresentPreference::VSync),
+            "mailbox" => Some(PresentPreference::Mailbox),
+            "immediate" => Some(PresentPreference::Immediate),
+            _ => None,
+        }
+    }
+
+    // Modes are tried in order, first one the surface supports wins. Fifo is always supported.
+    fn priority(self) -> Vec<PresentMode> {
+        match self {
+            PresentPreference::VSync => vec![PresentMode::Fifo],
+            PresentPreference::Mailbox => vec![PresentMode::Mailbox, PresentMode::Fifo],
+            PresentPreference::Immediate => vec![
+                PresentMode::Immediate,
+                PresentMode::Mailbox,
+                PresentMode::Fifo,
+            ],
+        }
+    }
+}
+
+// Forward lights the water and writes the final color. Deferred writes the surface
+// into a G-buffer instead, so a host renderer can light it together with its own scene.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RenderMode {
+    #[default]
+    Forward,
+    Deferred,
+}
+
+// Deferred mode outputs, render_scale times the window size, recreated with the swapchain
+pub struct GBuffer {
+    pub normal: Arc<ImageView<AttachmentImage>>,
+    pub albedo: Arc<ImageView<AttachmentImage>>,
+    pub roughness: Arc<ImageView<AttachmentImage>>,
+    pub depth: Arc<ImageView<AttachmentImage>>,
+}
+
+// The G-buffer attachment the window shows in deferred mode, as raw values like DebugView
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum GBufferView {
+    #[default]
+    Albedo,
+    // World normal, only the positive half of each axis shows
+    Normal,
+    // In the red channel
+    Roughness,
+}
+
+impl GBufferView {
+    pub fn next(self) -> Self {
+        match self {
+            GBufferView::Albedo => GBufferView::Normal,
+            GBufferView::Normal => GBufferView::Roughness,
+            GBufferView::Roughness => GBufferView::Albedo,
+        }
+    }
+}
+
+impl GBuffer {
+    fn view(&self, view: GBufferView) -> Arc<ImageView<AttachmentImage>> {
+        match view {
+            GBufferView::Albedo => self.albedo.clone(),
+            GBufferView::Normal => self.normal.clone(),
+            GBufferView::Roughness => self.roughness.clone(),
+        }
+    }
+}
+
+// Timing of the last frame run_sim was called for
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct FrameStats {
+    // Seconds
+    pub delta_time: f32,
+    pub fps: f32,
+    // Exponential moving average of fps, steady enough to show on screen
+    pub smoothed_fps: f32,
+}
+
+// Offscreen target the water gets rendered into, render_scale times the window size.
+// finish stretches it over the swapchain image.
+struct SceneTarget {
+    framebuffer: Arc<Framebuffer>,
+    upscale_set: Arc<PersistentDescriptorSet>,
+    viewport: Viewport,
+    gbuffer: Option<GBuffer>,
+}
+
+// Depth of the scene without the water, render_scale times the window size like SceneTarget.
+// The depth prepass draws into it, the water samples it through set (set 3 of its pipelines).
+struct SceneDepth {
+    framebuffer: Arc<Framebuffer>,
+    set: Arc<PersistentDescriptorSet>,
+    // False until the prepass has run on it once, a frozen depth still needs that first pass
+    drawn: bool,
+}
+
+// Where the red, green and blue bytes sit in a texel of the swapchain format, None for formats
+// screenshots don't handle. sRGB and UNORM both hold the encoded values the display shows.
+fn screenshot_channels(format: Format) -> Option<[usize; 3]> {
+    match format {
+        Format::B8G8R8A8_SRGB | Format::B8G8R8A8_UNORM => Some([2, 1, 0]),
+        Format::R8G8B8A8_SRGB | Format::R8G8B8A8_UNORM => Some([0, 1, 2]),
+        _ => None,
+    }
+}
+
+pub fn write_png(path: &Path, dimensions: [u32; 2], rgba: &[u8]) -> io::Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, dimensions[0], dimensions[1]);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_source_srgb(png::SrgbRenderingIntent::Perceptual);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(rgba)?;
+    writer.finish()?;
+    Ok(())
+}
+
+fn scale_dimensions(dimensions: [u32; 2], scale: f32) -> [u32; 2] {
+    dimensions.map(|d| ((d as f32 * scale) as u32).max(1))
+}
+
+// samples only applies to forward mode, the G-buffer is always single sampled
+fn create_scene_pass(
+    device: &Arc<Device>,
+    render_mode: RenderMode,
+    samples: SampleCount,
+) -> Arc<RenderPass> {
+    match render_mode {
+        RenderMode::Forward if samples != SampleCount::Sample1 => {
+            vulkano::ordered_passes_renderpass!(device.clone(),
+                attachments: {
+                    msaa_color: {
+                        load: Clear,
+                        store: DontCare,
+                        format: HDR_FORMAT,
+                        samples: samples as u32,
+                    },
+                    depth: {
+                        load: Clear,
+                        store: DontCare,
+                        format: Format::D16_UNORM,
+                        samples: samples as u32,
+                    },
+                    // Resolved at the end of the pass, this is what gets tone mapped
+                    scene_color: {
+                        load: DontCare,
+                        store: Store,
+                        format: HDR_FORMAT,
+                        samples: 1,
+                    }
+                },
+                passes: [
+                    {
+                        color: [msaa_color],
+                        depth_stencil: {depth},
+                        input: [],
+                        resolve: [scene_color]
+                    }
+                ]
+            )
+            .unwrap()
+        }
+        RenderMode::Forward => vulkano::ordered_passes_renderpass!(device.clone(),
+            attachments: {
+                scene_color: {
+                    load: Clear,
+                    store: Store,
+                    format: HDR_FORMAT,
+                    samples: 1,
+                },
+                depth: {
+                    load: Clear,
+                    store: DontCare,
+                    format: Format::D16_UNORM,
+                    samples: 1,
+                }
+            },
+            passes: [
+                {
+                    color: [scene_color],
+                    depth_stencil: {depth},
+                    input: []
+                }
+            ]
+        )
+        .unwrap(),
+        // Depth is kept so whoever does the lighting can rebuild positions
+        RenderMode::Deferred => vulkano::ordered_passes_renderpass!(device.clone(),
+            attachments: {
+                normal: {
+                    load: Clear,
+                    store: Store,
+                    format: GBUFFER_NORMAL_FORMAT,
+                    samples: 1,
+                },
+                albedo: {
+                    load: Clear,
+                    store: Store,
+                    format: GBUFFER_ALBEDO_FORMAT,
+                    samples: 1,
+                },
+                roughness: {
+                    load: Clear,
+                    store: Store,
+                    format: GBUFFER_ROUGHNESS_FORMAT,
+                    samples: 1,
+                },
+                depth: {
+                    load: Clear,
+                    store: Store,
+                    format: Format::D16_UNORM,
+                    samples: 1,
+                }
+            },
+            passes: [
+                {
+                    color: [normal, albedo, roughness],
+                    depth_stencil: {depth},
+                    input: []
+                }
+            ]
+        )
+        .unwrap(),
+    }
+}
+
+// Opaque geometry other than the water, depth only, drawn before the scene pass
+fn create_depth_pass(device: &Arc<Device>) -> Arc<RenderPass> {
+    vulkano::single_pass_renderpass!(device.clone(),
+        attachments: {
+            depth: {
+                load: Clear,
+                store: Store,
+                format: Format::D16_UNORM,
+                samples: 1,
+            }
+        },
+        pass: {
+            color: [],
+            depth_stencil: {depth}
+        }
+    )
+    .unwrap()
+}
+
+// What the present pass samples, shown stretched over the swapchain image
+fn create_upscale_set(
+    descriptor_set_allocator: &StandardDescriptorSetAllocator,
+    upscale_pipeline: &Arc<GraphicsPipeline>,
+    sampler: &Arc<Sampler>,
+    shown: Arc<ImageView<AttachmentImage>>,
+) -> Arc<PersistentDescriptorSet> {
+    PersistentDescriptorSet::new(
+        descriptor_set_allocator,
+        upscale_pipeline.layout().set_layouts()[0].clone(),
+        [WriteDescriptorSet::image_view_sampler(
+            0,
+            shown,
+            sampler.clone(),
+        )],
+    )
+    .unwrap()
+}
+
+// water_pipeline is any of the water pipelines, they all share the layout of set 3
+fn create_scene_depth(
+    allocator: &StandardMemoryAllocator,
+    descriptor_set_allocator: &StandardDescriptorSetAllocator,
+    depth_pass: Arc<RenderPass>,
+    water_pipeline: &Arc<GraphicsPipeline>,
+    sampler: &Arc<Sampler>,
+    dimensions: [u32; 2],
+) -> SceneDepth {
+    let depth = ImageView::new_default(
+        AttachmentImage::sampled(allocator, dimensions, Format::D16_UNORM).unwrap(),
+    )
+    .unwrap();
+    let framebuffer = Framebuffer::new(
+        depth_pass,
+        FramebufferCreateInfo {
+            attachments: vec![depth.clone()],
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let set = PersistentDescriptorSet::new(
+        descriptor_set_allocator,
+        water_pipeline.layout().set_layouts()[3].clone(),
+        [WriteDescriptorSet::image_view_sampler(
+            0,
+            depth,
+            sampler.clone(),
+        )],
+    )
+    .unwrap();
+    SceneDepth {
+        framebuffer,
+        set,
+        drawn: false,
+    }
+}
+
+// Clears the framebuffer's depth and draws the seabed into it, if there is one
+fn record_depth_prepass(
+    commands: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+    framebuffer: Arc<Framebuffer>,
+    seabed_pipeline: &Arc<GraphicsPipeline>,
+    seabed: Option<seabed_vert::ty::Seabed>,
+) {
+    let [width, height] = framebuffer.extent();
+    commands
+        .begin_render_pass(
+            RenderPassBeginInfo {
+                clear_values: vec![Some(1.0.into())],
+                ..RenderPassBeginInfo::framebuffer(framebuffer)
+            },
+            SubpassContents::Inline,
+        )
+        .unwrap();
+    if let Some(seabed) = seabed {
+        commands
+            .set_viewport(
+                0,
+                [Viewport {
+                    origin: [0.0, 0.0],
+                    dimensions: [width as f32, height as f32],
+                    depth_range: 0.0..1.0,
+                }],
+            )
+            .bind_pipeline_graphics(seabed_pipeline.clone())
+            .push_constants(seabed_pipeline.layout().clone(), 0, seabed)
+            .draw(4, 1, 0, 0)
+            .unwrap();
+    }
+    commands.end_render_pass().unwrap();
+}
+
+// Stages of the water pipelines. The tessellation ones (patch vertex, control, evaluation) only
+// exist when the device supports them.
+#[derive(Clone)]
+struct WaterShaders {
+    vert: Arc<ShaderModule>,
+    frag: Arc<ShaderModule>,
+    tessellation: Option<[Arc<ShaderModule>; 3]>,
+}
+
+impl WaterShaders {
+    // Same shader source, the G-buffer variant is compiled with DEFERRED defined
+    fn builtin(device: &Arc<Device>, render_mode: RenderMode) -> Self {
+        WaterShaders {
+            vert: water_vert::load(device.clone()).unwrap(),
+            frag: match render_mode {
+                RenderMode::Forward => water_frag::load(device.clone()).unwrap(),
+                RenderMode::Deferred => water_gbuffer_frag::load(device.clone()).unwrap(),
+            },
+            tessellation: device.enabled_features().tessellation_shader.then(|| {
+                [
+                    water_patch_vert::load(device.clone()).unwrap(),
+                    water_tesc::load(device.clone()).unwrap(),
+                    water_tese::load(device.clone()).unwrap(),
+                ]
+            }),
+        }
+    }
+
+    // The same shaders read from disk and compiled now instead of at build time
+    #[cfg(feature = "shader-reload")]
+    fn compile(device: &Arc<Device>, render_mode: RenderMode) -> Result<Self, ShaderReloadError> {
+        let defines: &[(&str, &str)] = match render_mode {
+            RenderMode::Forward => &[],
+            RenderMode::Deferred => &[("DEFERRED", "1")],
+        };
+        let tessellation = if device.enabled_features().tessellation_shader {
+            Some([
+                shader_reload::compile(device, "water_patch.vert", ShaderKind::Vertex, &[])?,
+                shader_reload::compile(device, "water.tesc", ShaderKind::TessControl, &[])?,
+                shader_reload::compile(device, "water.tese", ShaderKind::TessEvaluation, &[])?,
+            ])
+        } else {
+            None
+        };
+        Ok(WaterShaders {
+            vert: shader_reload::compile(device, "water.vert", ShaderKind::Vertex, &[])?,
+            frag: shader_reload::compile(device, "water.frag", ShaderKind::Fragment, defines)?,
+            tessellation,
+        })
+    }
+}
+
+fn create_geometry_pipeline(
+    device: &Arc<Device>,
+    render_pass: &Arc<RenderPass>,
+    shaders: &WaterShaders,
+    topology: MeshTopology,
+    polygon_mode: PolygonMode,
+) -> Arc<GraphicsPipeline> {
+    let input_assembly = match topology {
+        MeshTopology::TriangleList => InputAssemblyState::new(),
+        MeshTopology::TriangleStrip => InputAssemblyState::new()
+            .topology(PrimitiveTopology::TriangleStrip)
+            .primitive_restart_enable(),
+        MeshTopology::Patches => InputAssemblyState::new().topology(PrimitiveTopology::PatchList),
+    };
+    let geometry_pass = Subpass::from(render_pass.clone(), 0).unwrap();
+    let builder = GraphicsPipeline::start()
+        .vertex_input_state(
+            BuffersDefinition::new()
+                .vertex::<Vertex>()
+                .instance::<Instance>(),
+        )
+        .input_assembly_state(input_assembly)
+        .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+        .fragment_shader(shaders.frag.entry_point("main").unwrap(), ())
+        .depth_stencil_state(DepthStencilState::simple_depth_test())
+        .rasterization_state(
+            RasterizationState::new()
+                .cull_mode(CullMode::None)
+                .polygon_mode(polygon_mode),
+        )
+        .multisample_state(MultisampleState {
+            rasterization_samples: geometry_pass.num_samples().unwrap(),
+            ..Default::default()
+        })
+        .render_pass(geometry_pass);
+
+    // Sets 0 and 1 are visible to every stage, so draw caches built against one water pipeline
+    // also bind to the tessellated ones. Set 2 has to keep matching the skybox's set 0, the
+    // environment set gets built against that.
+    let shared_layouts = |set_layouts: &mut [DescriptorSetLayoutCreateInfo]| {
+        for set_layout in set_layouts.iter_mut().take(2) {
+            for binding in set_layout.bindings.values_mut() {
+                binding.stages = ShaderStages::all_graphics();
+            }
+        }
+    };
+    if topology == MeshTopology::Patches {
+        let [patch_vert, tesc, tese] = shaders.tessellation.as_ref().unwrap();
+        builder
+            .vertex_shader(patch_vert.entry_point("main").unwrap(), ())
+            .tessellation_shaders(
+                tesc.entry_point("main").unwrap(),
+                (),
+                tese.entry_point("main").unwrap(),
+                (),
+            )
+            .tessellation_state(TessellationState::new().patch_control_points(3))
+            .with_auto_layout(device.clone(), shared_layouts)
+            .unwrap()
+    } else {
+        builder
+            .vertex_shader(shaders.vert.entry_point("main").unwrap(), ())
+            .with_auto_layout(device.clone(), shared_layouts)
+            .unwrap()
+    }
+}
+
+// Background for the forward scene pass, drawn first without depth so the water covers it
+fn create_skybox_pipeline(
+    device: &Arc<Device>,
+    render_pass: &Arc<RenderPass>,
+) -> Arc<GraphicsPipeline> {
+    let skybox_vert = skybox_vert::load(device.clone()).unwrap();
+    let skybox_frag = skybox_frag::load(device.clone()).unwrap();
+    let subpass = Subpass::from(render_pass.clone(), 0).unwrap();
+    GraphicsPipeline::start()
+        .vertex_input_state(BuffersDefinition::new())
+        .vertex_shader(skybox_vert.entry_point("main").unwrap(), ())
+        .input_assembly_state(InputAssemblyState::new())
+        .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+        .fragment_shader(skybox_frag.entry_point("main").unwrap(), ())
+        .rasterization_state(RasterizationState::new().cull_mode(CullMode::None))
+        .multisample_state(MultisampleState {
+            rasterization_samples: subpass.num_samples().unwrap(),
+            ..Default::default()
+        })
+        .render_pass(subpass)
+        .build(device.clone())
+        .unwrap()
+}
+
+// Flat ground, depth only in the depth prepass and a plain color in the forward scene pass
+fn create_seabed_pipeline(
+    device: &Arc<Device>,
+    render_pass: &Arc<RenderPass>,
+) -> Arc<GraphicsPipeline> {
+    let seabed_vert = seabed_vert::load(device.clone()).unwrap();
+    let subpass = Subpass::from(render_pass.clone(), 0).unwrap();
+    let builder = GraphicsPipeline::start()
+        .vertex_input_state(BuffersDefinition::new())
+        .vertex_shader(seabed_vert.entry_point("main").unwrap(), ())
+        .input_assembly_state(InputAssemblyState::new().topology(PrimitiveTopology::TriangleStrip))
+        .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+        .depth_stencil_state(DepthStencilState::simple_depth_test())
+        .rasterization_state(RasterizationState::new().cull_mode(CullMode::None))
+        .multisample_state(MultisampleState {
+            rasterization_samples: subpass.num_samples().unwrap_or(SampleCount::Sample1),
+            ..Default::default()
+        })
+        .render_pass(subpass.clone());
+    if subpass.num_color_attachments() == 0 {
+        return builder.build(device.clone()).unwrap();
+    }
+    let seabed_frag = seabed_frag::load(device.clone()).unwrap();
+    builder
+        .fragment_shader(seabed_frag.entry_point("main").unwrap(), ())
+        .build(device.clone())
+        .unwrap()
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum RenderStage {
     Stopped,
@@ -79,100 +916,223 @@ enum RenderStage {
     NeedsRedraw,
 }
 
+// check_stage without the renderer, recreate stands in for recreate_swapchain and returns the
+// stage that leaves. Gives the stage to carry on from and whether the call expecting `expected`
+// can go ahead.
+fn advance_stage(
+    current: RenderStage,
+    expected: RenderStage,
+    recreate: impl FnOnce() -> RenderStage,
+) -> (RenderStage, bool) {
+    if current == expected {
+        return (current, true);
+    }
+
+    match current {
+        RenderStage::NeedsRedraw => {
+            // Stays NeedsRedraw while the window is minimized (0x0) and gets retried next
+            // frame. On success start can carry on with this frame instead of skipping it.
+            let stage = recreate();
+            (stage, stage == expected)
+        }
+        _ => (RenderStage::Stopped, false),
+    }
+}
+
+// The settings adaptive quality turns down
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Quality {
+    render_scale: f32,
+    samples: SampleCount,
+    cascades: usize,
+}
+
+// Levels from top (index 0) down, each one step cheaper than the one before: MSAA off first,
+// then the smallest cascades one at a time, then the render scale. Nothing goes below the
+// ADAPTIVE_MIN_* floors or above what top already has.
+fn quality_ladder(top: Quality) -> Vec<Quality> {
+    let mut ladder = vec![top];
+    let mut quality = top;
+    if quality.samples != SampleCount::Sample1 {
+        quality.samples = SampleCount::Sample1;
+        ladder.push(quality);
+    }
+    while quality.cascades > ADAPTIVE_MIN_CASCADES {
+        quality.cascades -= 1;
+        ladder.push(quality);
+    }
+    while quality.render_scale > ADAPTIVE_MIN_RENDER_SCALE + f32::EPSILON {
+        quality.render_scale =
+            (quality.render_scale - ADAPTIVE_RENDER_SCALE_STEP).max(ADAPTIVE_MIN_RENDER_SCALE);
+        ladder.push(quality);
+    }
+    ladder
+}
+
+// Picks the level of quality_ladder from the smoothed FPS, with the margins and delays of
+// the ADAPTIVE_* constants as hysteresis
+struct AdaptiveQuality {
+    target_fps: f32,
+    level: usize,
+    // Seconds the smoothed FPS has been past the down and up margins, every step resets both
+    // so the average gets to settle on the new level first
+    below: f32,
+    above: f32,
+}
+
+impl AdaptiveQuality {
+    fn new(target_fps: f32) -> Self {
+        AdaptiveQuality {
+            target_fps,
+            level: 0,
+            below: 0.0,
+            above: 0.0,
+        }
+    }
+
+    // levels is the length of the ladder. Returns whether the level changed.
+    fn update(&mut self, delta_time: f32, smoothed_fps: f32, levels: usize) -> bool {
+        let previous = self.level.min(levels - 1);
+        if smoothed_fps < self.target_fps * ADAPTIVE_DOWN_MARGIN {
+            self.below += delta_time;
+            self.above = 0.0;
+        } else if smoothed_fps > self.target_fps * ADAPTIVE_UP_MARGIN {
+            self.above += delta_time;
+            self.below = 0.0;
+        } else {
+            self.below = 0.0;
+            self.above = 0.0;
+        }
+
+        self.level = if self.below >= ADAPTIVE_DOWN_DELAY {
+            (previous + 1).min(levels - 1)
+        } else if self.above >= ADAPTIVE_UP_DELAY {
+            previous.saturating_sub(1)
+        } else {
+            previous
+        };
+        if self.below >= ADAPTIVE_DOWN_DELAY || self.above >= ADAPTIVE_UP_DELAY {
+            self.below = 0.0;
+            self.above = 0.0;
+        }
+        self.level != previous
+    }
+}
+
 pub struct Renderer {
-    pub device: Arc<Device>,
+    pub context: GpuContext,
     pub aspect_ratio: f32,
 
     surface: Arc<Surface>,
-    queue: Arc<Queue>,
     swapchain: Arc<Swapchain>,
-    memory_allocator: Arc<StandardMemoryAllocator>,
-    command_buffer_allocator: StandardCommandBufferAllocator,
     render_pass: Arc<RenderPass>,
+    present_pass: Arc<RenderPass>,
     geometry_pipeline: Arc<GraphicsPipeline>,
+    // Same as geometry_pipeline for meshes with MeshTopology::TriangleStrip
+    strip_pipeline: Arc<GraphicsPipeline>,
+    // Line polygon mode versions of the two above, None without fill_mode_non_solid
+    wireframe_pipeline: Option<Arc<GraphicsPipeline>>,
+    wireframe_strip_pipeline: Option<Arc<GraphicsPipeline>>,
+    // MeshTopology::Patches, fill and line. None without tessellation_shader (the line one
+    // without fill_mode_non_solid too).
+    patch_pipeline: Option<Arc<GraphicsPipeline>>,
+    wireframe_patch_pipeline: Option<Arc<GraphicsPipeline>>,
+    // What the water pipelines were last built from, pipeline rebuilds keep using them
+    water_shaders: WaterShaders,
+    // Set once reload_shaders succeeded, a render mode switch compiles the sources again
+    #[cfg(feature = "shader-reload")]
+    shaders_reloaded: bool,
+    wireframe: bool,
+    compass_pipeline: Arc<GraphicsPipeline>,
+    text_pipeline: Arc<GraphicsPipeline>,
+    // The font bitmap, set 0 of the text pipeline
+    font_set: Arc<PersistentDescriptorSet>,
+    // Laid out by set_overlay_text, empty hides the overlay
+    overlay_glyphs: Vec<GlyphInstance>,
+    // Only drawn in forward mode, kept around in deferred for its descriptor layouts
+    skybox_pipeline: Arc<GraphicsPipeline>,
+    // The cubemap, set 0 of the skybox pipeline
+    skybox_set: Arc<PersistentDescriptorSet>,
+    // Its GGX prefiltered copy, set 2 of the forward water
+    environment_set: Arc<PersistentDescriptorSet>,
+    // Trilinear, the water picks the environment mip by roughness
+    environment_sampler: Arc<Sampler>,
+    skybox_params_set: Arc<PersistentDescriptorSet>,
+    depth_pass: Arc<RenderPass>,
+    // For the depth prepass and, in forward mode, the scene pass
+    seabed_depth_pipeline: Arc<GraphicsPipeline>,
+    seabed_pipeline: Arc<GraphicsPipeline>,
+    // Height of the seabed along the up axis, None draws none
+    seabed: Option<f32>,
+    upscale_pipeline: Arc<GraphicsPipeline>,
+    tonemap_pipeline: Arc<GraphicsPipeline>,
+    upscale_sampler: Arc<Sampler>,
+    exposure: f32,
+    dither_strength: f32,
+    clear_color: [f32; 4],
     viewport: Viewport,
     framebuffers: Vec<Arc<Framebuffer>>,
+    scene_target: SceneTarget,
+    scene_depth: SceneDepth,
+    // Skips the depth prepass and keeps the last scene depth, see freeze_depth
+    depth_frozen: bool,
+    render_scale: f32,
+    render_mode: RenderMode,
+    gbuffer_view: GBufferView,
+    // What the forward scene pass uses at full quality, after falling back for the device.
+    // The pass itself follows quality().
+    samples: SampleCount,
+    // None keeps full quality, see set_adaptive_quality
+    adaptive_quality: Option<AdaptiveQuality>,
     render_stage: RenderStage,
     commands: Option<AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>>,
     image_index: u32,
     acquire_future: Option<SwapchainAcquireFuture>,
     descriptor_set_allocator: StandardDescriptorSetAllocator,
+    present_modes: Vec<PresentMode>,
+    present_failures: u32,
+    // Set by capture_screenshot, taken by the next finish
+    screenshot_request: Option<PathBuf>,
+    frame_stats: FrameStats,
+    fps_smoothing: f32,
+    // When advance_frame last ran, None before the first call
+    last_frame: Option<Instant>,
 
     pub ocean_params_buffer: Arc<CpuAccessibleBuffer<water_frag::ty::OceanParams>>,
     pub mat_params_buffer: Arc<CpuAccessibleBuffer<water_frag::ty::MaterialParams>>,
+    pub gerstner_buffer: Arc<CpuAccessibleBuffer<water_vert::ty::GerstnerWaves>>,
+    wave_backend: WaveBackend,
 
     pub texture_sampler: Arc<Sampler>,
     camera_push: water_vert::ty::Camera,
+    camera_yaw: f32,
+    up_axis: UpAxis,
+    // Same as MaterialParams.underwaterFog, the skybox gets it through its push constants
+    underwater_fog: [f32; 4],
+    show_compass: bool,
+    debug_view: DebugView,
     pub simulation: Simulation,
 }
 
 impl Renderer {
-    pub fn new(event_loop: &winit::event_loop::EventLoop<()>) -> Self {
-        let instance = {
-            let library = VulkanLibrary::new().unwrap();
-
-            let mut extensions = vulkano_win::required_extensions(&library);
-            extensions.khr_get_surface_capabilities2 = true;
-
-            vulkano::instance::Instance::new(
-                library,
-                vulkano::instance::InstanceCreateInfo {
-                    enabled_extensions: extensions,
-                    enumerate_portability: true,
-                    max_api_version: Some(vulkano::Version::V1_1),
-                    ..Default::default()
-                },
-            )
-            .unwrap()
-        };
-
-        let surface = WindowBuilder::new()
-            .build_vk_surface(event_loop, instance.clone())
-            .unwrap();
-        let device_extensions = device::DeviceExtensions {
-            ext_full_screen_exclusive: false,
-            khr_swapchain: true,
-            ..device::DeviceExtensions::empty()
-        };
+    // samples is the MSAA count for the forward scene pass, 1 if the device doesn't support it.
+    // texture_size is the simulation's map resolution, see Simulation::new.
+    pub fn new(
+        event_loop: &winit::event_loop::EventLoop<()>,
+        samples: SampleCount,
+        present: PresentPreference,
+        validation: ValidationConfig,
+        texture_size: u32,
+        noise_source: NoiseSource,
+        noise_seed: Option<u64>,
+    ) -> Result<Self, RendererError> {
+        let (context, surface) = GpuContext::windowed(event_loop, validation)?;
+        let device = context.device.clone();
+        let queue = context.queue.clone();
+        let memory_allocator = context.memory_allocator.clone();
+        // GpuContext turns it on when the device has it, the wireframe view is left out otherwise
+        let wireframe_supported = device.enabled_features().fill_mode_non_solid;
 
-        let (physical_device, queue_family_index) = instance
-            .enumerate_physical_devices()
-            .unwrap()
-            .filter(|p| p.supported_extensions().contains(&device_extensions))
-            .filter_map(|p| {
-                p.queue_family_properties()
-                    .iter()
-                    .enumerate()
-                    .position(|(i, q)| {
-                        q.queue_flags.graphics
-                            && p.surface_support(i as u32, &surface).unwrap_or(false)
-                    })
-                    .map(|i| (p, i as u32))
-            })
-            .min_by_key(|(p, _)| match p.properties().device_type {
-                PhysicalDeviceType::DiscreteGpu => 0,
-                PhysicalDeviceType::IntegratedGpu => 1,
-                PhysicalDeviceType::VirtualGpu => 2,
-                PhysicalDeviceType::Cpu => 3,
-                PhysicalDeviceType::Other => 4,
-                _ => 5,
-            })
-            .expect("No suitable physical device found");
-
-        let (device, mut queues) = Device::new(
-            physical_device,
-            DeviceCreateInfo {
-                enabled_extensions: device_extensions,
-                queue_create_infos: vec![QueueCreateInfo {
-                    queue_family_index,
-                    ..Default::default()
-                }],
-                ..Default::default()
-            },
-        )
-        .unwrap();
-
-        let queue = queues.next().unwrap();
         let (swapchain, images) = {
             let caps = device
                 .physical_device()
@@ -193,12 +1153,8 @@ impl Renderer {
             let window = get_window(&surface);
             let image_extent: [u32; 2] = window.inner_size().into();
 
-            let present_mode = device
-                .physical_device()
-                .surface_present_modes(&surface)
-                .unwrap()
-                .find(|&mode| mode == PresentMode::Mailbox)
-                .unwrap_or(PresentMode::Fifo);
+            let present_mode = choose_present_mode(&device, &surface, &present.priority());
+            info!("Present mode: {:?} (preferred {:?})", present_mode, present);
 
             Swapchain::new(
                 device.clone(),
@@ -216,67 +1172,207 @@ impl Renderer {
             .unwrap()
         };
 
-        let render_pass = vulkano::ordered_passes_renderpass!(device.clone(),
+        // Color and depth share the attachments, both have to support the count
+        let samples = {
+            let properties = device.physical_device().properties();
+            let color_counts = properties.framebuffer_color_sample_counts;
+            let depth_counts = properties.framebuffer_depth_sample_counts;
+            if color_counts.contains_count(samples) && depth_counts.contains_count(samples) {
+                samples
+            } else {
+                warn!("{:?} MSAA is not supported, rendering without it", samples);
+                SampleCount::Sample1
+            }
+        };
+        let render_pass = create_scene_pass(&device, RenderMode::Forward, samples);
+
+        // Upscales the scene onto the swapchain image and draws the overlays at full resolution
+        let present_pass = vulkano::ordered_passes_renderpass!(device.clone(),
             attachments: {
                 final_color: {
-                    load: Clear,
+                    load: DontCare,
                     store: Store,
                     format: swapchain.image_format(),
                     samples: 1,
-                },
-                depth: {
-                    load: Clear,
-                    store: DontCare,
-                    format: Format::D16_UNORM,
-                    samples: 1,
                 }
             },
             passes: [
                 {
                     color: [final_color],
-                    depth_stencil: {depth},
+                    depth_stencil: {},
                     input: []
                 }
             ]
         )
         .unwrap();
 
-        let deferred_vert = water_vert::load(device.clone()).unwrap();
-        let deferred_frag = water_frag::load(device.clone()).unwrap();
-        let geometry_pass = Subpass::from(render_pass.clone(), 0).unwrap();
-        let geometry_pipeline = GraphicsPipeline::start()
-            .vertex_input_state(
-                BuffersDefinition::new()
-                    .vertex::<Vertex>()
-                    .instance::<Instance>(),
+        let water_shaders = WaterShaders::builtin(&device, RenderMode::Forward);
+        let geometry_pipeline = create_geometry_pipeline(
+            &device,
+            &render_pass,
+            &water_shaders,
+            MeshTopology::TriangleList,
+            PolygonMode::Fill,
+        );
+        let strip_pipeline = create_geometry_pipeline(
+            &device,
+            &render_pass,
+            &water_shaders,
+            MeshTopology::TriangleStrip,
+            PolygonMode::Fill,
+        );
+        let wireframe_pipeline = wireframe_supported.then(|| {
+            create_geometry_pipeline(
+                &device,
+                &render_pass,
+                &water_shaders,
+                MeshTopology::TriangleList,
+                PolygonMode::Line,
+            )
+        });
+        let wireframe_strip_pipeline = wireframe_supported.then(|| {
+            create_geometry_pipeline(
+                &device,
+                &render_pass,
+                &water_shaders,
+                MeshTopology::TriangleStrip,
+                PolygonMode::Line,
+            )
+        });
+        let tessellation_supported = device.enabled_features().tessellation_shader;
+        let patch_pipeline = tessellation_supported.then(|| {
+            create_geometry_pipeline(
+                &device,
+                &render_pass,
+                &water_shaders,
+                MeshTopology::Patches,
+                PolygonMode::Fill,
+            )
+        });
+        let wireframe_patch_pipeline = (tessellation_supported && wireframe_supported).then(|| {
+            create_geometry_pipeline(
+                &device,
+                &render_pass,
+                &water_shaders,
+                MeshTopology::Patches,
+                PolygonMode::Line,
+            )
+        });
+
+        let skybox_pipeline = create_skybox_pipeline(&device, &render_pass);
+        let depth_pass = create_depth_pass(&device);
+        let seabed_depth_pipeline = create_seabed_pipeline(&device, &depth_pass);
+        let seabed_pipeline = create_seabed_pipeline(&device, &render_pass);
+
+        // Screen space wind direction arrow, no vertex buffers, the shader has the geometry
+        let present_subpass = Subpass::from(present_pass.clone(), 0).unwrap();
+        let compass_vert = compass_vert::load(device.clone()).unwrap();
+        let compass_frag = compass_frag::load(device.clone()).unwrap();
+        let compass_pipeline = GraphicsPipeline::start()
+            .vertex_input_state(BuffersDefinition::new())
+            .vertex_shader(compass_vert.entry_point("main").unwrap(), ())
+            .input_assembly_state(InputAssemblyState::new())
+            .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+            .fragment_shader(compass_frag.entry_point("main").unwrap(), ())
+            .rasterization_state(RasterizationState::new().cull_mode(CullMode::None))
+            .render_pass(present_subpass.clone())
+            .build(device.clone())
+            .unwrap();
+
+        let upscale_vert = upscale_vert::load(device.clone()).unwrap();
+        let upscale_frag = upscale_frag::load(device.clone()).unwrap();
+        let upscale_pipeline = GraphicsPipeline::start()
+            .vertex_input_state(BuffersDefinition::new())
+            .vertex_shader(upscale_vert.entry_point("main").unwrap(), ())
+            .input_assembly_state(InputAssemblyState::new())
+            .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+            .fragment_shader(upscale_frag.entry_point("main").unwrap(), ())
+            .rasterization_state(RasterizationState::new().cull_mode(CullMode::None))
+            .render_pass(present_subpass.clone())
+            .build(device.clone())
+            .unwrap();
+        // Upscale for the forward HDR scene, maps it into the swapchain's 0..1 on the way.
+        // Same set layout as the upscale pipeline, so it binds the same upscale_set.
+        let tonemap_frag = tonemap_frag::load(device.clone()).unwrap();
+        let tonemap_pipeline = GraphicsPipeline::start()
+            .vertex_input_state(BuffersDefinition::new())
+            .vertex_shader(upscale_vert.entry_point("main").unwrap(), ())
+            .input_assembly_state(InputAssemblyState::new())
+            .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+            .fragment_shader(tonemap_frag.entry_point("main").unwrap(), ())
+            .rasterization_state(RasterizationState::new().cull_mode(CullMode::None))
+            .render_pass(present_subpass.clone())
+            .build(device.clone())
+            .unwrap();
+
+        // Bitmap font text in the top left corner, one instanced quad per character
+        let text_vert = text_vert::load(device.clone()).unwrap();
+        let text_frag = text_frag::load(device.clone()).unwrap();
+        let text_pipeline = GraphicsPipeline::start()
+            .vertex_input_state(BuffersDefinition::new().instance::<GlyphInstance>())
+            .vertex_shader(
+                text_vert.entry_point("main").unwrap(),
+                text_vert::SpecializationConstants {
+                    glyphWidth: overlay::GLYPH_SIZE[0] as f32,
+                    glyphHeight: overlay::GLYPH_SIZE[1] as f32,
+                    cellWidth: overlay::CELL_SIZE[0] as f32,
+                    cellHeight: overlay::CELL_SIZE[1] as f32,
+                },
             )
-            .vertex_shader(deferred_vert.entry_point("main").unwrap(), ())
             .input_assembly_state(InputAssemblyState::new())
             .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
-            .fragment_shader(deferred_frag.entry_point("main").unwrap(), ())
-            .depth_stencil_state(DepthStencilState::simple_depth_test())
+            .fragment_shader(
+                text_frag.entry_point("main").unwrap(),
+                text_frag::SpecializationConstants {
+                    glyphWidth: overlay::GLYPH_SIZE[0],
+                    glyphHeight: overlay::GLYPH_SIZE[1],
+                },
+            )
             .rasterization_state(RasterizationState::new().cull_mode(CullMode::None))
-            .render_pass(geometry_pass.clone())
+            .render_pass(present_subpass)
             .build(device.clone())
             .unwrap();
+        let upscale_sampler = Sampler::new(
+            device.clone(),
+            SamplerCreateInfo {
+                mag_filter: Filter::Nearest,
+                min_filter: Filter::Nearest,
+                address_mode: [SamplerAddressMode::ClampToEdge; 3],
+                ..Default::default()
+            },
+        )
+        .unwrap();
 
-        let memory_allocator = Arc::new(StandardMemoryAllocator::new_default(device.clone()));
+        let descriptor_set_allocator = StandardDescriptorSetAllocator::new(device.clone());
         let mut viewport = Viewport {
             origin: [0.0, 0.0],
             dimensions: [0.0, 0.0],
             depth_range: 0.0..1.0,
         };
 
-        let framebuffers = Renderer::window_size_dependent_setup(
+        let render_scale = 1.0;
+        let framebuffers =
+            Renderer::window_size_dependent_setup(&images, present_pass.clone(), &mut viewport);
+        let scene_dimensions =
+            scale_dimensions(images[0].dimensions().width_height(), render_scale);
+        let scene_target = Renderer::create_scene_target(
             &memory_allocator,
-            &images,
+            &descriptor_set_allocator,
             render_pass.clone(),
-            &mut viewport,
+            RenderMode::Forward,
+            &upscale_pipeline,
+            &upscale_sampler,
+            scene_dimensions,
+        );
+        let scene_depth = create_scene_depth(
+            &memory_allocator,
+            &descriptor_set_allocator,
+            depth_pass.clone(),
+            &geometry_pipeline,
+            &upscale_sampler,
+            scene_dimensions,
         );
 
-        let descriptor_set_allocator = StandardDescriptorSetAllocator::new(device.clone());
-        let command_buffer_allocator =
-            StandardCommandBufferAllocator::new(device.clone(), Default::default());
         let acquire_future = None;
         let commands = None;
         let render_stage = RenderStage::Stopped;
@@ -291,6 +1387,10 @@ impl Renderer {
             proj: [[0.0; 4]; 4],
             view: [[0.0; 4]; 4],
             pos: [0.0; 3],
+            time: 0.0,
+            underwater: 0.0,
+            zNear: DEFAULT_NEAR_PLANE,
+            zFar: DEFAULT_FAR_PLANE,
         };
 
         let texture_sampler = Sampler::new(
@@ -304,13 +1404,80 @@ impl Renderer {
         )
         .unwrap();
 
-        let simulation = Simulation::new(
+        let font_buffer = CpuAccessibleBuffer::from_iter(
+            &memory_allocator,
+            BufferUsage {
+                storage_buffer: true,
+                ..BufferUsage::empty()
+            },
+            false,
+            overlay::font_rows(),
+        )
+        .unwrap();
+        let font_set = PersistentDescriptorSet::new(
+            &descriptor_set_allocator,
+            text_pipeline.layout().set_layouts()[0].clone(),
+            [WriteDescriptorSet::buffer(0, font_buffer)],
+        )
+        .unwrap();
+
+        let cubemap = skybox::solid_cubemap(
             &memory_allocator,
+            &context.command_buffer_allocator,
             &queue,
-            &command_buffer_allocator,
-            &device,
+            DEFAULT_SKY_COLOR,
+        );
+        let environment = skybox::prefilter(
+            &memory_allocator,
+            &context.command_buffer_allocator,
+            &descriptor_set_allocator,
+            &queue,
+            cubemap.clone(),
+        );
+        let skybox_set = PersistentDescriptorSet::new(
+            &descriptor_set_allocator,
+            skybox_pipeline.layout().set_layouts()[0].clone(),
+            [WriteDescriptorSet::image_view_sampler(
+                0,
+                cubemap,
+                texture_sampler.clone(),
+            )],
+        )
+        .unwrap();
+        let environment_sampler =
+            Sampler::new(device.clone(), SamplerCreateInfo::simple_repeat_linear()).unwrap();
+        let environment_set = PersistentDescriptorSet::new(
+            &descriptor_set_allocator,
+            skybox_pipeline.layout().set_layouts()[0].clone(),
+            [WriteDescriptorSet::image_view_sampler(
+                0,
+                environment,
+                environment_sampler.clone(),
+            )],
+        )
+        .unwrap();
+
+        let simulation = Simulation::new(
+            &context,
+            &texture_sampler,
+            noise_source,
+            noise_seed,
+            &DEFAULT_CASCADE_LENGTH_SCALES,
+            texture_size,
         );
 
+        let wave_backend = if simulation.has_compute() {
+            WaveBackend::Fft
+        } else {
+            warn!("Compute pipelines unavailable, falling back to Gerstner waves");
+            WaveBackend::Gerstner
+        };
+
+        // Unused cascade slots stay 0, the shaders stop at cascadeCount
+        let mut length_scales = [0.0; MAX_CASCADES];
+        length_scales[..DEFAULT_CASCADE_LENGTH_SCALES.len()]
+            .copy_from_slice(&DEFAULT_CASCADE_LENGTH_SCALES);
+
         let ocean_params_buffer = CpuAccessibleBuffer::from_data(
             &memory_allocator,
             BufferUsage {
@@ -319,13 +1486,23 @@ impl Renderer {
             },
             false,
             water_frag::ty::OceanParams {
-                lengthScale: 30.2,
+                lengthScales: length_scales,
+                foamWeights: DEFAULT_CASCADE_FOAM_WEIGHTS,
                 lodScale: 1.0,
                 sssBase: -0.1,
                 sssScale: 4.8,
+                upAxis: UpAxis::default().shader_id(),
+                cascadeCount: simulation.cascade_count() as u32,
+                waveBackend: wave_backend.shader_id(),
+                worldUVScale: 1.0,
+                flowMapExtent: FLOW_MAP_EXTENT,
             },
         )
         .unwrap();
+        let mut foam_gradient = [[1.0; 4]; MAX_FOAM_STOPS];
+        for (stop, ([r, g, b], amount)) in foam_gradient.iter_mut().zip(DEFAULT_FOAM_GRADIENT) {
+            *stop = [r, g, b, amount];
+        }
         let mat_params_buffer = CpuAccessibleBuffer::from_data(
             &memory_allocator,
             BufferUsage {
@@ -334,71 +1511,455 @@ impl Renderer {
             },
             false,
             water_frag::ty::MaterialParams {
-                color: [0.03457636, 0.12297464, 0.1981132, 1.0],
-                foamColor: [1.0, 1.0, 1.0, 1.0],
+                baseWaterColor: DEFAULT_WATER_COLOR,
+                deepWaterColor: DEFAULT_WATER_COLOR,
+                foamGradient: foam_gradient,
                 sssColor: [0.1541919, 0.8857628, 0.990566, 1.0],
+                shoreFoamColor: [1.0, 1.0, 1.0, 1.0],
+                sunColor: [1.0, 1.0, 1.0, 1.0],
+                underwaterFog: DEFAULT_UNDERWATER_FOG,
+                distanceFog: DEFAULT_DISTANCE_FOG,
                 sssStrength: 0.133,
                 roughness: 0.311,
                 roughnessScale: 0.0044,
                 maxGloss: 0.91,
-                foamBias: 0.84,
+                foamBias: DEFAULT_FOAM_BIAS,
                 foamScale: 2.4,
                 contactFoam: 1.0,
+                shoreFoamWidth: 0.0,
+                deepWaterDistance: DEFAULT_DEEP_WATER_DISTANCE,
+                foamStopCount: DEFAULT_FOAM_GRADIENT.len() as u32,
                 time: 0.0,
                 lightDir: [0.0, 1.0, 0.0],
+                sunIntensity: 1.0,
+                glitterStrength: 0.5,
+                debugView: DebugView::Shaded.shader_id(),
+                srgbTarget: (swapchain.image_format().type_color() == Some(NumericType::SRGB))
+                    as u32,
+            },
+        )
+        .unwrap();
+        // Always bound, water.vert only reads it with WaveBackend::Gerstner
+        let mut gerstner_waves = [[0.0; 4]; 8];
+        gerstner_waves[..GERSTNER_WAVES.len()].copy_from_slice(&GERSTNER_WAVES);
+        let gerstner_buffer = CpuAccessibleBuffer::from_data(
+            &memory_allocator,
+            BufferUsage {
+                uniform_buffer: true,
+                ..BufferUsage::empty()
+            },
+            false,
+            water_vert::ty::GerstnerWaves {
+                waves: gerstner_waves,
+                waveCount: GERSTNER_WAVES.len() as u32,
             },
         )
         .unwrap();
+        let skybox_params_set = PersistentDescriptorSet::new(
+            &descriptor_set_allocator,
+            skybox_pipeline.layout().set_layouts()[1].clone(),
+            [WriteDescriptorSet::buffer(0, ocean_params_buffer.clone())],
+        )
+        .unwrap();
 
-        Renderer {
+        Ok(Renderer {
+            context,
             surface,
-            device,
-            queue,
             swapchain,
-            memory_allocator,
             descriptor_set_allocator,
-            command_buffer_allocator,
             render_pass,
+            present_pass,
             geometry_pipeline,
+            strip_pipeline,
+            wireframe_pipeline,
+            wireframe_strip_pipeline,
+            patch_pipeline,
+            wireframe_patch_pipeline,
+            water_shaders,
+            #[cfg(feature = "shader-reload")]
+            shaders_reloaded: false,
+            wireframe: false,
+            compass_pipeline,
+            text_pipeline,
+            font_set,
+            overlay_glyphs: Vec::new(),
+            skybox_pipeline,
+            skybox_set,
+            environment_set,
+            environment_sampler,
+            skybox_params_set,
+            depth_pass,
+            seabed_depth_pipeline,
+            seabed_pipeline,
+            seabed: None,
+            upscale_pipeline,
+            tonemap_pipeline,
+            upscale_sampler,
+            exposure: 1.0,
+            dither_strength: DEFAULT_DITHER_STRENGTH,
+            clear_color: DEFAULT_CLEAR_COLOR,
             viewport,
             framebuffers,
+            scene_target,
+            scene_depth,
+            depth_frozen: false,
+            render_scale,
+            render_mode: RenderMode::Forward,
+            gbuffer_view: GBufferView::Albedo,
+            samples,
+            adaptive_quality: None,
             render_stage,
             commands,
             image_index,
             acquire_future,
+            present_modes: present.priority(),
+            present_failures: 0,
+            screenshot_request: None,
+            frame_stats: FrameStats::default(),
+            fps_smoothing: DEFAULT_FPS_SMOOTHING,
+            last_frame: None,
 
             ocean_params_buffer,
             mat_params_buffer,
+            gerstner_buffer,
+            wave_backend,
 
             texture_sampler,
             camera_push,
+            camera_yaw: 0.0,
+            up_axis: UpAxis::default(),
+            underwater_fog: DEFAULT_UNDERWATER_FOG,
+            show_compass: true,
+            debug_view: DebugView::Shaded,
             aspect_ratio,
             simulation,
-        }
+        })
     }
 
-    pub fn init(&mut self) {
-        self.simulation.init(
-            &self.command_buffer_allocator,
+    // Does all the one-time GPU work up front and blocks until it's done, so the first frame
+    // doesn't hitch: noise, FFT twiddle factors, h0 and a first simulation step. Pipelines and
+    // render targets are already built in new. Call once, after setting parameters.
+    pub fn warmup(&mut self) {
+        self.camera_push.time = self.simulation.time;
+        self.simulation.warmup(
+            &self.context.command_buffer_allocator,
             &self.descriptor_set_allocator,
-            self.queue.clone(),
-            self.texture_sampler.clone(),
+            self.context.queue.clone(),
         );
     }
 
+    // Runs the simulation for the time since the previous call, for callers without a clock
+    // of their own. The first call advances nothing. Returns the delta in seconds.
+    pub fn advance_frame(&mut self) -> f32 {
+        let now = Instant::now();
+        let delta_time = self
+            .last_frame
+            .map_or(0.0, |last| now.duration_since(last).as_secs_f32());
+        self.last_frame = Some(now);
+        self.run_sim(delta_time);
+        delta_time
+    }
+
     pub fn run_sim(&mut self, delta_time: f32) {
-        self.simulation.time += delta_time;
-        self.simulation.run(
-            &self.command_buffer_allocator,
+        self.record_frame_time(delta_time);
+        self.update_adaptive_quality(delta_time);
+        self.simulation.advance(
+            delta_time,
+            &self.context.command_buffer_allocator,
             &self.descriptor_set_allocator,
-            self.queue.clone(),
+            self.context.queue.clone(),
         );
+        self.camera_push.time = self.simulation.time;
+        self.update_underwater();
+    }
+
+    // How far the camera is under the displaced surface, faded over UNDERWATER_BLEND meters
+    // either side so crossing it isn't a one frame flip. The surface is looked up where the
+    // tile over the camera samples the maps. Without set_displacement_queries the surface is
+    // taken as flat at 0.
+    fn update_underwater(&mut self) {
+        let pos = self.up_axis.to_sim(Vec3::from(self.camera_push.pos));
+        let samples = water::map_samples(pos.xz(), self.world_uv_scale());
+        let height = |p: Vec2| self.simulation.sample_displacement(p.x, p.y)[1];
+        let (plain, jittered) = (height(samples.plain), height(samples.jittered));
+        let surface = plain + (jittered - plain) * samples.blend;
+        let t = ((surface - pos.y) / UNDERWATER_BLEND * 0.5 + 0.5).clamp(0.0, 1.0);
+        self.camera_push.underwater = t * t * (3.0 - 2.0 * t);
+    }
+
+    pub fn is_underwater(&self) -> bool {
+        self.camera_push.underwater > 0.5
+    }
+
+    // Murk seen from below the surface, linear RGB and density per meter
+    pub fn set_underwater_fog(&mut self, color: [f32; 3], density: f32) {
+        self.underwater_fog = [color[0], color[1], color[2], density.max(0.0)];
+        self.mat_params_buffer.write().unwrap().underwaterFog = self.underwater_fog;
+    }
+
+    // Fog the water fades into towards the horizon, linear RGB and density per meter. Match the
+    // color to the skybox's horizon, density 0 turns it off. Forward mode, above water only.
+    pub fn set_distance_fog(&mut self, color: [f32; 3], density: f32) {
+        self.mat_params_buffer.write().unwrap().distanceFog =
+            [color[0], color[1], color[2], density.max(0.0)];
+    }
+
+    fn record_frame_time(&mut self, delta_time: f32) {
+        if delta_time <= 0.0 {
+            return;
+        }
+        let fps = 1.0 / delta_time;
+        let previous = self.frame_stats.smoothed_fps;
+        let smoothed_fps = if previous > 0.0 {
+            previous + (fps - previous) * self.fps_smoothing
+        } else {
+            fps
+        };
+        self.frame_stats = FrameStats {
+            delta_time,
+            fps,
+            smoothed_fps,
+        };
+    }
+
+    pub fn frame_stats(&self) -> FrameStats {
+        self.frame_stats
+    }
+
+    // Holds the smoothed FPS near target_fps by turning MSAA, the cascade count and the render
+    // scale down while it's below and back up once there's headroom, see quality_ladder. The
+    // settings made through the other setters are the top it goes back up to, None turns it
+    // off and restores them. With vsync the FPS stops at the refresh rate, so a target that
+    // close to it never steps back up.
+    pub fn set_adaptive_quality(&mut self, target_fps: Option<f32>) {
+        let target_fps = target_fps.filter(|fps| *fps > 0.0);
+        if self.adaptive_quality.as_ref().map(|a| a.target_fps) == target_fps {
+            return;
+        }
+        let previous = self.quality();
+        self.adaptive_quality = target_fps.map(AdaptiveQuality::new);
+        self.apply_quality(previous);
+    }
+
+    fn update_adaptive_quality(&mut self, delta_time: f32) {
+        if self.adaptive_quality.is_none() || delta_time <= 0.0 {
+            return;
+        }
+        let previous = self.quality();
+        let levels = quality_ladder(self.top_quality()).len();
+        let smoothed_fps = self.frame_stats.smoothed_fps;
+        let adaptive = self.adaptive_quality.as_mut().unwrap();
+        if adaptive.update(delta_time, smoothed_fps, levels) {
+            let quality = self.quality();
+            info!(
+                "Adaptive quality at {:.0} fps: {:?} MSAA, {} cascades, render scale {:.1}",
+                smoothed_fps, quality.samples, quality.cascades, quality.render_scale
+            );
+            self.apply_quality(previous);
+        }
+    }
+
+    // The settings as set, deferred mode has no MSAA to turn down
+    fn top_quality(&self) -> Quality {
+        Quality {
+            render_scale: self.render_scale,
+            samples: match self.render_mode {
+                RenderMode::Forward => self.samples,
+                RenderMode::Deferred => SampleCount::Sample1,
+            },
+            cascades: self.simulation.cascade_count(),
+        }
+    }
+
+    // What the scene gets drawn with, below top_quality while adaptive quality turned it down
+    fn quality(&self) -> Quality {
+        let top = self.top_quality();
+        match &self.adaptive_quality {
+            Some(adaptive) => {
+                let ladder = quality_ladder(top);
+                ladder[adaptive.level.min(ladder.len() - 1)]
+            }
+            None => top,
+        }
+    }
+
+    // Catches the scene pass and the simulation up with quality() after it changed from
+    // previous. A new render scale only needs the redraw.
+    fn apply_quality(&mut self, previous: Quality) {
+        let quality = self.quality();
+        if quality.samples != previous.samples {
+            self.rebuild_scene_pass();
+        }
+        if quality.cascades != previous.cascades {
+            self.simulation.set_active_cascades(quality.cascades);
+            self.ocean_params_buffer.write().unwrap().cascadeCount = quality.cascades as u32;
+        }
+        if quality != previous {
+            self.render_stage = RenderStage::NeedsRedraw;
+        }
+    }
+
+    // Weight of the newest frame in smoothed_fps, 1 follows fps exactly and lower values
+    // settle slower
+    pub fn set_fps_smoothing(&mut self, smoothing: f32) {
+        self.fps_smoothing = smoothing.clamp(f32::EPSILON, 1.0);
     }
 
     pub fn window(&self) -> &Window {
         get_window(&self.surface)
     }
 
+    pub fn share_code(&self) -> String {
+        let ocean = *self.ocean_params_buffer.read().unwrap();
+        let (crossing_angle, secondary_strength) = self.simulation.crossing_sea();
+        ShareState {
+            length_scale: ocean.lengthScales[0],
+            lod_scale: ocean.lodScale,
+            sss_base: ocean.sssBase,
+            sss_scale: ocean.sssScale,
+            crossing_angle,
+            secondary_strength,
+            time: self.simulation.time,
+            seed: Some(self.simulation.noise_seed()),
+        }
+        .to_share_code()
+    }
+
+    pub fn apply_share_code(&mut self, code: &str) -> Result<(), ShareCodeError> {
+        let state = ShareState::from_share_code(code)?;
+
+        self.set_world_scale(state.length_scale);
+        {
+            let mut ocean = self.ocean_params_buffer.write().unwrap();
+            ocean.lodScale = state.lod_scale;
+            ocean.sssBase = state.sss_base;
+            ocean.sssScale = state.sss_scale;
+        }
+        self.simulation
+            .set_crossing_sea(state.crossing_angle, state.secondary_strength);
+        self.simulation.time = state.time;
+        // Codes without a seed keep the current noise
+        if let Some(seed) = state.seed {
+            self.simulation.set_noise_seed(seed);
+        }
+        Ok(())
+    }
+
+    // Simulation::set_world_scale, with the shader's patch size kept in step
+    pub fn set_world_scale(&mut self, meters_per_patch: f32) {
+        self.simulation.set_world_scale(meters_per_patch);
+        self.ocean_params_buffer.write().unwrap().lengthScales[0] = meters_per_patch;
+    }
+
+    // How much of the cascade's foam shows, 0 hides it. Its turbulence still integrates, see
+    // Simulation::set_cascade_foam_threshold for where foam gets generated.
+    pub fn set_cascade_foam_weight(&mut self, cascade: usize, weight: f32) {
+        self.ocean_params_buffer.write().unwrap().foamWeights[cascade] = weight.max(0.0);
+    }
+
+    // Simulation meters per world unit, the same waves drawn smaller (above 1) or larger. The
+    // CPU side queries of the simulation take simulation space positions, see world_to_sim.
+    pub fn set_world_uv_scale(&mut self, scale: f32) {
+        self.ocean_params_buffer.write().unwrap().worldUVScale = scale;
+    }
+
+    pub fn world_uv_scale(&self) -> f32 {
+        self.ocean_params_buffer.read().unwrap().worldUVScale
+    }
+
+    // A world position or direction in simulation space, Y-up with the horizontal axes in
+    // simulation meters. Heights keep their size, like in the water shader.
+    pub fn world_to_sim(&self, v: Vec3) -> Vec3 {
+        let scale = self.world_uv_scale();
+        self.up_axis
+            .to_sim(v)
+            .component_mul(&Vec3::new(scale, 1.0, scale))
+    }
+
+    pub fn set_up_axis(&mut self, up_axis: UpAxis) {
+        self.up_axis = up_axis;
+        self.ocean_params_buffer.write().unwrap().upAxis = up_axis.shader_id();
+    }
+
+    pub fn set_glitter_strength(&mut self, strength: f32) {
+        self.mat_params_buffer.write().unwrap().glitterStrength = strength;
+    }
+
+    // Direction towards the sun in simulation space (Y-up), the shaders rotate it along with
+    // the up axis. Drives the diffuse and specular light and the subsurface glow of crests
+    // with the sun behind them.
+    pub fn set_light_direction(&mut self, direction: [f32; 3]) {
+        let length = direction.iter().map(|d| d * d).sum::<f32>().sqrt();
+        if length > 0.0 {
+            self.mat_params_buffer.write().unwrap().lightDir = direction.map(|d| d / length);
+        }
+    }
+
+    // Directional sun, see set_light_direction. Color is linear RGB, intensity scales it.
+    // The forward scene is HDR, so intensities above 1 are fine.
+    pub fn set_sun(&mut self, direction: [f32; 3], color: [f32; 3], intensity: f32) {
+        self.set_light_direction(direction);
+        let mut params = self.mat_params_buffer.write().unwrap();
+        params.sunColor = [color[0], color[1], color[2], 1.0];
+        params.sunIntensity = intensity.max(0.0);
+    }
+
+    // Foam band where geometry behind the water (the depth prepass, see set_seabed) is less
+    // than width meters past the surface, shorelines and rocks. Width 0 turns it off. Color
+    // is linear RGB.
+    pub fn set_shore_foam(&mut self, width: f32, color: [f32; 3]) {
+        let mut params = self.mat_params_buffer.write().unwrap();
+        params.shoreFoamWidth = width.max(0.0);
+        params.shoreFoamColor = [color[0], color[1], color[2], 1.0];
+    }
+
+    // Flat opaque ground at height meters along the up axis, None for open ocean. It's what
+    // the shore and contact foam meet. Only forward mode draws its color, both foam against it.
+    pub fn set_seabed(&mut self, height: Option<f32>) {
+        self.seabed = height;
+    }
+
+    // Foam shows where the simulated jacobian drops below bias (the surface compresses), higher
+    // values cover more of the water
+    pub fn set_foam_bias(&mut self, bias: f32) {
+        self.mat_params_buffer.write().unwrap().foamBias = bias;
+    }
+
+    // Color stops as (linear RGB, foam amount 0..1), in any order. Foam below the first stop
+    // takes its color, above the last one that one's, linear in between. Only the first
+    // MAX_FOAM_STOPS are used, an empty slice is ignored.
+    pub fn set_foam_gradient(&mut self, stops: &[([f32; 3], f32)]) {
+        if stops.is_empty() {
+            return;
+        }
+        let mut stops = stops[..stops.len().min(MAX_FOAM_STOPS)].to_vec();
+        stops.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+        let mut params = self.mat_params_buffer.write().unwrap();
+        for (stop, (color, amount)) in params.foamGradient.iter_mut().zip(&stops) {
+            *stop = [color[0], color[1], color[2], amount.clamp(0.0, 1.0)];
+        }
+        params.foamStopCount = stops.len() as u32;
+    }
+
+    // Water tint near the camera and at distance meters away (most of the way there, the
+    // blend is exponential). Colors are linear RGB, pass the same color twice for a flat tint.
+    pub fn set_water_colors(&mut self, base: [f32; 3], deep: [f32; 3], distance: f32) {
+        let mut params = self.mat_params_buffer.write().unwrap();
+        params.baseWaterColor = [base[0], base[1], base[2], 1.0];
+        params.deepWaterColor = [deep[0], deep[1], deep[2], 1.0];
+        params.deepWaterDistance = distance.max(f32::EPSILON);
+    }
+
+    pub fn set_debug_view(&mut self, view: DebugView) {
+        self.debug_view = view;
+        self.mat_params_buffer.write().unwrap().debugView = view.shader_id();
+    }
+
+    pub fn debug_view(&self) -> DebugView {
+        self.debug_view
+    }
+
     // TODO: This can either be done as multiple smaller buffers
     // Or just use push constants
     pub fn set_camera(&mut self, camera: &Camera) {
@@ -406,25 +1967,419 @@ impl Renderer {
             proj: camera.projection_matrix_raw(),
             view: camera.view_matrix_raw(),
             pos: camera.position.into(),
+            time: self.simulation.time,
+            underwater: self.camera_push.underwater,
+            zNear: camera.clip_planes().0,
+            zFar: camera.clip_planes().1,
         };
+        self.camera_yaw = camera.yaw();
+    }
+
+    pub fn set_show_compass(&mut self, show: bool) {
+        self.show_compass = show;
+    }
+
+    pub fn show_compass(&self) -> bool {
+        self.show_compass
+    }
+
+    // One string per line, drawn in the top left corner until replaced. Empty hides the overlay.
+    pub fn set_overlay_text(&mut self, lines: &[String]) {
+        self.overlay_glyphs = overlay::layout_lines(lines);
     }
 
     pub fn get_draw_cache(
         &self,
         mesh: &Mesh,
-        instances: &Vec<Instance>,
+        instances: &[Instance],
         descriptor_writes: Vec<impl IntoIterator<Item = WriteDescriptorSet>>,
-    ) -> DrawCache {
+    ) -> Result<DrawCache, MeshError> {
         DrawCache::new(
             mesh,
             instances,
-            &self.memory_allocator,
+            &self.context.memory_allocator,
             &self.descriptor_set_allocator,
             &self.geometry_pipeline,
             descriptor_writes,
         )
     }
 
+    // Fraction of the window resolution the water is rendered at, the result is stretched over
+    // the window with nearest filtering. Overlays stay at full resolution. Applied next frame.
+    pub fn set_render_scale(&mut self, scale: f32) {
+        let scale = scale.clamp(MIN_RENDER_SCALE, 1.0);
+        if scale != self.render_scale {
+            self.render_scale = scale;
+            self.render_stage = RenderStage::NeedsRedraw;
+        }
+    }
+
+    // Multiplier on the forward HDR color before tone mapping, below 1 darkens the scene and
+    // brings back detail in bright highlights. Not applied to debug views or deferred mode.
+    pub fn set_exposure(&mut self, exposure: f32) {
+        self.exposure = exposure.max(0.0);
+    }
+
+    pub fn exposure(&self) -> f32 {
+        self.exposure
+    }
+
+    // Amount of ordered dither the tone map pass adds against banding, in steps of the 8 bit
+    // output. 0 turns it off. Forward mode only, like exposure.
+    pub fn set_dither_strength(&mut self, strength: f32) {
+        self.dither_strength = strength.max(0.0);
+    }
+
+    // Linear RGBA the forward scene is cleared to before the skybox, deferred mode clears
+    // the G-buffer instead
+    pub fn set_clear_color(&mut self, color: [f32; 4]) {
+        self.clear_color = color;
+    }
+
+    // Rebuilds the scene pass and water pipeline for the mode, applied next frame.
+    // Existing draw caches stay valid, both pipelines share the same descriptor layout.
+    pub fn set_render_mode(&mut self, render_mode: RenderMode) {
+        if render_mode == self.render_mode {
+            return;
+        }
+
+        self.render_mode = render_mode;
+        self.water_shaders = self.mode_shaders();
+        self.rebuild_scene_pass();
+    }
+
+    // The water shaders for render_mode, from wherever the current ones came from. Reloaded
+    // ones are compiled again for the other variant, falling back to the compiled in shaders.
+    fn mode_shaders(&self) -> WaterShaders {
+        #[cfg(feature = "shader-reload")]
+        if self.shaders_reloaded {
+            match WaterShaders::compile(&self.context.device, self.render_mode) {
+                Ok(shaders) => return shaders,
+                Err(e) => warn!(
+                    "Reloaded shaders failed to compile, using the builtin: {}",
+                    e
+                ),
+            }
+        }
+        WaterShaders::builtin(&self.context.device, self.render_mode)
+    }
+
+    // Scene pass for the render mode and the MSAA of quality(), with everything drawn in it
+    fn rebuild_scene_pass(&mut self) {
+        self.render_pass = create_scene_pass(
+            &self.context.device,
+            self.render_mode,
+            self.quality().samples,
+        );
+        let shaders = self.water_shaders.clone();
+        self.rebuild_geometry_pipelines(&shaders);
+        if self.render_mode == RenderMode::Forward {
+            self.skybox_pipeline = create_skybox_pipeline(&self.context.device, &self.render_pass);
+            self.seabed_pipeline = create_seabed_pipeline(&self.context.device, &self.render_pass);
+        }
+        self.render_stage = RenderStage::NeedsRedraw;
+    }
+
+    // Every water pipeline the device supports, against the current scene pass
+    fn rebuild_geometry_pipelines(&mut self, shaders: &WaterShaders) {
+        self.geometry_pipeline = create_geometry_pipeline(
+            &self.context.device,
+            &self.render_pass,
+            shaders,
+            MeshTopology::TriangleList,
+            PolygonMode::Fill,
+        );
+        self.strip_pipeline = create_geometry_pipeline(
+            &self.context.device,
+            &self.render_pass,
+            shaders,
+            MeshTopology::TriangleStrip,
+            PolygonMode::Fill,
+        );
+        if self.wireframe_pipeline.is_some() {
+            self.wireframe_pipeline = Some(create_geometry_pipeline(
+                &self.context.device,
+                &self.render_pass,
+                shaders,
+                MeshTopology::TriangleList,
+                PolygonMode::Line,
+            ));
+            self.wireframe_strip_pipeline = Some(create_geometry_pipeline(
+                &self.context.device,
+                &self.render_pass,
+                shaders,
+                MeshTopology::TriangleStrip,
+                PolygonMode::Line,
+            ));
+        }
+        if self.patch_pipeline.is_some() {
+            self.patch_pipeline = Some(create_geometry_pipeline(
+                &self.context.device,
+                &self.render_pass,
+                shaders,
+                MeshTopology::Patches,
+                PolygonMode::Fill,
+            ));
+        }
+        if self.wireframe_patch_pipeline.is_some() {
+            self.wireframe_patch_pipeline = Some(create_geometry_pipeline(
+                &self.context.device,
+                &self.render_pass,
+                shaders,
+                MeshTopology::Patches,
+                PolygonMode::Line,
+            ));
+        }
+    }
+
+    // Recompiles the water shaders from src/shaders and swaps their pipelines in, applied next
+    // frame. The running pipelines stay on error. Bindings and push constants have to keep
+    // matching the compiled in shaders. Kept through render mode switches and MSAA steps.
+    #[cfg(feature = "shader-reload")]
+    pub fn reload_shaders(&mut self) -> Result<(), ShaderReloadError> {
+        let shaders = WaterShaders::compile(&self.context.device, self.render_mode)?;
+        self.rebuild_geometry_pipelines(&shaders);
+        self.water_shaders = shaders;
+        self.shaders_reloaded = true;
+        self.render_stage = RenderStage::NeedsRedraw;
+        Ok(())
+    }
+
+    // Replaces the sky behind the water and in its reflections. Faces are square PNGs of the
+    // same size in the order +X, -X, +Y, -Y, +Z, -Z, with +Y up whatever the up axis.
+    // Blocks until the upload is done, the old skybox stays on error.
+    pub fn load_skybox(&mut self, paths: [PathBuf; 6]) -> Result<(), SkyboxError> {
+        let cubemap = skybox::load_cubemap(
+            &self.context.memory_allocator,
+            &self.context.command_buffer_allocator,
+            &self.context.queue,
+            &paths,
+        )?;
+        let environment = skybox::prefilter(
+            &self.context.memory_allocator,
+            &self.context.command_buffer_allocator,
+            &self.descriptor_set_allocator,
+            &self.context.queue,
+            cubemap.clone(),
+        );
+        self.skybox_set = PersistentDescriptorSet::new(
+            &self.descriptor_set_allocator,
+            self.skybox_pipeline.layout().set_layouts()[0].clone(),
+            [WriteDescriptorSet::image_view_sampler(
+                0,
+                cubemap,
+                self.texture_sampler.clone(),
+            )],
+        )
+        .unwrap();
+        self.environment_set = PersistentDescriptorSet::new(
+            &self.descriptor_set_allocator,
+            self.skybox_pipeline.layout().set_layouts()[0].clone(),
+            [WriteDescriptorSet::image_view_sampler(
+                0,
+                environment,
+                self.environment_sampler.clone(),
+            )],
+        )
+        .unwrap();
+        Ok(())
+    }
+
+    // The G-buffer of the last frame in deferred mode, None in forward mode. Replaced on
+    // resizes that change the render resolution and on render mode changes.
+    pub fn gbuffer(&self) -> Option<&GBuffer> {
+        self.scene_target.gbuffer.as_ref()
+    }
+
+    pub fn set_gbuffer_view(&mut self, view: GBufferView) {
+        self.gbuffer_view = view;
+        if let Some(gbuffer) = self.gbuffer() {
+            let upscale_set = create_upscale_set(
+                &self.descriptor_set_allocator,
+                &self.upscale_pipeline,
+                &self.upscale_sampler,
+                gbuffer.view(view),
+            );
+            self.scene_target.upscale_set = upscale_set;
+        }
+    }
+
+    pub fn gbuffer_view(&self) -> GBufferView {
+        self.gbuffer_view
+    }
+
+    // Stops redrawing the scene depth the contact and shore foam read, so the waves move
+    // against a fixed depth while the camera moves on. For telling a wrong depth apart from a
+    // wrong effect. Resizes that change the render resolution still draw it once again.
+    pub fn freeze_depth(&mut self, frozen: bool) {
+        self.depth_frozen = frozen;
+    }
+
+    pub fn depth_frozen(&self) -> bool {
+        self.depth_frozen
+    }
+
+    pub fn wave_backend(&self) -> WaveBackend {
+        self.wave_backend
+    }
+
+    // Draws the water as lines to show the grid. Stays on fill when the device can't do
+    // line polygon mode (no fill_mode_non_solid).
+    pub fn set_wireframe(&mut self, on: bool) {
+        if on && self.wireframe_pipeline.is_none() {
+            warn!("Wireframe needs the fill_mode_non_solid feature, staying on fill");
+            return;
+        }
+        self.wireframe = on;
+    }
+
+    pub fn wireframe(&self) -> bool {
+        self.wireframe
+    }
+
+    // Whether MeshTopology::Patches meshes can be drawn, needs the tessellation_shader feature
+    pub fn supports_tessellation(&self) -> bool {
+        self.patch_pipeline.is_some()
+    }
+
+    // Saves the next finished frame, overlays included, as a PNG at the swapchain resolution.
+    // That frame waits for the GPU so the copy can be read back right away.
+    pub fn capture_screenshot(&mut self, path: &Path) {
+        self.screenshot_request = Some(path.to_owned());
+    }
+
+    // Begins the present pass on framebuffer and draws the scene image of scene_set into it,
+    // tone mapped in forward mode. The pass is left open for the overlays.
+    fn record_present(
+        &self,
+        commands: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        framebuffer: Arc<Framebuffer>,
+        viewport: Viewport,
+        scene_set: Arc<PersistentDescriptorSet>,
+    ) {
+        // Deferred shows the albedo, which is already 0..1 and has nothing to tone map
+        let present_pipeline = match self.render_mode {
+            RenderMode::Forward => self.tonemap_pipeline.clone(),
+            RenderMode::Deferred => self.upscale_pipeline.clone(),
+        };
+        commands
+            .begin_render_pass(
+                RenderPassBeginInfo {
+                    clear_values: vec![None],
+                    ..RenderPassBeginInfo::framebuffer(framebuffer)
+                },
+                SubpassContents::Inline,
+            )
+            .unwrap()
+            .set_viewport(0, [viewport])
+            .bind_pipeline_graphics(present_pipeline.clone())
+            .bind_descriptor_sets(
+                PipelineBindPoint::Graphics,
+                present_pipeline.layout().clone(),
+                0,
+                scene_set,
+            );
+        if self.render_mode == RenderMode::Forward {
+            let [r, g, b] = UNDERWATER_TINT.map(|t| 1.0 + (t - 1.0) * self.camera_push.underwater);
+            commands.push_constants(
+                present_pipeline.layout().clone(),
+                0,
+                tonemap_frag::ty::Tonemap {
+                    tint: [r, g, b, 1.0],
+                    exposure: self.exposure,
+                    passthrough: (self.debug_view != DebugView::Shaded) as u32,
+                    ditherStrength: self.dither_strength,
+                    srgbTarget: (self.swapchain.image_format().type_color()
+                        == Some(NumericType::SRGB)) as u32,
+                },
+            );
+        }
+        commands.draw(3, 1, 0, 0).unwrap();
+    }
+
+    // Copy of the frame's swapchain image after the present pass, None if it can't be done
+    fn record_screenshot_copy(
+        &self,
+        commands: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+    ) -> Option<Arc<CpuAccessibleBuffer<[u8]>>> {
+        if screenshot_channels(self.swapchain.image_format()).is_none() {
+            error!(
+                "Screenshots don't support the {:?} swapchain format",
+                self.swapchain.image_format()
+            );
+            return None;
+        }
+        if !self.swapchain.image_usage().transfer_src {
+            error!("Swapchain images can't be copied from, no screenshot");
+            return None;
+        }
+
+        let [width, height] = self.swapchain.image_extent();
+        let buffer = CpuAccessibleBuffer::from_iter(
+            &self.context.memory_allocator,
+            BufferUsage {
+                transfer_dst: true,
+                ..BufferUsage::empty()
+            },
+            false,
+            (0..width * height * 4).map(|_| 0u8),
+        )
+        .unwrap();
+        let image = self.framebuffers[self.image_index as usize].attachments()[0].image();
+        commands
+            .copy_image_to_buffer(CopyImageToBufferInfo::image_buffer(image, buffer.clone()))
+            .unwrap();
+        Some(buffer)
+    }
+
+    fn save_screenshot(
+        &self,
+        path: &Path,
+        dimensions: [u32; 2],
+        buffer: &CpuAccessibleBuffer<[u8]>,
+    ) {
+        let channels = screenshot_channels(self.swapchain.image_format()).unwrap();
+        let texels = buffer.read().unwrap();
+        // Rows are already top to bottom, only the channel order and alpha need fixing
+        let rgba: Vec<u8> = texels
+            .chunks_exact(4)
+            .flat_map(|texel| {
+                [
+                    texel[channels[0]],
+                    texel[channels[1]],
+                    texel[channels[2]],
+                    255,
+                ]
+            })
+            .collect();
+
+        match write_png(path, dimensions, &rgba) {
+            Ok(()) => info!("Saved screenshot to {}", path.display()),
+            Err(e) => error!("Failed to write screenshot: {:?}", e),
+        }
+    }
+
+    // Returns true when the present mode list changed and the swapchain needs recreating
+    fn on_present_failure(&mut self) -> bool {
+        self.present_failures += 1;
+        if self.present_failures < MAX_PRESENT_FAILURES
+            || self.swapchain.present_mode() != PresentMode::Mailbox
+        {
+            return false;
+        }
+
+        warn!(
+            "Present mode {:?} failed {} frames in a row, falling back to {:?}",
+            PresentMode::Mailbox,
+            self.present_failures,
+            PresentMode::Fifo
+        );
+        self.present_modes
+            .retain(|&mode| mode != PresentMode::Mailbox);
+        self.present_failures = 0;
+        true
+    }
+
     pub fn recreate_swapchain(&mut self) {
         self.render_stage = RenderStage::NeedsRedraw;
         self.commands = None;
@@ -440,8 +2395,15 @@ impl Renderer {
             return;
         }
 
+        // Resizes keep the list, so the mode only changes after set_present_modes or a fallback
+        let present_mode =
+            choose_present_mode(&self.context.device, &self.surface, &self.present_modes);
+        if present_mode != self.swapchain.present_mode() {
+            info!("Present mode: {:?}", present_mode);
+        }
         let (new_swapchain, new_images) = match self.swapchain.recreate(SwapchainCreateInfo {
             image_extent,
+            present_mode,
             ..self.swapchain.create_info()
         }) {
             Ok(r) => r,
@@ -450,11 +2412,40 @@ impl Renderer {
         };
 
         let new_framebuffers = Renderer::window_size_dependent_setup(
-            &self.memory_allocator,
             &new_images,
-            self.render_pass.clone(),
+            self.present_pass.clone(),
             &mut self.viewport,
         );
+        // Resizes that land on the same render resolution keep the scene target and its depth
+        // buffer, a new scene pass (render mode change) always needs a new one
+        let scene_dimensions = scale_dimensions(
+            new_images[0].dimensions().width_height(),
+            self.quality().render_scale,
+        );
+        let framebuffer = &self.scene_target.framebuffer;
+        if framebuffer.extent() != scene_dimensions
+            || !Arc::ptr_eq(framebuffer.render_pass(), &self.render_pass)
+        {
+            self.scene_target = Renderer::create_scene_target(
+                &self.context.memory_allocator,
+                &self.descriptor_set_allocator,
+                self.render_pass.clone(),
+                self.render_mode,
+                &self.upscale_pipeline,
+                &self.upscale_sampler,
+                scene_dimensions,
+            );
+        }
+        if self.scene_depth.framebuffer.extent() != scene_dimensions {
+            self.scene_depth = create_scene_depth(
+                &self.context.memory_allocator,
+                &self.descriptor_set_allocator,
+                self.depth_pass.clone(),
+                &self.geometry_pipeline,
+                &self.upscale_sampler,
+                scene_dimensions,
+            );
+        }
 
         let aspect_ratio = window.inner_size().width as f32 / window.inner_size().height as f32;
 
@@ -462,10 +2453,11 @@ impl Renderer {
         self.framebuffers = new_framebuffers;
         self.render_stage = RenderStage::Stopped;
         self.aspect_ratio = aspect_ratio;
+        // A new scene target starts out showing the albedo
+        self.set_gbuffer_view(self.gbuffer_view);
     }
 
     fn window_size_dependent_setup(
-        allocator: &StandardMemoryAllocator,
         images: &[Arc<SwapchainImage>],
         render_pass: Arc<RenderPass>,
         viewport: &mut Viewport,
@@ -473,59 +2465,126 @@ impl Renderer {
         let dimensions = images[0].dimensions().width_height();
         viewport.dimensions = [dimensions[0] as f32, dimensions[1] as f32];
 
-        let depth_buffer = ImageView::new_default(
-            AttachmentImage::transient(allocator, dimensions, Format::D16_UNORM).unwrap(),
-        )
-        .unwrap();
-
-        let framebuffers = images
+        images
             .iter()
             .map(|image| {
                 let view = ImageView::new_default(image.clone()).unwrap();
                 Framebuffer::new(
                     render_pass.clone(),
                     FramebufferCreateInfo {
-                        attachments: vec![view, depth_buffer.clone()],
+                        attachments: vec![view],
                         ..Default::default()
                     },
                 )
                 .unwrap()
             })
-            .collect::<Vec<_>>();
-
-        framebuffers
+            .collect()
     }
 
-    fn check_stage(&mut self, expected: RenderStage) -> bool {
-        if self.render_stage == expected {
-            return true;
-        }
+    fn create_scene_target(
+        allocator: &StandardMemoryAllocator,
+        descriptor_set_allocator: &StandardDescriptorSetAllocator,
+        render_pass: Arc<RenderPass>,
+        render_mode: RenderMode,
+        upscale_pipeline: &Arc<GraphicsPipeline>,
+        sampler: &Arc<Sampler>,
+        dimensions: [u32; 2],
+    ) -> SceneTarget {
+        let sampled = |format| {
+            ImageView::new_default(AttachmentImage::sampled(allocator, dimensions, format).unwrap())
+                .unwrap()
+        };
 
-        match self.render_stage {
-            RenderStage::NeedsRedraw => {
-                self.recreate_swapchain();
-                self.render_stage = RenderStage::Stopped;
-                self.commands = None;
-                false
+        let (attachments, shown, gbuffer) = match render_mode {
+            RenderMode::Forward => {
+                // Multisampled images only live through the pass, only the resolve is sampled
+                let samples = render_pass.attachments()[0].samples;
+                let transient = |format| {
+                    ImageView::new_default(
+                        AttachmentImage::transient_multisampled(
+                            allocator, dimensions, samples, format,
+                        )
+                        .unwrap(),
+                    )
+                    .unwrap()
+                };
+                let color = sampled(HDR_FORMAT);
+                let depth_buffer = transient(Format::D16_UNORM);
+                let attachments: Vec<Arc<dyn ImageViewAbstract>> =
+                    if samples == SampleCount::Sample1 {
+                        vec![color.clone(), depth_buffer]
+                    } else {
+                        vec![transient(HDR_FORMAT), depth_buffer, color.clone()]
+                    };
+                (attachments, color, None)
             }
-            _ => {
-                self.render_stage = RenderStage::Stopped;
-                self.commands = None;
-                false
+            RenderMode::Deferred => {
+                let gbuffer = GBuffer {
+                    normal: sampled(GBUFFER_NORMAL_FORMAT),
+                    albedo: sampled(GBUFFER_ALBEDO_FORMAT),
+                    roughness: sampled(GBUFFER_ROUGHNESS_FORMAT),
+                    depth: sampled(Format::D16_UNORM),
+                };
+                let attachments: Vec<Arc<dyn ImageViewAbstract>> = vec![
+                    gbuffer.normal.clone(),
+                    gbuffer.albedo.clone(),
+                    gbuffer.roughness.clone(),
+                    gbuffer.depth.clone(),
+                ];
+                // Nothing gets lit here, the window just shows the albedo until
+                // set_gbuffer_view picks another attachment
+                (attachments, gbuffer.albedo.clone(), Some(gbuffer))
             }
+        };
+
+        let framebuffer = Framebuffer::new(
+            render_pass,
+            FramebufferCreateInfo {
+                attachments,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        SceneTarget {
+            framebuffer,
+            upscale_set: create_upscale_set(
+                descriptor_set_allocator,
+                upscale_pipeline,
+                sampler,
+                shown,
+            ),
+            viewport: Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [dimensions[0] as f32, dimensions[1] as f32],
+                depth_range: 0.0..1.0,
+            },
+            gbuffer,
+        }
+    }
+
+    fn check_stage(&mut self, expected: RenderStage) -> bool {
+        let (stage, proceed) = advance_stage(self.render_stage, expected, || {
+            self.recreate_swapchain();
+            self.render_stage
+        });
+        self.render_stage = stage;
+        if !proceed {
+            self.commands = None;
         }
+        proceed
     }
 
     pub fn start(&mut self) {
         if !self.check_stage(RenderStage::Stopped) {
             return;
         }
-        self.render_stage = RenderStage::Render;
 
         let (image_index, suboptimal, acquire_future) =
             match swapchain::acquire_next_image(self.swapchain.clone(), None) {
                 Ok(r) => r,
                 Err(AcquireError::OutOfDate) => {
+                    self.on_present_failure();
                     self.recreate_swapchain();
                     return;
                 }
@@ -537,30 +2596,134 @@ impl Renderer {
             return;
         }
 
-        let clear_values = vec![Some([0.1, 0.7, 0.9, 1.0].into()), Some(1.0.into())];
-
         let mut commands = AutoCommandBufferBuilder::primary(
-            &self.command_buffer_allocator,
-            self.queue.queue_family_index(),
+            &self.context.command_buffer_allocator,
+            self.context.queue.queue_family_index(),
             CommandBufferUsage::OneTimeSubmit,
         )
         .unwrap();
 
+        if !self.depth_frozen || !self.scene_depth.drawn {
+            record_depth_prepass(
+                &mut commands,
+                self.scene_depth.framebuffer.clone(),
+                &self.seabed_depth_pipeline,
+                self.seabed_push(&self.camera_push),
+            );
+            self.scene_depth.drawn = true;
+        }
         commands
             .begin_render_pass(
                 RenderPassBeginInfo {
-                    clear_values,
-                    ..RenderPassBeginInfo::framebuffer(
-                        self.framebuffers[image_index as usize].clone(),
-                    )
+                    clear_values: self.scene_clear_values(),
+                    ..RenderPassBeginInfo::framebuffer(self.scene_target.framebuffer.clone())
                 },
                 SubpassContents::Inline,
             )
             .unwrap();
+        self.record_skybox(
+            &mut commands,
+            self.scene_target.viewport.clone(),
+            &self.camera_push,
+        );
+        self.record_seabed(
+            &mut commands,
+            self.scene_target.viewport.clone(),
+            &self.camera_push,
+        );
 
+        // Only Render once commands are recording, render and finish rely on that
         self.commands = Some(commands);
         self.image_index = image_index;
         self.acquire_future = Some(acquire_future);
+        self.render_stage = RenderStage::Render;
+    }
+
+    // In attachment order of the scene pass for the current render mode
+    fn scene_clear_values(&self) -> Vec<Option<ClearValue>> {
+        match self.render_mode {
+            // The MSAA resolve target is written whole by the resolve, nothing to clear
+            RenderMode::Forward if self.quality().samples != SampleCount::Sample1 => {
+                vec![Some(self.clear_color.into()), Some(1.0.into()), None]
+            }
+            RenderMode::Forward => vec![Some(self.clear_color.into()), Some(1.0.into())],
+            RenderMode::Deferred => vec![
+                Some([0.0; 4].into()),
+                Some([0.0; 4].into()),
+                Some([1.0, 0.0, 0.0, 0.0].into()),
+                Some(1.0.into()),
+            ],
+        }
+    }
+
+    // Forward only, deferred leaves the background to whoever lights the G-buffer
+    fn record_skybox(
+        &self,
+        commands: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        viewport: Viewport,
+        camera: &water_vert::ty::Camera,
+    ) {
+        if self.render_mode != RenderMode::Forward {
+            return;
+        }
+        commands
+            .set_viewport(0, [viewport])
+            .bind_pipeline_graphics(self.skybox_pipeline.clone())
+            .push_constants(
+                self.skybox_pipeline.layout().clone(),
+                0,
+                skybox_vert::ty::Sky {
+                    proj: camera.proj,
+                    view: camera.view,
+                    fog: [
+                        self.underwater_fog[0],
+                        self.underwater_fog[1],
+                        self.underwater_fog[2],
+                        camera.underwater,
+                    ],
+                },
+            )
+            .bind_descriptor_sets(
+                PipelineBindPoint::Graphics,
+                self.skybox_pipeline.layout().clone(),
+                0,
+                vec![self.skybox_set.clone(), self.skybox_params_set.clone()],
+            )
+            .draw(3, 1, 0, 0)
+            .unwrap();
+    }
+
+    // The seabed as seen by camera, None when there is none
+    fn seabed_push(&self, camera: &water_vert::ty::Camera) -> Option<seabed_vert::ty::Seabed> {
+        self.seabed.map(|height| seabed_vert::ty::Seabed {
+            proj: camera.proj,
+            view: camera.view,
+            center: camera.pos,
+            height,
+            upAxis: self.up_axis.shader_id(),
+            extent: camera.zFar,
+        })
+    }
+
+    // Forward only like the skybox, deferred still gets the seabed's depth from the prepass
+    fn record_seabed(
+        &self,
+        commands: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        viewport: Viewport,
+        camera: &water_vert::ty::Camera,
+    ) {
+        let Some(seabed) = self.seabed_push(camera) else {
+            return;
+        };
+        if self.render_mode != RenderMode::Forward {
+            return;
+        }
+        commands
+            .set_viewport(0, [viewport])
+            .bind_pipeline_graphics(self.seabed_pipeline.clone())
+            .push_constants(self.seabed_pipeline.layout().clone(), 0, seabed)
+            .draw(4, 1, 0, 0)
+            .unwrap();
     }
 
     pub fn render(&mut self, draw_cache: &DrawCache) {
@@ -568,21 +2731,77 @@ impl Renderer {
             return;
         }
 
+        let mut commands = self.commands.take().unwrap();
+        self.record_draw(
+            &mut commands,
+            self.scene_target.viewport.clone(),
+            &self.camera_push,
+            &self.scene_depth.set,
+            draw_cache,
+        );
+        self.commands = Some(commands);
+    }
+
+    fn record_draw(
+        &self,
+        commands: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        viewport: Viewport,
+        camera: &water_vert::ty::Camera,
+        scene_depth_set: &Arc<PersistentDescriptorSet>,
+        draw_cache: &DrawCache,
+    ) {
+        let (Some(vertex_buffer), Some(index_buffer), Some(inst_buffer)) = (
+            draw_cache.vertex_buffer.clone(),
+            draw_cache.index_buffer.clone(),
+            draw_cache.inst_buffer.clone(),
+        ) else {
+            // Empty cache (culled away or switched off), skip the draw
+            return;
+        };
         let geometry_sets = draw_cache.geometry_sets.clone();
-        let vertex_buffer = draw_cache.vertex_buffer.clone();
-        let index_buffer = draw_cache.index_buffer.clone();
-        let inst_buffer = draw_cache.inst_buffer.clone();
-        self.commands
-            .as_mut()
-            .unwrap()
-            .set_viewport(0, [self.viewport.clone()])
-            .bind_pipeline_graphics(self.geometry_pipeline.clone())
-            .push_constants(self.geometry_pipeline.layout().clone(), 0, self.camera_push)
+        let (fill, line) = match draw_cache.topology {
+            MeshTopology::TriangleList => (
+                Some(self.geometry_pipeline.clone()),
+                self.wireframe_pipeline.clone(),
+            ),
+            MeshTopology::TriangleStrip => (
+                Some(self.strip_pipeline.clone()),
+                self.wireframe_strip_pipeline.clone(),
+            ),
+            MeshTopology::Patches => (
+                self.patch_pipeline.clone(),
+                self.wireframe_patch_pipeline.clone(),
+            ),
+        };
+        let Some(pipeline) = line.filter(|_| self.wireframe).or(fill) else {
+            // Patches on a device without tessellation, check supports_tessellation first
+            return;
+        };
+        commands
+            .set_viewport(0, [viewport])
+            .bind_pipeline_graphics(pipeline.clone())
+            .push_constants(pipeline.layout().clone(), 0, *camera)
             .bind_descriptor_sets(
                 PipelineBindPoint::Graphics,
-                self.geometry_pipeline.layout().clone(),
+                pipeline.layout().clone(),
                 0,
                 geometry_sets,
+            );
+        // Reflections are forward only, the G-buffer pipelines have no set 2
+        if self.render_mode == RenderMode::Forward {
+            commands.bind_descriptor_sets(
+                PipelineBindPoint::Graphics,
+                pipeline.layout().clone(),
+                2,
+                self.environment_set.clone(),
+            );
+        }
+        commands
+            .bind_descriptor_sets(
+                PipelineBindPoint::Graphics,
+                pipeline.layout().clone(),
+                3,
+                scene_depth_set.clone(),
             )
             .bind_vertex_buffers(0, (vertex_buffer.clone(), inst_buffer.clone()))
             .bind_index_buffer(index_buffer.clone())
@@ -590,6 +2809,230 @@ impl Renderer {
             .unwrap();
     }
 
+    // Draws the scene once into a new width x height image, whatever the window size. It has
+    // its own framebuffer and depth buffer, so the frame being recorded isn't touched. Uses the
+    // current camera with the aspect ratio fixed up for the image. Forward mode gives the HDR
+    // scene color before tone mapping, deferred the G-buffer albedo. No overlays, and it waits
+    // for the GPU before returning.
+    pub fn render_offscreen<'a>(
+        &mut self,
+        width: u32,
+        height: u32,
+        caches: impl IntoIterator<Item = &'a DrawCache>,
+    ) -> Arc<StorageImage> {
+        let dimensions = [width.max(1), height.max(1)];
+        let allocator = &*self.context.memory_allocator;
+        let render_pass = self.scene_target.framebuffer.render_pass().clone();
+
+        let output = StorageImage::with_usage(
+            allocator,
+            ImageDimensions::Dim2d {
+                width: dimensions[0],
+                height: dimensions[1],
+                array_layers: 1,
+            },
+            match self.render_mode {
+                RenderMode::Forward => HDR_FORMAT,
+                RenderMode::Deferred => GBUFFER_ALBEDO_FORMAT,
+            },
+            ImageUsage {
+                color_attachment: true,
+                sampled: true,
+                transfer_src: true,
+                ..ImageUsage::empty()
+            },
+            ImageCreateFlags::empty(),
+            [self.context.queue.queue_family_index()],
+        )
+        .unwrap();
+        let output_view: Arc<dyn ImageViewAbstract> =
+            ImageView::new_default(output.clone()).unwrap();
+        let attachment = |format, samples| -> Arc<dyn ImageViewAbstract> {
+            ImageView::new_default(
+                AttachmentImage::multisampled(allocator, dimensions, samples, format).unwrap(),
+            )
+            .unwrap()
+        };
+        let samples = self.quality().samples;
+        let attachments = match self.render_mode {
+            RenderMode::Forward if samples != SampleCount::Sample1 => vec![
+                attachment(HDR_FORMAT, samples),
+                attachment(Format::D16_UNORM, samples),
+                output_view,
+            ],
+            RenderMode::Forward => vec![
+                output_view,
+                attachment(Format::D16_UNORM, SampleCount::Sample1),
+            ],
+            RenderMode::Deferred => vec![
+                attachment(GBUFFER_NORMAL_FORMAT, SampleCount::Sample1),
+                output_view,
+                attachment(GBUFFER_ROUGHNESS_FORMAT, SampleCount::Sample1),
+                attachment(Format::D16_UNORM, SampleCount::Sample1),
+            ],
+        };
+        let framebuffer = Framebuffer::new(
+            render_pass,
+            FramebufferCreateInfo {
+                attachments,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let viewport = Viewport {
+            origin: [0.0, 0.0],
+            dimensions: [dimensions[0] as f32, dimensions[1] as f32],
+            depth_range: 0.0..1.0,
+        };
+
+        // The projection was made for the window, keep its vertical field of view
+        let mut camera = self.camera_push;
+        let [window_width, window_height] = self.scene_target.viewport.dimensions;
+        let [width, height] = viewport.dimensions;
+        camera.proj[0][0] *= (window_width / window_height) / (width / height);
+
+        let scene_depth = create_scene_depth(
+            allocator,
+            &self.descriptor_set_allocator,
+            self.depth_pass.clone(),
+            &self.geometry_pipeline,
+            &self.upscale_sampler,
+            dimensions,
+        );
+
+        let mut commands = AutoCommandBufferBuilder::primary(
+            &self.context.command_buffer_allocator,
+            self.context.queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+        record_depth_prepass(
+            &mut commands,
+            scene_depth.framebuffer.clone(),
+            &self.seabed_depth_pipeline,
+            self.seabed_push(&camera),
+        );
+        commands
+            .begin_render_pass(
+                RenderPassBeginInfo {
+                    clear_values: self.scene_clear_values(),
+                    ..RenderPassBeginInfo::framebuffer(framebuffer)
+                },
+                SubpassContents::Inline,
+            )
+            .unwrap();
+        self.record_skybox(&mut commands, viewport.clone(), &camera);
+        self.record_seabed(&mut commands, viewport.clone(), &camera);
+        for cache in caches {
+            self.record_draw(
+                &mut commands,
+                viewport.clone(),
+                &camera,
+                &scene_depth.set,
+                cache,
+            );
+        }
+        commands.end_render_pass().unwrap();
+        commands
+            .build()
+            .unwrap()
+            .execute(self.context.queue.clone())
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+
+        output
+    }
+
+    // Renders the caches offscreen at width x height and saves them like a screenshot,
+    // through the same present pass but without the overlays
+    pub fn capture_offscreen<'a>(
+        &mut self,
+        path: &Path,
+        width: u32,
+        height: u32,
+        caches: impl IntoIterator<Item = &'a DrawCache>,
+    ) {
+        let format = self.swapchain.image_format();
+        if screenshot_channels(format).is_none() {
+            error!("Captures don't support the {:?} swapchain format", format);
+            return;
+        }
+        let scene = self.render_offscreen(width, height, caches);
+        let dimensions = scene.dimensions().width_height();
+
+        let allocator = &*self.context.memory_allocator;
+        let target = AttachmentImage::with_usage(
+            allocator,
+            dimensions,
+            format,
+            ImageUsage {
+                color_attachment: true,
+                transfer_src: true,
+                ..ImageUsage::empty()
+            },
+        )
+        .unwrap();
+        let framebuffer = Framebuffer::new(
+            self.present_pass.clone(),
+            FramebufferCreateInfo {
+                attachments: vec![ImageView::new_default(target.clone()).unwrap()],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let scene_set = PersistentDescriptorSet::new(
+            &self.descriptor_set_allocator,
+            self.upscale_pipeline.layout().set_layouts()[0].clone(),
+            [WriteDescriptorSet::image_view_sampler(
+                0,
+                ImageView::new_default(scene).unwrap(),
+                self.upscale_sampler.clone(),
+            )],
+        )
+        .unwrap();
+        let buffer = CpuAccessibleBuffer::from_iter(
+            allocator,
+            BufferUsage {
+                transfer_dst: true,
+                ..BufferUsage::empty()
+            },
+            false,
+            (0..dimensions[0] * dimensions[1] * 4).map(|_| 0u8),
+        )
+        .unwrap();
+
+        let mut commands = AutoCommandBufferBuilder::primary(
+            &self.context.command_buffer_allocator,
+            self.context.queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+        let viewport = Viewport {
+            origin: [0.0, 0.0],
+            dimensions: [dimensions[0] as f32, dimensions[1] as f32],
+            depth_range: 0.0..1.0,
+        };
+        self.record_present(&mut commands, framebuffer, viewport, scene_set);
+        commands.end_render_pass().unwrap();
+        commands
+            .copy_image_to_buffer(CopyImageToBufferInfo::image_buffer(target, buffer.clone()))
+            .unwrap();
+        commands
+            .build()
+            .unwrap()
+            .execute(self.context.queue.clone())
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+
+        self.save_screenshot(path, dimensions, &buffer);
+    }
+
     pub fn finish(&mut self, previous_frame_end: &mut Option<Box<dyn GpuFuture>>) {
         if !self.check_stage(RenderStage::Render) {
             return;
@@ -597,12 +3040,84 @@ impl Renderer {
 
         let mut commands = self.commands.take().unwrap();
         commands.end_render_pass().unwrap();
+
+        self.record_present(
+            &mut commands,
+            self.framebuffers[self.image_index as usize].clone(),
+            self.viewport.clone(),
+            self.scene_target.upscale_set.clone(),
+        );
+
+        if self.show_compass {
+            // Wind angle and yaw are both measured in the simulation XZ plane,
+            // so their difference is the wind direction relative to where we look
+            let relative = self.simulation.wind_angle() - self.camera_yaw;
+            commands
+                .bind_pipeline_graphics(self.compass_pipeline.clone())
+                .push_constants(
+                    self.compass_pipeline.layout().clone(),
+                    0,
+                    compass_vert::ty::Compass {
+                        direction: [relative.sin(), relative.cos()],
+                        aspectRatio: self.aspect_ratio,
+                    },
+                )
+                .draw(9, 1, 0, 0)
+                .unwrap();
+        }
+
+        if !self.overlay_glyphs.is_empty() {
+            let glyph_buffer = CpuAccessibleBuffer::from_iter(
+                &self.context.memory_allocator,
+                BufferUsage {
+                    vertex_buffer: true,
+                    ..BufferUsage::empty()
+                },
+                false,
+                self.overlay_glyphs.iter().copied(),
+            )
+            .unwrap();
+            commands
+                .bind_pipeline_graphics(self.text_pipeline.clone())
+                .bind_descriptor_sets(
+                    PipelineBindPoint::Graphics,
+                    self.text_pipeline.layout().clone(),
+                    0,
+                    self.font_set.clone(),
+                )
+                .bind_vertex_buffers(0, glyph_buffer.clone());
+            // Black copy one font pixel down and right first, keeps it readable over foam and sky
+            let passes = [
+                ([OVERLAY_TEXT_SCALE; 2], [0.0, 0.0, 0.0, 1.0]),
+                ([0.0; 2], [1.0; 4]),
+            ];
+            for (offset, color) in passes {
+                commands
+                    .push_constants(
+                        self.text_pipeline.layout().clone(),
+                        0,
+                        text_vert::ty::Text {
+                            screenSize: self.viewport.dimensions,
+                            offset,
+                            color,
+                            scale: OVERLAY_TEXT_SCALE,
+                        },
+                    )
+                    .draw(6, glyph_buffer.len() as u32, 0, 0)
+                    .unwrap();
+            }
+        }
+        commands.end_render_pass().unwrap();
+        let screenshot = self
+            .screenshot_request
+            .take()
+            .and_then(|path| Some((path, self.record_screenshot_copy(&mut commands)?)));
         let command_buffer = commands.build().unwrap();
 
         let af = self.acquire_future.take().unwrap();
 
         let mut local_future: Option<Box<dyn GpuFuture>> =
-            Some(Box::new(sync::now(self.device.clone())) as Box<dyn GpuFuture>);
+            Some(Box::new(sync::now(self.context.device.clone())) as Box<dyn GpuFuture>);
 
         mem::swap(&mut local_future, previous_frame_end);
 
@@ -610,10 +3125,10 @@ impl Renderer {
             .take()
             .unwrap()
             .join(af)
-            .then_execute(self.queue.clone(), command_buffer)
+            .then_execute(self.context.queue.clone(), command_buffer)
             .unwrap()
             .then_swapchain_present(
-                self.queue.clone(),
+                self.context.queue.clone(),
                 SwapchainPresentInfo::swapchain_image_index(
                     self.swapchain.clone(),
                     self.image_index,
@@ -623,19 +3138,225 @@ impl Renderer {
 
         match future {
             Ok(future) => {
+                self.present_failures = 0;
+                if let Some((path, buffer)) = screenshot {
+                    future.wait(None).unwrap();
+                    self.save_screenshot(&path, self.swapchain.image_extent(), &buffer);
+                }
                 *previous_frame_end = Some(Box::new(future) as Box<_>);
             }
             Err(FlushError::OutOfDate) => {
+                self.on_present_failure();
                 self.recreate_swapchain();
-                *previous_frame_end = Some(Box::new(sync::now(self.device.clone())) as Box<_>);
+                *previous_frame_end =
+                    Some(Box::new(sync::now(self.context.device.clone())) as Box<_>);
             }
             Err(e) => {
-                println!("Failed to flush future: {:?}", e);
-                *previous_frame_end = Some(Box::new(sync::now(self.device.clone())) as Box<_>);
+                error!("Failed to flush future: {:?}", e);
+                if self.on_present_failure() {
+                    self.recreate_swapchain();
+                }
+                *previous_frame_end =
+                    Some(Box::new(sync::now(self.context.device.clone())) as Box<_>);
             }
         }
 
         self.commands = None;
-        self.render_stage = RenderStage::Stopped;
+        // A failed present may have asked for a new swapchain that couldn't be made yet
+        if self.render_stage == RenderStage::Render {
+            self.render_stage = RenderStage::Stopped;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra_glm as glm;
+
+    use super::*;
+
+    #[test]
+    fn render_stage_recovers_through_swapchain_recreation() {
+        let never = || -> RenderStage { panic!("Swapchain recreated without a pending redraw") };
+        // start -> render -> finish, nothing to recreate
+        assert_eq!(
+            advance_stage(RenderStage::Stopped, RenderStage::Stopped, never),
+            (RenderStage::Stopped, true)
+        );
+        assert_eq!(
+            advance_stage(RenderStage::Render, RenderStage::Render, never),
+            (RenderStage::Render, true)
+        );
+        // Out of order calls drop the frame
+        assert_eq!(
+            advance_stage(RenderStage::Stopped, RenderStage::Render, never),
+            (RenderStage::Stopped, false)
+        );
+
+        // Resized before start, the frame goes on with the new swapchain
+        assert_eq!(
+            advance_stage(RenderStage::NeedsRedraw, RenderStage::Stopped, || {
+                RenderStage::Stopped
+            }),
+            (RenderStage::Stopped, true)
+        );
+        // Resized between start and finish, that frame is dropped and the next one starts
+        let (stage, proceed) = advance_stage(RenderStage::NeedsRedraw, RenderStage::Render, || {
+            RenderStage::Stopped
+        });
+        assert_eq!((stage, proceed), (RenderStage::Stopped, false));
+        assert_eq!(
+            advance_stage(stage, RenderStage::Stopped, never),
+            (RenderStage::Stopped, true)
+        );
+        // Minimized, recreation fails and is tried again on the next start
+        let (stage, proceed) =
+            advance_stage(RenderStage::NeedsRedraw, RenderStage::Stopped, || {
+                RenderStage::NeedsRedraw
+            });
+        assert_eq!((stage, proceed), (RenderStage::NeedsRedraw, false));
+        let mut recreated = false;
+        assert_eq!(
+            advance_stage(stage, RenderStage::Stopped, || {
+                recreated = true;
+                RenderStage::Stopped
+            }),
+            (RenderStage::Stopped, true)
+        );
+        assert!(recreated, "Restored window should recreate the swapchain");
+    }
+
+    // 4x MSAA and four cascades at full scale: MSAA off, two cascades, then 0.9 to 0.5 scale
+    #[test]
+    fn adaptive_quality_steps_down_and_recovers() {
+        let top = Quality {
+            render_scale: 1.0,
+            samples: SampleCount::Sample4,
+            cascades: 4,
+        };
+        let ladder = quality_ladder(top);
+        assert_eq!(ladder.len(), 1 + 1 + 2 + 5);
+        assert_eq!(ladder[1].samples, SampleCount::Sample1);
+        assert_eq!(ladder[3].cascades, ADAPTIVE_MIN_CASCADES);
+        let bottom = ladder[ladder.len() - 1];
+        assert_eq!(bottom.render_scale, ADAPTIVE_MIN_RENDER_SCALE);
+        assert_eq!(bottom.cascades, ADAPTIVE_MIN_CASCADES);
+
+        let frame = 1.0 / 60.0;
+        let mut adaptive = AdaptiveQuality::new(60.0);
+        let run = |seconds: f32, fps: f32, adaptive: &mut AdaptiveQuality| {
+            let mut steps = 0;
+            for _ in 0..(seconds / frame) as u32 {
+                steps += adaptive.update(frame, fps, ladder.len()) as u32;
+            }
+            steps
+        };
+        // Just under target, inside the margin, holds
+        assert_eq!(run(10.0, 57.0, &mut adaptive), 0);
+        // Well under steps down once per delay
+        assert_eq!(run(2.5, 40.0, &mut adaptive), 2);
+        assert_eq!(adaptive.level, 2);
+        // Bottoms out at the end of the ladder
+        run(30.0, 20.0, &mut adaptive);
+        assert_eq!(adaptive.level, ladder.len() - 1);
+        // Headroom has to last the longer up delay before each step back
+        assert_eq!(run(ADAPTIVE_UP_DELAY * 0.9, 90.0, &mut adaptive), 0);
+        assert_eq!(run(ADAPTIVE_UP_DELAY * 0.2, 90.0, &mut adaptive), 1);
+        assert_eq!(adaptive.level, ladder.len() - 2);
+    }
+
+    // Camera 10m above the water looking 60 degrees down, seabed 2m below the surface. The
+    // prepass depth behind the middle pixel has to turn back into the seabed's eye depth the
+    // way water.frag does it, leaving 2m / sin(60) of water for the shore foam band.
+    #[test]
+    fn submerged_seabed_sits_behind_the_surface() {
+        let context = match GpuContext::headless(ValidationConfig::default()) {
+            Ok(context) => context,
+            Err(e) => {
+                eprintln!("Skipping, no Vulkan device: {}", e);
+                return;
+            }
+        };
+        let allocator = &*context.memory_allocator;
+        let size = 64;
+
+        let depth_pass = create_depth_pass(&context.device);
+        let pipeline = create_seabed_pipeline(&context.device, &depth_pass);
+        let depth = AttachmentImage::with_usage(
+            allocator,
+            [size, size],
+            Format::D16_UNORM,
+            ImageUsage {
+                depth_stencil_attachment: true,
+                transfer_src: true,
+                ..ImageUsage::empty()
+            },
+        )
+        .unwrap();
+        let framebuffer = Framebuffer::new(
+            depth_pass,
+            FramebufferCreateInfo {
+                attachments: vec![ImageView::new_default(depth.clone()).unwrap()],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let (near, far) = (0.5, 100.0);
+        let mut proj = glm::perspective_rh_zo(1.0, 60.0_f32.to_radians(), near, far);
+        proj[(1, 1)] *= -1.0;
+        let pitch = 60.0_f32.to_radians();
+        let eye = glm::vec3(0.0, 10.0, 0.0);
+        let forward = glm::vec3(pitch.cos(), -pitch.sin(), 0.0);
+        let view = glm::look_at_rh(&eye, &(eye + forward), &glm::Vec3::y());
+        let seabed = seabed_vert::ty::Seabed {
+            proj: proj.into(),
+            view: view.into(),
+            center: eye.into(),
+            height: -2.0,
+            upAxis: UpAxis::Y.shader_id(),
+            extent: far,
+        };
+
+        let readback = CpuAccessibleBuffer::from_iter(
+            allocator,
+            BufferUsage {
+                transfer_dst: true,
+                ..BufferUsage::empty()
+            },
+            false,
+            (0..size * size).map(|_| 0u16),
+        )
+        .unwrap();
+        let mut commands = AutoCommandBufferBuilder::primary(
+            &context.command_buffer_allocator,
+            context.queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+        record_depth_prepass(&mut commands, framebuffer, &pipeline, Some(seabed));
+        commands
+            .copy_image_to_buffer(CopyImageToBufferInfo::image_buffer(depth, readback.clone()))
+            .unwrap();
+        commands
+            .build()
+            .unwrap()
+            .execute(context.queue.clone())
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+
+        // The middle pixel is on the view axis, where eye depth is the distance along the ray
+        let texel = readback.read().unwrap()[(size * size / 2 + size / 2) as usize];
+        let depth = texel as f32 / u16::MAX as f32;
+        let eye_depth = near * far / (far - depth * (far - near));
+        let water_depth = eye_depth - 10.0 / pitch.sin();
+        assert!(
+            (water_depth - 2.0 / pitch.sin()).abs() < 0.1,
+            "{}m of water behind the surface",
+            water_depth
+        );
     }
 }
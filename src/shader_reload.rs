@@ -0,0 +1,84 @@
+use std::{fmt, fs, io, path::Path, sync::Arc, time::SystemTime};
+
+use shaderc::{CompileOptions, Compiler, ResolvedInclude, ShaderKind};
+use vulkano::{
+    device::Device,
+    shader::{ShaderCreationError, ShaderModule},
+};
+
+// Where the sources the shader! macros compile in live, relative to the working directory
+// (cargo run from the repo root)
+pub const SHADER_DIR: &str = "src/shaders";
+
+#[derive(Debug)]
+pub enum ShaderReloadError {
+    Io(io::Error),
+    Compile(shaderc::Error),
+    Module(ShaderCreationError),
+}
+
+impl fmt::Display for ShaderReloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShaderReloadError::Io(e) => write!(f, "{}", e),
+            ShaderReloadError::Compile(e) => write!(f, "{}", e),
+            ShaderReloadError::Module(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for ShaderReloadError {
+    fn from(e: io::Error) -> Self {
+        ShaderReloadError::Io(e)
+    }
+}
+
+impl From<shaderc::Error> for ShaderReloadError {
+    fn from(e: shaderc::Error) -> Self {
+        ShaderReloadError::Compile(e)
+    }
+}
+
+impl From<ShaderCreationError> for ShaderReloadError {
+    fn from(e: ShaderCreationError) -> Self {
+        ShaderReloadError::Module(e)
+    }
+}
+
+// Compiles file from SHADER_DIR the way shader! does, #includes are looked up next to it
+pub fn compile(
+    device: &Arc<Device>,
+    file: &str,
+    kind: ShaderKind,
+    defines: &[(&str, &str)],
+) -> Result<Arc<ShaderModule>, ShaderReloadError> {
+    let dir = Path::new(SHADER_DIR);
+    let source = fs::read_to_string(dir.join(file))?;
+
+    let compiler = Compiler::new().unwrap();
+    let mut options = CompileOptions::new().unwrap();
+    options.set_include_callback(|name, _, _, _| {
+        let path = dir.join(name);
+        fs::read_to_string(&path)
+            .map(|content| ResolvedInclude {
+                resolved_name: path.display().to_string(),
+                content,
+            })
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    });
+    for (name, value) in defines {
+        options.add_macro_definition(name, Some(value));
+    }
+    let spirv = compiler.compile_into_spirv(&source, kind, file, "main", Some(&options))?;
+
+    // shaderc only hands out valid SPIR-V, vulkano checks it against the device on top
+    Ok(unsafe { ShaderModule::from_words(device.clone(), spirv.as_binary()) }?)
+}
+
+// Newest modification time in SHADER_DIR, includes count as well
+pub fn sources_modified() -> Option<SystemTime> {
+    fs::read_dir(SHADER_DIR)
+        .ok()?
+        .filter_map(|entry| entry.ok()?.metadata().ok()?.modified().ok())
+        .max()
+}
@@ -0,0 +1,26 @@
+#version 450
+
+layout(push_constant) uniform Compass {
+    vec2 direction; // Wind direction on screen, x right, y up
+    float aspectRatio;
+} compass;
+
+// Arrow pointing along +y, shaft quad and a head triangle
+const vec2 ARROW[9] = vec2[](
+    vec2(-0.15, -1.0), vec2(0.15, -1.0), vec2(0.15, 0.2),
+    vec2(-0.15, -1.0), vec2(0.15, 0.2), vec2(-0.15, 0.2),
+    vec2(-0.5, 0.2), vec2(0.5, 0.2), vec2(0.0, 1.0)
+);
+
+const vec2 CENTER = vec2(0.85, -0.8); // Top right corner
+const float SIZE = 0.08;
+
+void main() {
+    vec2 p = ARROW[gl_VertexIndex];
+    vec2 right = vec2(compass.direction.y, -compass.direction.x);
+    vec2 rotated = p.x * right + p.y * compass.direction;
+
+    // Vulkan NDC has y pointing down
+    vec2 offset = vec2(rotated.x / compass.aspectRatio, -rotated.y) * SIZE;
+    gl_Position = vec4(CENTER + offset, 0.0, 1.0);
+}
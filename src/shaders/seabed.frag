@@ -0,0 +1,10 @@
+#version 450
+
+// Wet sand, linear RGB. Unlit, it's only there to give the water something to meet.
+const vec3 SEABED_COLOR = vec3(0.2, 0.17, 0.11);
+
+layout(location = 0) out vec4 outColor;
+
+void main() {
+    outColor = vec4(SEABED_COLOR, 1.0);
+}
@@ -0,0 +1,21 @@
+#version 450
+
+// Flat ground below the water, a square of twice extent meters on a side under the camera
+layout(push_constant) uniform Seabed {
+    mat4 proj;
+    mat4 view;
+    vec3 center; // world position the square is centered on, only its horizontal part counts
+    float height; // meters along the up axis
+    uint upAxis; // 0 = Y-up, 1 = Z-up
+    float extent;
+} seabed;
+
+// Triangle strip over the four corners, no vertex buffer
+void main() {
+    vec2 corner = vec2(gl_VertexIndex & 1, gl_VertexIndex >> 1) * 2.0 - 1.0;
+    vec3 p = seabed.upAxis == 1u
+        ? vec3(seabed.center.xy + corner * seabed.extent, seabed.height)
+        : vec3(seabed.center.x, seabed.height, seabed.center.z)
+            + vec3(corner.x, 0.0, corner.y) * seabed.extent;
+    gl_Position = seabed.proj * seabed.view * vec4(p, 1.0);
+}
@@ -0,0 +1,37 @@
+#version 450
+
+layout(location = 0) in vec3 direction;
+
+layout(location = 0) out vec4 outColor;
+
+// Faces are in simulation space (Y-up), the same set water.frag reflects
+layout(set = 0, binding = 0) uniform samplerCube skybox;
+
+layout(set = 1, binding = 0) uniform OceanParams {
+    vec4 lengthScales;
+    vec4 foamWeights;
+    float lodScale;
+    float sssBase;
+    float sssScale;
+    uint upAxis; // 0 = Y-up, 1 = Z-up
+    uint cascadeCount;
+    uint waveBackend;
+    float worldUVScale;
+    float flowMapExtent;
+} params;
+
+// Only the fog of the vertex stage's Sky block
+layout(push_constant) uniform Sky {
+    layout(offset = 128) vec4 fog;
+} sky;
+
+// Inverse of toWorld in water.frag
+vec3 fromWorld(vec3 v) {
+    return params.upAxis == 1u ? vec3(v.x, v.z, -v.y) : v;
+}
+
+void main() {
+    // Underwater there's no sky past the surface, only murk
+    vec3 color = texture(skybox, fromWorld(direction)).rgb;
+    outColor = vec4(mix(color, sky.fog.rgb, sky.fog.a), 1.0);
+}
@@ -0,0 +1,21 @@
+#version 450
+
+layout(push_constant) uniform Sky {
+    mat4 proj;
+    mat4 view;
+    vec4 fog; // rgb = underwater fog color, a = how far under the camera is, 0..1
+} sky;
+
+layout(location = 0) out vec3 direction;
+
+// Same full screen triangle as upscale.vert, turned into world space view rays.
+// With a fixed clip depth w stays the same across the screen, so the rays interpolate linearly.
+void main() {
+    vec2 uv = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+    vec4 clip = vec4(uv * 2.0 - 1.0, 1.0, 1.0);
+    gl_Position = clip;
+
+    // Only the rotation of the view matters, the sky is infinitely far away
+    vec4 viewPos = inverse(sky.proj) * clip;
+    direction = transpose(mat3(sky.view)) * (viewPos.xyz / viewPos.w);
+}
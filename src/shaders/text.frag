@@ -0,0 +1,25 @@
+#version 450
+
+layout(location = 0) in vec2 fontPixel;
+layout(location = 1) flat in uint glyphIndex;
+layout(location = 2) flat in vec4 color;
+
+layout(location = 0) out vec4 outColor;
+
+// GLYPH_SIZE of overlay.rs, set when the pipeline is built
+layout(constant_id = 0) const uint glyphWidth = 5;
+layout(constant_id = 1) const uint glyphHeight = 7;
+
+// glyphHeight rows per glyph top to bottom, the highest of glyphWidth bits is the leftmost pixel
+layout(set = 0, binding = 0) readonly buffer Font {
+    uint rows[];
+} font;
+
+void main() {
+    uvec2 p = min(uvec2(fontPixel), uvec2(glyphWidth - 1, glyphHeight - 1));
+    uint row = font.rows[glyphIndex * glyphHeight + p.y];
+    if (((row >> (glyphWidth - 1 - p.x)) & 1) == 0) {
+        discard;
+    }
+    outColor = color;
+}
@@ -0,0 +1,41 @@
+#version 450
+
+// One instance per character, cell is its column and line
+layout(location = 0) in vec2 cell;
+layout(location = 1) in uint glyph;
+
+layout(push_constant) uniform Text {
+    vec2 screenSize; // In pixels
+    vec2 offset; // In pixels, moves the whole block, used for the drop shadow
+    vec4 color;
+    float scale; // Screen pixels per font pixel
+} text;
+
+layout(location = 0) out vec2 fontPixel;
+layout(location = 1) flat out uint glyphIndex;
+layout(location = 2) flat out vec4 color;
+
+// GLYPH_SIZE and CELL_SIZE of overlay.rs, set when the pipeline is built
+layout(constant_id = 0) const float glyphWidth = 5.0;
+layout(constant_id = 1) const float glyphHeight = 7.0;
+layout(constant_id = 2) const float cellWidth = 6.0;
+layout(constant_id = 3) const float cellHeight = 9.0;
+const vec2 MARGIN = vec2(8.0, 8.0); // Top left corner
+
+const vec2 CORNERS[6] = vec2[](
+    vec2(0.0, 0.0), vec2(1.0, 0.0), vec2(1.0, 1.0),
+    vec2(0.0, 0.0), vec2(1.0, 1.0), vec2(0.0, 1.0)
+);
+
+void main() {
+    vec2 corner = CORNERS[gl_VertexIndex];
+    vec2 glyphSize = vec2(glyphWidth, glyphHeight);
+    vec2 cellSize = vec2(cellWidth, cellHeight);
+    vec2 pixel = MARGIN + text.offset + (cell * cellSize + corner * glyphSize) * text.scale;
+
+    // Vulkan NDC has y pointing down, same as pixel rows
+    gl_Position = vec4(pixel / text.screenSize * 2.0 - 1.0, 0.0, 1.0);
+    fontPixel = corner * glyphSize;
+    glyphIndex = glyph;
+    color = text.color;
+}
@@ -0,0 +1,55 @@
+#version 450
+
+layout(location = 0) in vec2 uv;
+
+layout(location = 0) out vec4 outColor;
+
+// The HDR scene, sampled with nearest filtering like upscale.frag
+layout(set = 0, binding = 0) uniform sampler2D scene;
+
+layout(push_constant) uniform Tonemap {
+    vec4 tint; // multiplies the HDR color, blue-green underwater and white otherwise
+    float exposure;
+    uint passthrough; // 1 for the debug views, their values have to reach the screen as is
+    float ditherStrength; // in steps of the 8 bit output, 0 = off
+    uint srgbTarget; // 1 when the swapchain encodes to sRGB on write
+} tonemap;
+
+// 4x4 Bayer matrix, thresholds spread evenly over 0..1
+const float BAYER[16] = float[](
+    0.0, 8.0, 2.0, 10.0,
+    12.0, 4.0, 14.0, 6.0,
+    3.0, 11.0, 1.0, 9.0,
+    15.0, 7.0, 13.0, 5.0
+);
+
+vec3 linearToSrgb(vec3 c) {
+    return mix(c * 12.92, 1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055, step(0.0031308, c));
+}
+
+vec3 srgbToLinear(vec3 c) {
+    return mix(c / 12.92, pow((c + 0.055) / 1.055, vec3(2.4)), step(0.04045, c));
+}
+
+// Ordered dither against 8 bit banding, tied to the pixel so it doesn't shimmer between
+// frames. The offset is in the encoded values that actually get quantized.
+vec3 dither(vec3 color) {
+    ivec2 p = ivec2(gl_FragCoord.xy) & 3;
+    float offset = (BAYER[p.y * 4 + p.x] + 0.5) / 16.0 - 0.5;
+    offset *= tonemap.ditherStrength / 255.0;
+    if (tonemap.srgbTarget == 1u) {
+        return srgbToLinear(clamp(linearToSrgb(color) + offset, 0.0, 1.0));
+    }
+    return clamp(color + offset, 0.0, 1.0);
+}
+
+// Narkowicz's fit of the ACES filmic curve, rolls highlights off instead of clipping them
+vec3 aces(vec3 x) {
+    return clamp((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14), 0.0, 1.0);
+}
+
+void main() {
+    vec3 color = texture(scene, uv).rgb;
+    vec3 tinted = color * tonemap.tint.rgb * tonemap.exposure;
+    outColor = vec4(tonemap.passthrough == 1u ? color : dither(aces(tinted)), 1.0);
+}
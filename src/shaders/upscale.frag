@@ -0,0 +1,12 @@
+#version 450
+
+layout(location = 0) in vec2 uv;
+
+layout(location = 0) out vec4 outColor;
+
+// The reduced resolution scene, sampled with nearest filtering for the blocky look
+layout(set = 0, binding = 0) uniform sampler2D scene;
+
+void main() {
+    outColor = texture(scene, uv);
+}
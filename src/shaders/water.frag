@@ -1,28 +1,48 @@
 #version 450
 
 layout(location = 0) in vec2 worldUV;
-layout(location = 1) in float lodScale;
+layout(location = 1) in vec4 lodScales;
 layout(location = 2) in float sssScaleFactor;
 layout(location = 3) in vec3 viewVector;
 layout(location = 4) in vec4 screenPos;
+layout(location = 5) in vec2 flowOffset0;
+layout(location = 6) in vec2 flowOffset1;
+layout(location = 7) in float flowBlend;
+layout(location = 8) in vec4 gerstnerDerivs; // 0 unless the Gerstner fallback is active
+layout(location = 9) in vec2 jitteredUV;
+layout(location = 10) flat in vec2 jitterTurn;
+layout(location = 11) in float jitterBlend;
 
-layout(set = 0, binding = 0) uniform sampler2D displacement;
-layout(set = 0, binding = 1) uniform sampler2D derivatives;
-layout(set = 0, binding = 2) uniform sampler2D turbulence;
-layout(set = 0, binding = 3) uniform sampler2D cameraDepthTexture;
+layout(set = 0, binding = 0) uniform sampler2DArray displacement;
+layout(set = 0, binding = 1) uniform sampler2DArray derivatives;
+layout(set = 0, binding = 2) uniform sampler2DArray turbulence;
 layout(set = 0, binding = 4) uniform sampler2D foamTexture;
 
 layout(set = 1, binding = 0) uniform OceanParams {
-    float lengthScale;
+    vec4 lengthScales; // meters per patch of each cascade
+    vec4 foamWeights; // how much of each cascade's foam shows
     float lodScale;
     float sssBase;
     float sssScale;
+    uint upAxis; // 0 = Y-up, 1 = Z-up
+    uint cascadeCount;
+    uint waveBackend; // 0 = FFT maps, 1 = Gerstner waves below
+    float worldUVScale; // simulation meters per world unit, above 1 shrinks the waves
+    float flowMapExtent; // world meters the flow map spans, centered on the origin
 } params;
 
+// Renderer::set_foam_gradient takes at most this many
+const uint MAX_FOAM_STOPS = 4u;
+
 layout(set = 1, binding = 1) uniform MaterialParams {
-    vec4 color;
-    vec4 foamColor;
+    vec4 baseWaterColor; // linear, near the camera
+    vec4 deepWaterColor; // linear, towards the horizon
+    vec4 foamGradient[MAX_FOAM_STOPS]; // rgb = linear color, a = foam amount it's at, ascending
     vec4 sssColor;
+    vec4 shoreFoamColor;
+    vec4 sunColor; // rgb, linear
+    vec4 underwaterFog; // rgb = linear color, a = density per meter
+    vec4 distanceFog; // rgb = linear horizon color, a = density per meter
     float sssStrength;
     float roughness;
     float roughnessScale;
@@ -31,45 +51,234 @@ layout(set = 1, binding = 1) uniform MaterialParams {
     float foamScale;
     float contactFoam;
     float time;
-    vec3 lightDir;
+    vec3 lightDir; // towards the sun
+    float sunIntensity;
+    float glitterStrength;
+    uint debugView; // 0 = shaded, 1 = normals, 2 = foam, 3 = height, 4 = steepness, 5 = uv
+    uint srgbTarget; // 1 when the swapchain encodes to sRGB on write
+    float shoreFoamWidth; // meters of water behind the surface where shore foam fades out, 0 = off
+    float deepWaterDistance; // meters, 1 - 1/e of the way from the base to the deep color
+    uint foamStopCount;
 } material;
 
+// Only the part of the vertex stage's Camera block the lighting needs
+layout(push_constant) uniform Camera {
+    layout(offset = 144) float underwater;
+    float zNear;
+    float zFar;
+} cam;
+
+// Only the forward build reflects the environment, the G-buffer pipelines have no set 2
+#ifndef DEFERRED
+// The sky prefiltered for GGX, mip 0 mirror sharp to the last mip fully rough. Faces in
+// simulation space.
+layout(set = 2, binding = 0) uniform samplerCube environment;
+#endif
+
+// Depth of everything but the water (the depth prepass), same resolution as the scene
+layout(set = 3, binding = 0) uniform sampler2D sceneDepth;
+
+// Deferred builds (compiled with DEFERRED defined) fill the G-buffer instead of lighting
+#ifdef DEFERRED
+layout(location = 0) out vec4 outNormal; // xyz = world normal, -1..1
+layout(location = 1) out vec4 outAlbedo; // rgb = water color with foam and sss, a = foam
+layout(location = 2) out vec4 outRoughness; // r = roughness
+#else
 layout(location = 0) out vec4 outColor;
+#endif
+
+// Thin foam takes the first stops' colors, thick churn the last ones, linear in between
+vec3 foamColor(float amount) {
+    vec3 color = material.foamGradient[0].rgb;
+    for (uint i = 1u; i < min(material.foamStopCount, MAX_FOAM_STOPS); i++) {
+        vec4 previous = material.foamGradient[i - 1u];
+        vec4 stop = material.foamGradient[i];
+        float t = clamp((amount - previous.a) / max(stop.a - previous.a, 1e-4), 0.0, 1.0);
+        color = mix(color, stop.rgb, t);
+    }
+    return color;
+}
 
 float pow5(float f) {
     return f * f * f * f * f;
 }
 
-float linearEyeDepth(float depth) {
-    float near = 0.1;
-    float far = 1000.0;
-    return (2.0 * near) / (far + near - depth * (far - near));
+// Distance from the camera along the view axis for a 0..1 depth buffer value
+float eyeDepth(float depth) {
+    float near = cam.zNear;
+    float far = cam.zFar;
+    return near * far / (far - depth * (far - near));
+}
+
+// 1 where geometry behind the surface is right at the waterline, fading to 0 at
+// shoreFoamWidth meters of water. Measured along the view axis, which is good enough for a band.
+// Geometry in front of the surface (negative depth) hides the water anyway, so no foam there.
+float shoreFoam(float waterDepth) {
+    if (material.shoreFoamWidth <= 0.0) {
+        return 0.0;
+    }
+    if (waterDepth < 0.0) {
+        return 0.0;
+    }
+    return 1.0 - smoothstep(0.0, material.shoreFoamWidth, waterDepth);
+}
+
+// Map lookup following the flow map advection set up in the vertex shader
+vec4 sampleFlowed(sampler2DArray tex, vec2 uv, uint cascade) {
+    float lengthScale = params.lengthScales[cascade];
+    return mix(
+        texture(tex, vec3((uv + flowOffset0) / lengthScale, float(cascade))),
+        texture(tex, vec3((uv + flowOffset1) / lengthScale, float(cascade))),
+        flowBlend
+    );
+}
+
+// sampleFlowed cross-faded from the plain to the jittered sample the same way the vertices are
+// displaced, see displaceSurface. Both are always taken, a branch would break the implicit
+// derivatives the mip selection needs. turned maps get their xy (a slope) turned back into
+// simulation space, the stretch terms in zw are left as sampled.
+vec4 sampleTile(sampler2DArray tex, uint cascade, bool turned) {
+    vec4 jittered = sampleFlowed(tex, jitteredUV, cascade);
+    if (turned) {
+        jittered.xy = vec2(
+            jitterTurn.x * jittered.x + jitterTurn.y * jittered.y,
+            jitterTurn.x * jittered.y - jitterTurn.y * jittered.x
+        );
+    }
+    return mix(sampleFlowed(tex, worldUV, cascade), jittered, jitterBlend);
+}
+
+float hash12(vec2 p) {
+    vec3 p3 = fract(vec3(p.xyx) * 0.1031);
+    p3 += dot(p3, p3.yzx + 33.33);
+    return fract((p3.x + p3.y) * p3.z);
+}
+
+// Cox-Munk style glitter. Facets smaller than a texel still have slopes, their variance is
+// estimated from how fast the resolved slope changes across the pixel, and the slope
+// distribution gives how likely one of them mirrors the sun into the eye.
+// The slope is the sum over all cascades, so the smaller ones add facets of their own.
+float glitter(vec3 normal, vec3 halfVec, vec2 slope) {
+    vec2 slopeWidth = fwidth(slope);
+    float variance = max(dot(slopeWidth, slopeWidth), 1e-4);
+
+    float ndoth = max(dot(normal, halfVec), 1e-3);
+    float ndoth2 = ndoth * ndoth;
+    float tan2 = (1.0 - ndoth2) / ndoth2;
+    float distribution = exp(-tan2 / variance) / (3.1415926 * variance * ndoth2 * ndoth2);
+
+    // Only a few facets are lined up at a time, which is what makes it sparkle
+    float sparkle = step(0.97, hash12(floor(worldUV * 8.0) + floor(material.time * 10.0)));
+    return min(distribution * sparkle, 1.0);
+}
+
+vec3 srgbToLinear(vec3 c) {
+    return mix(c / 12.92, pow((c + 0.055) / 1.055, vec3(2.4)), step(0.04045, c));
+}
+
+// Meters above and below the rest level the height debug view spans
+const float DEBUG_HEIGHT_RANGE = 2.0;
+
+// Debug views show raw data, a value of 0.5 has to end up as 0.5 in the framebuffer
+// (mid gray) so it can be read off the screen. An sRGB swapchain would encode it to
+// ~0.73 on write, so undo that here. No lighting or tone mapping touches these.
+vec4 debugOutput(vec3 value) {
+    return vec4(material.srgbTarget == 1u ? srgbToLinear(value) : value, 1.0);
+}
+
+// Derivatives map layout, same for every cascade layer and the Gerstner fallback:
+// x = dDy/dx, y = dDy/dz (height slope), z = dDx/dx, w = dDz/dz (horizontal stretch).
+// Vertices are pushed sideways as well, so the height slope is divided by how far the surface
+// got stretched along each axis. Layers are summed before this, never the normals after.
+vec2 surfaceSlope(vec4 derivs) {
+    return vec2(derivs.x / (1.0 + derivs.z), derivs.y / (1.0 + derivs.w));
+}
+
+// Normal of the displaced surface in simulation space (Y-up)
+vec3 surfaceNormal(vec2 slope) {
+    return normalize(vec3(-slope.x, 1.0, -slope.y));
+}
+
+// Simulation space is Y-up, normals and light direction get moved into world space
+vec3 toWorld(vec3 v) {
+    return params.upAxis == 1u ? vec3(v.x, -v.z, v.y) : v;
+}
+
+vec3 fromWorld(vec3 v) {
+    return params.upAxis == 1u ? vec3(v.x, v.z, -v.y) : v;
 }
 
 void main() {
-    vec4 derivs = texture(derivatives, worldUV / params.lengthScale);
+    vec3 lightDir = toWorld(material.lightDir);
+    // From below the surface is lit from its other side
+    float facing = cam.underwater > 0.5 ? -1.0 : 1.0;
+    // The largest cascade is always there, the smaller ones fade with the vertex LOD
+    vec4 swellDerivs = sampleTile(derivatives, 0u, true) + gerstnerDerivs;
+    vec4 derivs = swellDerivs;
+    for (uint i = 1u; i < params.cascadeCount; i++) {
+        derivs += sampleTile(derivatives, i, true) * lodScales[i];
+    }
     
-    vec2 slope = vec2(
-        derivs.x / (1.0 + derivs.z),
-        derivs.y / (1.0 + derivs.w)
-    );
-    vec3 worldNormal = normalize(vec3(-slope.x, 1.0, -slope.y));
+    // Height gradients are per simulation meter, the horizontal ones cancel out
+    derivs.xy *= params.worldUVScale;
+    swellDerivs.xy *= params.worldUVScale;
+    vec2 slope = surfaceSlope(derivs);
+    vec3 worldNormal = toWorld(surfaceNormal(slope)) * facing;
     
     // Calculate foam/turbulence (jacobian)
-    float jacobian = texture(turbulence, worldUV / params.lengthScale).x;
+    // Each cascade's turbulence sits around 1 on calm water, sum how far they dip below that
+    // so foamBias means the same thing for any number of cascades. Every cascade folds on its
+    // own scale, the weights keep the broad swell folds from smearing foam over the fine chop.
+    float jacobian = 1.0;
+    for (uint i = 0u; i < params.cascadeCount; i++) {
+        jacobian += params.foamWeights[i] * (sampleTile(turbulence, i, false).x - 1.0);
+    }
     jacobian = clamp((-jacobian + material.foamBias) * material.foamScale, 0.0, 1.0);
     
-    // Contact foam (depth-based)
+    // Meters of water along the view axis between the surface and the geometry behind it,
+    // around the far plane where there is none
     vec2 screenUV = (screenPos.xy / screenPos.w) * 0.5 + 0.5;
-    float backgroundDepth = linearEyeDepth(texture(cameraDepthTexture, screenUV).r);
-    float surfaceDepth = screenPos.z / screenPos.w;
-    float depthDifference = max(0.0, backgroundDepth - surfaceDepth - 0.1);
-    
+    float waterDepth = eyeDepth(texture(sceneDepth, screenUV).r) - screenPos.w;
+
+    // Contact foam, broken up by the foam texture where geometry is just behind the surface
+    float depthDifference = max(0.0, waterDepth - 0.1);
     float foam = texture(foamTexture, worldUV * 0.5 + material.time).r;
     jacobian += material.contactFoam * clamp(max(0.0, foam - depthDifference) * 5.0, 0.0, 1.0) * 0.9;
+    float shore = shoreFoam(waterDepth);
+
+#ifndef DEFERRED
+    if (material.debugView == 1u) {
+        outColor = debugOutput(worldNormal * 0.5 + 0.5);
+        return;
+    }
+    if (material.debugView == 2u) {
+        outColor = debugOutput(vec3(clamp(max(jacobian, shore), 0.0, 1.0)));
+        return;
+    }
+    if (material.debugView == 3u) {
+        float height = 0.0;
+        for (uint i = 0u; i < params.cascadeCount; i++) {
+            height += sampleTile(displacement, i, false).y * lodScales[i];
+        }
+        float t = clamp(height / DEBUG_HEIGHT_RANGE * 0.5 + 0.5, 0.0, 1.0);
+        outColor = debugOutput(mix(vec3(0.0, 0.05, 0.3), vec3(1.0), t));
+        return;
+    }
+    if (material.debugView == 4u) {
+        float t = clamp(atan(length(slope)) / radians(45.0), 0.0, 1.0);
+        outColor = debugOutput(t < 0.5
+            ? mix(vec3(0.0, 1.0, 0.0), vec3(1.0, 1.0, 0.0), t * 2.0)
+            : mix(vec3(1.0, 1.0, 0.0), vec3(1.0, 0.0, 0.0), t * 2.0 - 1.0));
+        return;
+    }
+    if (material.debugView == 5u) {
+        outColor = debugOutput(vec3(fract(worldUV / params.lengthScales[0]), 0.0));
+        return;
+    }
+#endif
     
     // Albedo (base color with foam)
-    vec3 albedo = mix(vec3(0.0), material.foamColor.rgb, jacobian);
+    vec3 albedo = mix(vec3(0.0), foamColor(jacobian), jacobian);
     
     // Smoothness/roughness calculation
     float distanceGloss = mix(
@@ -79,29 +288,67 @@ void main() {
     );
     float smoothness = mix(distanceGloss, 0.0, jacobian);
     
-    // Subsurface scattering
+    // Subsurface scattering, light coming through the back of a crest towards the camera.
+    // Only the largest waves tilt it, the ripples of the smaller cascades change direction
+    // within a pixel and would make the glow flicker as they move.
     vec3 viewDir = normalize(viewVector);
-    vec3 H = normalize(-worldNormal + material.lightDir);
+    vec3 swellNormal = toWorld(surfaceNormal(surfaceSlope(swellDerivs))) * facing;
+    vec3 H = normalize(-swellNormal + lightDir);
     float viewDotH = pow5(clamp(dot(viewDir, -H), 0.0, 1.0)) * 30.0 * material.sssStrength;
-    vec3 baseColor = clamp(material.color.rgb + material.sssColor.rgb * viewDotH * sssScaleFactor, 0.0, 1.0);
-    
+    // Looking further out the view goes through more water, shifting towards the deep tint
+    float depthBlend = 1.0 - exp(-length(viewVector) / material.deepWaterDistance);
+    vec3 waterColor = mix(material.baseWaterColor.rgb, material.deepWaterColor.rgb, depthBlend);
+    vec3 baseColor = clamp(waterColor + material.sssColor.rgb * viewDotH * sssScaleFactor, 0.0, 1.0);
+
+#ifdef DEFERRED
+    float foamAmount = clamp(jacobian, 0.0, 1.0);
+    outNormal = vec4(worldNormal, 0.0);
+    vec3 foamed = mix(baseColor, foamColor(foamAmount), foamAmount);
+    outAlbedo = vec4(mix(foamed, material.shoreFoamColor.rgb, shore), max(foamAmount, shore));
+    outRoughness = vec4(1.0 - smoothness, 0.0, 0.0, 0.0);
+#else
     // Fresnel
     float fresnel = dot(worldNormal, viewDir);
     fresnel = clamp(1.0 - fresnel, 0.0, 1.0);
     fresnel = pow5(fresnel);
     
+    // Environment reflection, kept above the horizon so faces tilted away from the camera
+    // don't pick up the bottom of the cubemap
+    vec3 skyDir = fromWorld(reflect(-viewDir, worldNormal));
+    skyDir.y = max(skyDir.y, 0.0);
+    float environmentLod = (1.0 - smoothness) * float(textureQueryLevels(environment) - 1);
+    vec3 reflection = textureLod(environment, skyDir, environmentLod).rgb;
+
     // Foam(basically a mask where white is foam and black is water)
-    vec3 emission = mix(baseColor * (1.0 - fresnel), vec3(0.0), jacobian);
+    vec3 emission = mix(baseColor * (1.0 - fresnel) + reflection * fresnel, vec3(0.0), jacobian);
     
+    vec3 sun = material.sunColor.rgb * material.sunIntensity;
+
     // Dot diffuse light
-    float ndotl = max(0.0, dot(worldNormal, material.lightDir));
-    vec3 diffuse = albedo * (0.2 + ndotl * 0.8);
+    float ndotl = max(0.0, dot(worldNormal, lightDir));
+    vec3 diffuse = albedo * (0.2 + ndotl * 0.8 * sun);
     
-    // Specular (simplified Blinn-Phong)
-    vec3 halfVec = normalize(viewDir + material.lightDir);
+    // Blinn-Phong, normalized so a glossier surface gets a brighter highlight rather than just
+    // a smaller one. Weighted by Schlick's Fresnel (water reflects 2% head on) and ndotl, the
+    // latter fades it out as the sun nears the horizon so grazing light doesn't draw a hard
+    // bright line over the whole ocean.
+    vec3 halfVec = normalize(viewDir + lightDir);
     float ndoth = max(0.0, dot(worldNormal, halfVec));
     float specPower = exp2(smoothness * 10.0 + 1.0);
-    vec3 specular = vec3(pow(ndoth, specPower)) * smoothness;
+    float specFresnel = 0.02 + 0.98 * pow5(1.0 - max(dot(viewDir, halfVec), 0.0));
+    float lobe = pow(ndoth, specPower) * (specPower + 8.0) / 8.0;
+    vec3 specular = sun * lobe * specFresnel * ndotl * smoothness;
+    specular += sun * glitter(worldNormal, halfVec, slope) * ndotl * material.glitterStrength;
     
-    outColor = vec4(diffuse + specular + emission, 1.0);
+    vec3 color = mix(diffuse + specular + emission, material.shoreFoamColor.rgb, shore);
+    // From above, the far tiles fade into the horizon so the grid doesn't end in a hard edge.
+    // Squared so the water up close stays clear.
+    // Horizon fog is for looking over the water, below it the underwater fog takes over
+    float distanceFog = length(viewVector) * material.distanceFog.a;
+    float horizon = (1.0 - cam.underwater) * (1.0 - exp(-distanceFog * distanceFog));
+    color = mix(color, material.distanceFog.rgb, horizon);
+    // Seen from underwater the surface fades into the murk with distance
+    float fog = cam.underwater * (1.0 - exp(-length(viewVector) * material.underwaterFog.a));
+    outColor = vec4(mix(color, material.underwaterFog.rgb, fog), 1.0);
+#endif
 }
\ No newline at end of file
@@ -0,0 +1,18 @@
+#version 450
+
+// Control points of the tessellated water, the displacement happens in water.tese
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec2 uv;
+layout(location = 2) in mat4 instance_model;
+layout(location = 6) in mat4 instance_normal;
+layout(location = 10) in mat2x4 uv_transform;
+
+layout(location = 0) out vec4 patchWorldPos;
+layout(location = 1) out vec2 patchUV;
+layout(location = 2) out mat2x4 patchUVTransform;
+
+void main() {
+    patchWorldPos = instance_model * vec4(position, 1.0);
+    patchUV = uv;
+    patchUVTransform = uv_transform;
+}
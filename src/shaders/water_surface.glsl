@@ -0,0 +1,174 @@
+// Shared by water.vert and water.tese, everything between the flat grid and the fragment shader
+
+// One layer per cascade, largest patch first
+layout(set = 0, binding = 0) uniform sampler2DArray displacement;
+layout(set = 0, binding = 1) uniform sampler2DArray derivatives;
+layout(set = 0, binding = 2) uniform sampler2DArray turbulence;
+layout(set = 0, binding = 5) uniform sampler2D flowMap;
+
+layout(set = 1, binding = 0) uniform OceanParams {
+    vec4 lengthScales; // meters per patch of each cascade
+    vec4 foamWeights; // how much of each cascade's foam shows
+    float lodScale;
+    float sssBase;
+    float sssScale;
+    uint upAxis; // 0 = Y-up, 1 = Z-up
+    uint cascadeCount;
+    uint waveBackend; // 0 = FFT maps, 1 = Gerstner waves below
+    float worldUVScale; // simulation meters per world unit, above 1 shrinks the waves
+    float flowMapExtent; // world meters the flow map spans, centered on the origin
+} params;
+
+// Fallback for devices that can't run the FFT simulation, the maps are flat then
+const uint MAX_GERSTNER_WAVES = 8;
+layout(set = 1, binding = 2) uniform GerstnerWaves {
+    vec4 waves[MAX_GERSTNER_WAVES]; // xy = direction, z = steepness, w = wavelength in meters
+    uint waveCount;
+} gerstner;
+
+layout(push_constant) uniform Camera {
+    mat4 proj;
+    mat4 view;
+    vec3 pos;
+    float time;
+    float underwater; // 0 above the surface, 1 below, in between while crossing it
+    float zNear; // clip planes of proj, meters
+    float zFar;
+} cam;
+
+layout(location = 0) out vec2 worldUV;
+layout(location = 1) out vec4 lodScales;
+layout(location = 2) out float sssScaleFactor;
+layout(location = 3) out vec3 viewVector;
+layout(location = 4) out vec4 screenPos;
+layout(location = 5) out vec2 flowOffset0;
+layout(location = 6) out vec2 flowOffset1;
+layout(location = 7) out float flowBlend;
+layout(location = 8) out vec4 gerstnerDerivs;
+layout(location = 9) out vec2 jitteredUV;
+layout(location = 10) flat out vec2 jitterTurn; // cos, sin of the tile's uv rotation
+layout(location = 11) out float jitterBlend;
+
+// Flow map advection, two layers scrolled half a period apart and crossfaded
+// so neither one ever stretches further than FLOW_PERIOD seconds of movement
+const float FLOW_PERIOD = 4.0;
+
+const float GRAVITY = 9.81;
+
+// Sum of the Gerstner waves at p, derivs gets the same layout as the derivatives map
+// (dDy/dx, dDy/dz, dDx/dx, dDz/dz) so the fragment shader can add them to it
+vec3 gerstnerDisplacement(vec2 p, float time, out vec4 derivs) {
+    vec3 displacement = vec3(0.0);
+    derivs = vec4(0.0);
+    for (uint i = 0u; i < min(gerstner.waveCount, MAX_GERSTNER_WAVES); i++) {
+        vec4 wave = gerstner.waves[i];
+        vec2 dir = normalize(wave.xy);
+        float k = 2.0 * 3.1415926 / wave.w;
+        float phase = k * (dot(dir, p) - sqrt(GRAVITY / k) * time);
+        float amplitude = wave.z / k;
+
+        displacement += vec3(dir.x * cos(phase), sin(phase), dir.y * cos(phase)) * amplitude;
+        derivs += vec4(
+            dir.x * wave.z * cos(phase),
+            dir.y * wave.z * cos(phase),
+            -dir.x * dir.x * wave.z * sin(phase),
+            -dir.y * dir.y * wave.z * sin(phase)
+        );
+    }
+    return displacement;
+}
+
+// Simulation space is Y-up, these move between it and the configured world up axis
+vec3 toWorld(vec3 v) {
+    return params.upAxis == 1u ? vec3(v.x, -v.z, v.y) : v;
+}
+
+vec2 toSimPlane(vec3 p) {
+    return params.upAxis == 1u ? vec2(p.x, -p.y) : p.xz;
+}
+
+// Fraction of the tile along each edge over which its jittered samples cross-fade to the plain
+// ones, so both tiles sample the same spot on the edge they share
+const float UV_TRANSFORM_FADE = 0.15;
+
+// Instance::uv_transform applied to p (simulation meters), a rigid turn and shift
+vec2 transformUV(vec2 p, mat2x4 uvTransform) {
+    vec2 center = vec2(uvTransform[0].w, uvTransform[1].w) * params.worldUVScale;
+    vec2 offset = vec2(uvTransform[0].z, uvTransform[1].z);
+    vec2 local = p - center;
+    return center + vec2(dot(uvTransform[0].xy, local), dot(uvTransform[1].xy, local)) + offset;
+}
+
+// A vector sampled at a transformUV position, turned back into simulation space
+vec2 unturn(vec2 v) {
+    return vec2(jitterTurn.x * v.x + jitterTurn.y * v.y, jitterTurn.x * v.y - jitterTurn.y * v.x);
+}
+
+// Flow advected displacement of one cascade at p (simulation meters)
+vec3 cascadeDisplacement(vec2 p, uint cascade) {
+    float lengthScale = params.lengthScales[cascade];
+    float layer = float(cascade);
+    return mix(
+        textureLod(displacement, vec3((p + flowOffset0) / lengthScale, layer), 0).xyz,
+        textureLod(displacement, vec3((p + flowOffset1) / lengthScale, layer), 0).xyz,
+        flowBlend
+    );
+}
+
+// Displaces a point of the flat water plane and fills in everything the fragment shader needs,
+// uv is the point's position on its tile for the uv transform's edge fade
+void displaceSurface(vec4 worldPos, vec2 uv, mat2x4 uvTransform) {
+    // The maps repeat, so the plain samples line up across tiles. Inside a tile they cross-fade
+    // to samples moved by its uv transform, which hides the repetition without stretching waves.
+    worldUV = toSimPlane(worldPos.xyz) * params.worldUVScale;
+    jitteredUV = transformUV(worldUV, uvTransform);
+    jitterTurn = vec2(uvTransform[0].x, uvTransform[1].x);
+    vec2 edge = min(uv, 1.0 - uv);
+    jitterBlend = smoothstep(0.0, UV_TRANSFORM_FADE, min(edge.x, edge.y));
+    
+    viewVector = cam.pos - worldPos.xyz;
+    float viewDist = length(viewVector);
+    
+    // Small cascades fade out with distance before their detail turns into aliasing
+    lodScales = vec4(0.0);
+    for (uint i = 0u; i < params.cascadeCount; i++) {
+        float worldLength = params.lengthScales[i] / params.worldUVScale;
+        lodScales[i] = min(params.lodScale * worldLength / viewDist, 1.0);
+    }
+    
+    // The flow map spans the whole body, beyond it the edge texels carry on
+    vec2 halfTexel = 0.5 / vec2(textureSize(flowMap, 0));
+    vec2 bodyUV = toSimPlane(worldPos.xyz) / params.flowMapExtent + 0.5;
+    // World meters per second, the offsets are in simulation meters like worldUV
+    vec2 flow = textureLod(flowMap, clamp(bodyUV, halfTexel, 1.0 - halfTexel), 0).xy;
+    flow *= params.worldUVScale;
+    float phase0 = fract(cam.time / FLOW_PERIOD);
+    float phase1 = fract(cam.time / FLOW_PERIOD + 0.5);
+    flowOffset0 = -flow * phase0 * FLOW_PERIOD;
+    flowOffset1 = -flow * phase1 * FLOW_PERIOD;
+    flowBlend = abs(1.0 - 2.0 * phase0);
+
+    vec3 displacementVec = vec3(0.0);
+    gerstnerDerivs = vec4(0.0);
+    if (params.waveBackend == 1u) {
+        displacementVec = gerstnerDisplacement(worldUV, cam.time, gerstnerDerivs);
+    }
+    for (uint i = 0u; i < params.cascadeCount; i++) {
+        // Only the fade band along the tile edges needs both samples
+        vec3 jittered = vec3(0.0);
+        if (jitterBlend > 0.0) {
+            jittered = cascadeDisplacement(jitteredUV, i);
+            jittered.xz = unturn(jittered.xz);
+        }
+        vec3 plain = jitterBlend < 1.0 ? cascadeDisplacement(worldUV, i) : vec3(0.0);
+        displacementVec += mix(plain, jittered, jitterBlend) * lodScales[i];
+    }
+    // Back from simulation meters, heights keep their size
+    displacementVec.xz /= params.worldUVScale;
+    worldPos.xyz += toWorld(displacementVec);
+    
+    sssScaleFactor = max(displacementVec.y - params.sssBase, 0.0) / params.sssScale;
+    
+    gl_Position = cam.proj * cam.view * worldPos;
+    screenPos = gl_Position;
+}
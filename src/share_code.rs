@@ -0,0 +1,157 @@
+// Compact, copy-pasteable encoding of everything needed to reproduce a frame.
+// Layout is a version byte followed by little endian fields, then url-safe base64.
+// New fields only ever get appended with a version bump, older codes decode with defaults.
+
+use std::fmt;
+
+const SHARE_CODE_VERSION: u8 = 1;
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShareCodeError {
+    InvalidCharacter(char),
+    UnsupportedVersion(u8),
+    Truncated,
+}
+
+impl fmt::Display for ShareCodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShareCodeError::InvalidCharacter(c) => {
+                write!(f, "'{}' isn't a share code character", c)
+            }
+            ShareCodeError::UnsupportedVersion(version) => {
+                write!(f, "share code version {} isn't supported", version)
+            }
+            ShareCodeError::Truncated => write!(f, "share code is cut off"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShareState {
+    pub length_scale: f32,
+    pub lod_scale: f32,
+    pub sss_base: f32,
+    pub sss_scale: f32,
+    pub crossing_angle: f32,
+    pub secondary_strength: f32,
+    pub time: f32,
+    // None in codes that carry no seed, applying one of those keeps the current noise
+    pub seed: Option<u64>,
+}
+
+impl ShareState {
+    pub fn to_share_code(self) -> String {
+        let mut bytes = vec![SHARE_CODE_VERSION];
+        for value in [
+            self.length_scale,
+            self.lod_scale,
+            self.sss_base,
+            self.sss_scale,
+            self.crossing_angle,
+            self.secondary_strength,
+            self.time,
+        ] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        match self.seed {
+            Some(seed) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&seed.to_le_bytes());
+            }
+            None => bytes.push(0),
+        }
+
+        encode_base64(&bytes)
+    }
+
+    pub fn from_share_code(code: &str) -> Result<Self, ShareCodeError> {
+        let bytes = decode_base64(code.trim())?;
+        let mut reader = Reader { bytes: &bytes };
+
+        let version = reader.u8()?;
+        if version == 0 || version > SHARE_CODE_VERSION {
+            return Err(ShareCodeError::UnsupportedVersion(version));
+        }
+
+        let mut state = ShareState {
+            length_scale: reader.f32()?,
+            lod_scale: reader.f32()?,
+            sss_base: reader.f32()?,
+            sss_scale: reader.f32()?,
+            crossing_angle: reader.f32()?,
+            secondary_strength: reader.f32()?,
+            time: reader.f32()?,
+            seed: None,
+        };
+        if reader.u8()? == 1 {
+            state.seed = Some(reader.u64()?);
+        }
+
+        Ok(state)
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], ShareCodeError> {
+        if self.bytes.len() < N {
+            return Err(ShareCodeError::Truncated);
+        }
+        let (head, rest) = self.bytes.split_at(N);
+        self.bytes = rest;
+        Ok(head.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, ShareCodeError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn f32(&mut self) -> Result<f32, ShareCodeError> {
+        Ok(f32::from_le_bytes(self.take()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, ShareCodeError> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+}
+
+fn encode_base64(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        // No padding, the decoder works out the length from the character count
+        for i in 0..chunk.len() + 1 {
+            out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    out
+}
+
+fn decode_base64(code: &str) -> Result<Vec<u8>, ShareCodeError> {
+    let mut out = Vec::with_capacity(code.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for c in code.chars() {
+        let value = ALPHABET
+            .iter()
+            .position(|&a| a as char == c)
+            .ok_or(ShareCodeError::InvalidCharacter(c))?;
+        acc = acc << 6 | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits & 0xff) as u8);
+        }
+    }
+    Ok(out)
+}
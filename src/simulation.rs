@@ -1,26 +1,44 @@
-use std::sync::Arc;
+use std::{
+    f32::consts::{PI, TAU},
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytemuck::{Pod, Zeroable};
+use log::{debug, warn};
+use nalgebra_glm::{Vec2, Vec3};
 
+use rand::{SeedableRng, rngs::StdRng};
 use rand_distr::Distribution;
 use vulkano::{
     buffer::{BufferContents, BufferUsage, CpuAccessibleBuffer},
     command_buffer::{
-        AutoCommandBufferBuilder, CommandBufferUsage, CopyBufferToImageInfo, CopyImageInfo,
-        PrimaryAutoCommandBuffer, PrimaryCommandBufferAbstract,
-        allocator::StandardCommandBufferAllocator,
+        AutoCommandBufferBuilder, ClearColorImageInfo, CommandBufferUsage, CopyBufferToImageInfo,
+        CopyImageInfo, CopyImageToBufferInfo, PrimaryAutoCommandBuffer,
+        PrimaryCommandBufferAbstract, allocator::StandardCommandBufferAllocator,
     },
     descriptor_set::{
         PersistentDescriptorSet, WriteDescriptorSet, allocator::StandardDescriptorSetAllocator,
     },
     device::{Device, Queue},
-    format::Format,
-    image::{ImageDimensions, ImageUsage, StorageImage, view::ImageView},
+    format::{ClearColorValue, Format},
+    image::{
+        ImageDimensions, ImageUsage, StorageImage,
+        view::{ImageView, ImageViewCreateInfo, ImageViewType},
+    },
     memory::allocator::StandardMemoryAllocator,
     pipeline::{ComputePipeline, Pipeline, PipelineBindPoint},
+    query::{QueryPool, QueryPoolCreateInfo, QueryResultFlags, QueryType},
     sampler::Sampler,
-    shader::ShaderModule,
-    sync::GpuFuture,
+    shader::{ShaderCreationError, ShaderModule},
+    sync::{GpuFuture, PipelineStage},
 };
 
+use crate::gpu_context::GpuContext;
+
 mod init_spec_shader {
     vulkano_shaders::shader! {
         ty: "compute",
@@ -76,6 +94,39 @@ mod fft_shader {
         },
     }
 }
+mod noise_gen_shader {
+    vulkano_shaders::shader! {
+        ty: "compute",
+        path: "src/shaders/noise_gen.comp",
+        types_meta: {
+            use bytemuck::{Pod, Zeroable};
+
+            #[derive(Clone, Copy, Zeroable, Pod)]
+        },
+    }
+}
+mod disturbance_shader {
+    vulkano_shaders::shader! {
+        ty: "compute",
+        path: "src/shaders/disturbance.comp",
+        types_meta: {
+            use bytemuck::{Pod, Zeroable};
+
+            #[derive(Clone, Copy, Zeroable, Pod)]
+        },
+    }
+}
+mod sanity_check_shader {
+    vulkano_shaders::shader! {
+        ty: "compute",
+        path: "src/shaders/sanity_check.comp",
+        types_meta: {
+            use bytemuck::{Pod, Zeroable};
+
+            #[derive(Clone, Copy, Zeroable, Pod)]
+        },
+    }
+}
 mod texture_merger_shader {
     vulkano_shaders::shader! {
         ty: "compute",
@@ -88,18 +139,361 @@ mod texture_merger_shader {
     }
 }
 
-pub const TEXTURE_SIZE: u32 = 1024;
-const WORKGROUP_SIZE: [u32; 3] = [TEXTURE_SIZE / 8, TEXTURE_SIZE / 8, 1];
+// Resolution of the simulation maps, Simulation::new takes any power of two
+pub const DEFAULT_TEXTURE_SIZE: u32 = 1024;
+// Flow map covers the whole water body, it's a smooth field so it can be small
+pub const FLOW_MAP_SIZE: u32 = 256;
+// Side of the square of world meters the flow map spans, centered on the origin
+pub const FLOW_MAP_EXTENT: f32 = 2000.0;
+// OceanParams.lengthScales is a vec4, so at most four cascades
+pub const MAX_CASCADES: usize = 4;
+// Largest first, each cascade picks up the wavelengths the bigger ones are too coarse for
+pub const DEFAULT_CASCADE_LENGTH_SCALES: [f32; 3] = [250.0, 17.0, 5.0];
+// Band edge between two cascades in multiples of the smaller cascade's fundamental
+// wavenumber, waves below that are left to the bigger cascade so no band is counted twice
+const CASCADE_BOUNDARY: f32 = 6.0;
+// Shallowest water set_depth allows, the dispersion relation has omega -> 0 at depth 0
+const MIN_DEPTH: f32 = 0.5;
+// Upper bound for set_disturbances, the source buffer is allocated once at this size
+pub const MAX_DISTURBANCES: usize = 1024;
+// add_ripple bumps: starting radius and how fast it widens (meters, m/s), seconds for the
+// height to fall to 1/e, and the height below which one is dropped (meters)
+const RIPPLE_RADIUS: f32 = 1.5;
+const RIPPLE_SPREAD: f32 = 2.0;
+const RIPPLE_DECAY_TIME: f32 = 1.5;
+const RIPPLE_MIN_STRENGTH: f32 = 0.001;
+// How long spectrum parameters have to stay unchanged before h0 gets regenerated
+const H0_DEBOUNCE: Duration = Duration::from_millis(250);
+// Seconds of simulation time for a wind turn to get 1 - 1/e of the way to its target
+const WIND_TURN_TIME: f32 = 1.5;
+// Radians the wind angle drifts from the one h0 was built with before h0 gets rebuilt
+const WIND_ANGLE_EPSILON: f32 = 0.01;
+// Step the foam integration moves in, independent of the render framerate
+pub const FIXED_TIMESTEP: f32 = 1.0 / 60.0;
+// Most foam steps a run integrates to catch up, anything beyond that gets dropped so a long
+// frame or a scrub forward doesn't pile up merger dispatches
+const MAX_FOAM_STEPS_PER_RUN: u32 = 4;
+
+// Where the gaussian noise for the initial spectrum comes from.
+// Cpu uploads samples from the rng, Gpu generates them in a compute shader
+// which is a lot faster for big textures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoiseSource {
+    Cpu,
+    Gpu,
+}
+
+// A local push on the surface (rain drop, boat hull), added on top of the FFT waves
+// as a gaussian bump. position is in meters on the simulation plane, wraps with the patch.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Zeroable, Pod)]
+pub struct Disturbance {
+    pub position: [f32; 2],
+    pub strength: f32,
+    pub radius: f32,
+}
+
+// A one-off disturbance from add_ripple, widens and fades out by itself
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Ripple {
+    position: [f32; 2],
+    strength: f32,
+    age: f32,
+}
+
+impl Ripple {
+    fn disturbance(&self) -> Disturbance {
+        Disturbance {
+            position: self.position,
+            strength: self.strength * (-self.age / RIPPLE_DECAY_TIME).exp(),
+            radius: RIPPLE_RADIUS + RIPPLE_SPREAD * self.age,
+        }
+    }
+}
+
+// Primary wind system of the JONSWAP spectrum. Defaults are the values the simulation
+// always used, wind_speed in m/s, fetch and depth in meters, angle in radians.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpectrumParams {
+    pub wind_speed: f32,
+    pub fetch: f32,
+    pub angle: f32,
+    pub swell: f32,
+    pub gamma: f32,
+    pub short_waves_fade: f32,
+    pub depth: f32,
+}
+
+impl Default for SpectrumParams {
+    fn default() -> Self {
+        SpectrumParams {
+            wind_speed: 0.5,
+            fetch: 100000.0,
+            angle: (-29.81_f32).to_radians(),
+            swell: 0.198,
+            gamma: 3.3,
+            short_waves_fade: 0.01,
+            depth: 500.0,
+        }
+    }
+}
+
+// Shape of the secondary spectrum, mixed into the same h0 as the primary one. Where it comes
+// from and how strong it is are set with set_crossing_sea, depth is shared with the primary.
+// The defaults are a moderate, fully developed sea (alpha ~0.0081, peak omega ~0.83).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SwellParams {
+    pub wind_speed: f32,
+    pub fetch: f32,
+    pub swell: f32,
+    pub gamma: f32,
+    pub short_waves_fade: f32,
+}
+
+impl Default for SwellParams {
+    fn default() -> Self {
+        SwellParams {
+            wind_speed: 9.0,
+            fetch: 218000.0,
+            swell: 1.0,
+            gamma: 3.3,
+            short_waves_fade: 0.01,
+        }
+    }
+}
+
+// Named sea states for Simulation::apply_preset, from a flat calm (the defaults) to a storm
+// with long steep waves and lasting foam
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SpectrumPreset {
+    #[default]
+    Calm,
+    Breeze,
+    Choppy,
+    Storm,
+}
+
+// How the FFT patch maps onto the world. One world unit is one meter, the patch covers
+// meters_per_patch in both directions and repeats, the water shader samples it with
+// worldUV / meters_per_patch (OceanParams.lengthScales) so both must come from here.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WorldMapping {
+    pub meters_per_patch: f32,
+    pub texture_size: u32,
+}
+
+impl WorldMapping {
+    pub fn meters_per_texel(&self) -> f32 {
+        self.meters_per_patch / self.texture_size as f32
+    }
+
+    pub fn wavelength_to_texels(&self, wavelength: f32) -> f32 {
+        wavelength / self.meters_per_texel()
+    }
+
+    pub fn texels_to_wavelength(&self, texels: f32) -> f32 {
+        texels * self.meters_per_texel()
+    }
+
+    // Nyquist, anything shorter than two texels can't be represented
+    pub fn min_wavelength(&self) -> f32 {
+        self.texels_to_wavelength(2.0)
+    }
+
+    // Longer waves than the patch itself would just repeat
+    pub fn max_wavelength(&self) -> f32 {
+        self.meters_per_patch
+    }
+
+    pub fn world_to_uv(&self, x: f32, z: f32) -> [f32; 2] {
+        [x / self.meters_per_patch, z / self.meters_per_patch]
+    }
+}
+
+// Maps that can be read back to the CPU every frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimMap {
+    Displacement,
+    Derivatives,
+    Turbulence,
+}
+
+// Borrowed view over the maps read back this frame. Each map holds one size * size layer
+// per cascade back to back, texels are row-major.
+pub struct FrameData<'a> {
+    pub time: f32,
+    pub size: u32,
+    pub cascades: u32,
+    pub maps: Vec<(SimMap, &'a [[f32; 4]])>,
+}
+
+impl FrameData<'_> {
+    pub fn map(&self, map: SimMap) -> Option<&[[f32; 4]]> {
+        self.maps
+            .iter()
+            .find(|(m, _)| *m == map)
+            .map(|(_, data)| *data)
+    }
+
+    pub fn cascade(&self, map: SimMap, cascade: u32) -> Option<&[[f32; 4]]> {
+        let texels = (self.size * self.size) as usize;
+        let start = cascade as usize * texels;
+        self.map(map)?.get(start..start + texels)
+    }
+}
+
+// Gets the read back maps after every run, see Simulation::on_frame_data
+pub type FrameDataCallback = Box<dyn FnMut(&FrameData)>;
+
+// Transfer destination for one map, every cascade's layer back to back
+type MapBuffer = Arc<CpuAccessibleBuffer<[[f32; 4]]>>;
+
+struct FrameReadback {
+    buffers: Vec<(SimMap, MapBuffer)>,
+    callback: FrameDataCallback,
+}
+
+// Displacement copied back after every run, sample_displacement reads the CPU side copy
+struct DisplacementQuery {
+    buffer: MapBuffer,
+    texels: Vec<[f32; 4]>,
+}
+
+// Timestamps around each stage of run, all in the one command buffer it submits
+struct GpuTimer {
+    pool: Arc<QueryPool>,
+    // Nanoseconds per tick
+    period: f32,
+    // Bits of the timestamp the queue actually writes, the rest wrap
+    mask: u64,
+}
+
+// Timestamp pairs of a run, pair i is queries 2i and 2i + 1. The h0 regeneration comes first,
+// then time_spec, IFFTs and merger of every cascade (see cascade_timing).
+const INIT_TIMING: u32 = 0;
+const TIMING_PAIRS: u32 = 1 + 3 * MAX_CASCADES as u32;
+
+// First of the three timing pairs of a cascade
+fn cascade_timing(cascade: usize) -> u32 {
+    1 + 3 * cascade as u32
+}
+
+// None when the queue can't write timestamps or the device reports a zero tick length
+fn create_gpu_timer(device: &Arc<Device>, queue: &Arc<Queue>) -> Option<GpuTimer> {
+    let family = queue.queue_family_index() as usize;
+    let valid_bits =
+        device.physical_device().queue_family_properties()[family].timestamp_valid_bits?;
+    let period = device.physical_device().properties().timestamp_period;
+    if period <= 0.0 {
+        return None;
+    }
+    let pool = QueryPool::new(
+        device.clone(),
+        QueryPoolCreateInfo {
+            query_count: 2 * TIMING_PAIRS,
+            ..QueryPoolCreateInfo::query_type(QueryType::Timestamp)
+        },
+    )
+    .ok()?;
+    Some(GpuTimer {
+        pool,
+        period,
+        mask: u64::MAX >> (64 - valid_bits),
+    })
+}
+
+// GPU time of the last run in milliseconds, time_spec and merger summed over the cascades.
+// init is the h0 spectrum regeneration, 0 unless a parameter change got applied that run.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SimTimings {
+    pub init: f32,
+    pub time_spec: f32,
+    // One entry per cascade, all four of its IFFTs together
+    pub ifft: Vec<f32>,
+    pub merger: f32,
+}
+
+// Bilinear lookup into a size x size map read back from the GPU, uv wraps like the Repeat sampler
+fn sample_bilinear(data: &[[f32; 4]], size: u32, u: f32, v: f32) -> [f32; 4] {
+    let x = u.rem_euclid(1.0) * size as f32 - 0.5;
+    let y = v.rem_euclid(1.0) * size as f32 - 0.5;
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+
+    let texel = |tx: f32, ty: f32| {
+        let tx = (tx as i64).rem_euclid(size as i64) as usize;
+        let ty = (ty as i64).rem_euclid(size as i64) as usize;
+        data[ty * size as usize + tx]
+    };
+    let (a, b) = (texel(x0, y0), texel(x0 + 1.0, y0));
+    let (c, d) = (texel(x0, y0 + 1.0), texel(x0 + 1.0, y0 + 1.0));
+
+    let mut out = [0.0; 4];
+    for i in 0..4 {
+        let top = a[i] + (b[i] - a[i]) * fx;
+        let bottom = c[i] + (d[i] - c[i]) * fx;
+        out[i] = top + (bottom - top) * fy;
+    }
+    out
+}
+
+// Sum of all cascades at world x/z, layers are back to back in the order of mappings
+fn sample_cascades(
+    displacement: &[[f32; 4]],
+    mappings: &[WorldMapping],
+    x: f32,
+    z: f32,
+) -> [f32; 4] {
+    let mut out = [0.0; 4];
+    for (i, mapping) in mappings.iter().enumerate() {
+        let texels = (mapping.texture_size * mapping.texture_size) as usize;
+        let layer = &displacement[i * texels..(i + 1) * texels];
+        let [u, v] = mapping.world_to_uv(x, z);
+        let d = sample_bilinear(layer, mapping.texture_size, u, v);
+        for (o, d) in out.iter_mut().zip(d) {
+            *o += d;
+        }
+    }
+    out
+}
+
+// Displacement of the surface point that ends up over world x/z. A texel at p ends up at
+// p + D(p), so find the texel that lands on x/z with a few fixed point iterations.
+fn surface_displacement(
+    displacement: &[[f32; 4]],
+    mappings: &[WorldMapping],
+    x: f32,
+    z: f32,
+) -> [f32; 4] {
+    let (mut px, mut pz) = (x, z);
+    for _ in 0..4 {
+        let d = sample_cascades(displacement, mappings, px, pz);
+        px = x - d[0];
+        pz = z - d[2];
+    }
+    sample_cascades(displacement, mappings, px, pz)
+}
+
+// Water height at world x/z with choppy displacement
+fn surface_height(displacement: &[[f32; 4]], mappings: &[WorldMapping], x: f32, z: f32) -> f32 {
+    surface_displacement(displacement, mappings, x, z)[1]
+}
 
-fn generate_gaussian_noise(size: u32) -> Vec<[f32; 4]> {
-    let mut rng = rand::rng();
+// The same seed always gives the same noise
+fn generate_gaussian_noise(size: u32, seed: u64) -> Vec<[f32; 4]> {
+    let mut rng = StdRng::seed_from_u64(seed);
     let normal = rand_distr::Normal::new(0.0, 1.0).unwrap();
 
     let mut data = Vec::with_capacity((size * size) as usize);
+    // xy drives the primary spectrum, zw the secondary one so the two wind systems stay
+    // uncorrelated
     for _ in 0..(size * size) {
-        let real = normal.sample(&mut rng);
-        let imag = normal.sample(&mut rng);
-        data.push([real, imag, 0.0, 0.0]);
+        data.push([
+            normal.sample(&mut rng),
+            normal.sample(&mut rng),
+            normal.sample(&mut rng),
+            normal.sample(&mut rng),
+        ]);
     }
 
     data
@@ -108,12 +502,13 @@ fn generate_gaussian_noise(size: u32) -> Vec<[f32; 4]> {
 fn create_image(
     allocator: &StandardMemoryAllocator,
     family_idx: u32,
+    size: u32,
 ) -> Arc<ImageView<StorageImage>> {
     let img = StorageImage::new(
         allocator,
         ImageDimensions::Dim2d {
-            width: TEXTURE_SIZE,
-            height: TEXTURE_SIZE,
+            width: size,
+            height: size,
             array_layers: 1,
         },
         Format::R32G32B32A32_SFLOAT,
@@ -123,15 +518,117 @@ fn create_image(
     ImageView::new_default(img).unwrap()
 }
 
-fn create_pipeline(device: Arc<Device>, shader: Arc<ShaderModule>) -> Arc<ComputePipeline> {
-    ComputePipeline::new(
-        device.clone(),
-        shader.entry_point("main").unwrap(),
-        &(),
-        None,
-        |_| {},
+fn create_layered_image(
+    allocator: &StandardMemoryAllocator,
+    family_idx: u32,
+    size: u32,
+    layers: u32,
+) -> Arc<StorageImage> {
+    StorageImage::new(
+        allocator,
+        ImageDimensions::Dim2d {
+            width: size,
+            height: size,
+            array_layers: layers,
+        },
+        Format::R32G32B32A32_SFLOAT,
+        [family_idx],
     )
-    .expect("Failed to create compute pipeline")
+    .unwrap()
+}
+
+// The water shaders sample the cascades as a sampler2DArray, even when there's only one
+fn array_view(image: &Arc<StorageImage>) -> Arc<ImageView<StorageImage>> {
+    let mut create_info = ImageViewCreateInfo::from_image(image);
+    create_info.view_type = ImageViewType::Dim2dArray;
+    ImageView::new(image.clone(), create_info).unwrap()
+}
+
+// Single layer for the compute shaders, which write one cascade at a time
+fn layer_view(image: &Arc<StorageImage>, layer: u32) -> Arc<ImageView<StorageImage>> {
+    let mut create_info = ImageViewCreateInfo::from_image(image);
+    create_info.view_type = ImageViewType::Dim2d;
+    create_info.subresource_range.array_layers = layer..layer + 1;
+    ImageView::new(image.clone(), create_info).unwrap()
+}
+
+fn create_pipeline(
+    device: &Arc<Device>,
+    shader: Result<Arc<ShaderModule>, ShaderCreationError>,
+    name: &str,
+) -> Option<Arc<ComputePipeline>> {
+    let pipeline = shader.map_err(|e| format!("{:?}", e)).and_then(|shader| {
+        ComputePipeline::new(
+            device.clone(),
+            shader.entry_point("main").unwrap(),
+            &(),
+            None,
+            |_| {},
+        )
+        .map_err(|e| format!("{:?}", e))
+    });
+    match pipeline {
+        Ok(pipeline) => Some(pipeline),
+        Err(e) => {
+            warn!("Failed to create the {} compute pipeline: {}", name, e);
+            None
+        }
+    }
+}
+
+// Everything the FFT simulation dispatches
+struct ComputePipelines {
+    init_spec: Arc<ComputePipeline>,
+    conj_spec: Arc<ComputePipeline>,
+    time_spec: Arc<ComputePipeline>,
+    fft_init: Arc<ComputePipeline>,
+    fft: Arc<ComputePipeline>,
+    texture_merger: Arc<ComputePipeline>,
+    noise_gen: Arc<ComputePipeline>,
+    disturbance: Arc<ComputePipeline>,
+    sanity_check: Arc<ComputePipeline>,
+}
+
+// Some older integrated GPUs fail to build these, or the graphics queue has no compute
+// support at all. All or nothing, a partial set can't run the simulation.
+fn create_compute_pipelines(device: &Arc<Device>, queue: &Arc<Queue>) -> Option<ComputePipelines> {
+    let family = queue.queue_family_index() as usize;
+    if !device.physical_device().queue_family_properties()[family]
+        .queue_flags
+        .compute
+    {
+        warn!("The graphics queue doesn't support compute");
+        return None;
+    }
+
+    Some(ComputePipelines {
+        init_spec: create_pipeline(device, init_spec_shader::load(device.clone()), "init")?,
+        conj_spec: create_pipeline(device, conj_spec_shader::load(device.clone()), "conj")?,
+        time_spec: create_pipeline(device, time_spec_shader::load(device.clone()), "time")?,
+        fft_init: create_pipeline(device, fft_init_shader::load(device.clone()), "fft init")?,
+        fft: create_pipeline(device, fft_shader::load(device.clone()), "fft")?,
+        texture_merger: create_pipeline(
+            device,
+            texture_merger_shader::load(device.clone()),
+            "texture merger",
+        )?,
+        noise_gen: create_pipeline(device, noise_gen_shader::load(device.clone()), "noise")?,
+        disturbance: create_pipeline(
+            device,
+            disturbance_shader::load(device.clone()),
+            "disturbance",
+        )?,
+        sanity_check: create_pipeline(
+            device,
+            sanity_check_shader::load(device.clone()),
+            "sanity check",
+        )?,
+    })
+}
+
+// Into -PI..PI, the short way round between two angles
+fn wrap_angle(angle: f32) -> f32 {
+    (angle + PI).rem_euclid(TAU) - PI
 }
 
 fn calculate_spectrum_params(wind_speed: f32, fetch: f32, g: f32) -> (f32, f32) {
@@ -140,17 +637,42 @@ fn calculate_spectrum_params(wind_speed: f32, fetch: f32, g: f32) -> (f32, f32)
     (alpha, peak_omega)
 }
 
+// One frequency band of the ocean. Every cascade runs the whole spectrum -> IFFT -> merge
+// chain on its own patch size, outputs are its layer of the shared map arrays and the water
+// shaders sum all layers. Patch sizes that don't divide each other keep the tiling from lining up.
+struct Cascade {
+    length_scale: f32,
+    // None follows foam_threshold
+    foam_threshold: Option<f32>,
+    spec_h0: Arc<ImageView<StorageImage>>,
+    waves_data: Arc<ImageView<StorageImage>>,
+    displacement: Arc<ImageView<StorageImage>>,
+    derivatives: Arc<ImageView<StorageImage>>,
+    turbulence: Arc<ImageView<StorageImage>>,
+}
+
+// Sets of the spectrum passes of one cascade. Nothing they bind ever gets replaced, so they're
+// built once in new instead of on every dispatch.
+struct CascadeSets {
+    init_spec: Arc<PersistentDescriptorSet>,
+    conj_spec: Arc<PersistentDescriptorSet>,
+    time_spec: Arc<PersistentDescriptorSet>,
+}
+
 pub struct Simulation {
     pub noise_image: Arc<ImageView<StorageImage>>,
     pub spec_hk: Arc<ImageView<StorageImage>>,
-    pub spec_h0: Arc<ImageView<StorageImage>>,
-    pub waves_data: Arc<ImageView<StorageImage>>,
 
+    // Array views, one layer per cascade
     pub displacement_map: Arc<ImageView<StorageImage>>,
+    // x = dDy/dx, y = dDy/dz, z = dDx/dx, w = dDz/dz, D being the displacement. The normal is
+    // (-x / (1 + z), 1, -y / (1 + w)) normalized, after summing the layers (surfaceSlope in
+    // water.frag). Y-up simulation space like the displacement.
     pub derivatives_map: Arc<ImageView<StorageImage>>,
     pub turbulence_map: Arc<ImageView<StorageImage>>,
-    pub camera_depth_map: Arc<ImageView<StorageImage>>,
     pub foam_map: Arc<ImageView<StorageImage>>,
+    // xy = flow velocity in m/s along world x/z, advects the map sampling (rivers etc.)
+    pub flow_map: Arc<ImageView<StorageImage>>,
 
     precomputed_data: Arc<ImageView<StorageImage>>,
     buffer: Arc<ImageView<StorageImage>>,
@@ -159,80 +681,146 @@ pub struct Simulation {
     dyx_dyz: Arc<ImageView<StorageImage>>,
     dxx_dzz: Arc<ImageView<StorageImage>>,
 
-    fft_init_pipeline: Arc<ComputePipeline>,
-    fft_pipeline: Arc<ComputePipeline>,
+    cascades: Vec<Cascade>,
+    // Same order as cascades, empty without compute pipelines
+    cascade_sets: Vec<CascadeSets>,
+    // The first this many cascades get run, see set_active_cascades
+    active_cascades: usize,
+
+    // None when the device can't run them, the maps then stay flat (see has_compute)
+    pipelines: Option<ComputePipelines>,
+    disturbance_buffer: Arc<CpuAccessibleBuffer<[Disturbance]>>,
+    disturbance_count: u32,
+    // Go into the disturbance buffer after the set_disturbances ones, oldest first
+    ripples: Vec<Ripple>,
+    // Some while output validation is on, the pass writes its flag in here
+    sanity_buffer: Option<Arc<CpuAccessibleBuffer<u32>>>,
+    output_sane: bool,
+    noise_source: NoiseSource,
+    noise_seed: u64,
+    // Set by set_noise_seed, the next run refills noise_image and regenerates h0
+    noise_dirty: bool,
+    frame_readback: Option<FrameReadback>,
+    displacement_query: Option<DisplacementQuery>,
+    gpu_timer: Option<GpuTimer>,
+    last_timings: Option<SimTimings>,
+    memory_allocator: Arc<StandardMemoryAllocator>,
+    // Own allocator and queue so one-off readbacks don't need the renderer's
+    readback_allocator: StandardCommandBufferAllocator,
+    // Only for cascade_sets, they live as long as the simulation
+    descriptor_set_allocator: StandardDescriptorSetAllocator,
+    queue: Arc<Queue>,
+    sampler: Arc<Sampler>,
 
-    init_spec_pipeline: Arc<ComputePipeline>,
-    conj_spec_pipeline: Arc<ComputePipeline>,
-    time_spec_pipeline: Arc<ComputePipeline>,
-    texture_merger_pipeline: Arc<ComputePipeline>,
+    texture_size: u32,
+    workgroup_size: [u32; 3],
+    spectrum_params: SpectrumParams,
+    max_displacement: f32,
+    choppiness: f32,
+    foam_decay: f32,
+    foam_threshold: f32,
+    foam_injection: f32,
+    // Time of the previous run, the wind turn, ripples and foam steps go by the difference
+    last_run_time: f32,
+    phase_offset: f32,
+    crest_stretch: f32,
+    crossing_angle: f32,
+    secondary_strength: f32,
+    secondary_swell: SwellParams,
+    h0_changed_at: Option<Instant>,
+    // Where turn_wind_to is taking spectrum_params.angle, and the angle h0 was last built with
+    target_wind_angle: f32,
+    h0_wind_angle: f32,
+    time_scale: f32,
+    paused: bool,
+    // Time since last_run_time the foam hasn't stepped through yet, below FIXED_TIMESTEP
+    // after a run
+    foam_accumulator: f32,
     pub time: f32,
 }
 
 impl Simulation {
     pub fn new(
-        allocator: &StandardMemoryAllocator,
-        queue: &Arc<Queue>,
-        command_buffer_allocator: &StandardCommandBufferAllocator,
-        device: &Arc<Device>,
+        context: &GpuContext,
+        sampler: &Arc<Sampler>,
+        noise_source: NoiseSource,
+        // Some makes the noise, and with it the whole ocean, the same on every run
+        noise_seed: Option<u64>,
+        length_scales: &[f32],
+        texture_size: u32,
     ) -> Self {
-        let noise_image = Self::generate_noise_texture(allocator, queue, command_buffer_allocator);
-        let waves_data = create_image(allocator, queue.queue_family_index());
-        let spec_hk = create_image(allocator, queue.queue_family_index());
-        let spec_h0 = create_image(allocator, queue.queue_family_index());
-
-        let displacement_map = create_image(allocator, queue.queue_family_index());
-        let derivatives_map = create_image(allocator, queue.queue_family_index());
-        let turbulence_map = create_image(allocator, queue.queue_family_index());
-        let camera_depth_map = create_image(allocator, queue.queue_family_index());
-        let foam_map = create_image(allocator, queue.queue_family_index());
-
-        let precomputed_data = create_image(allocator, queue.queue_family_index());
-        let buffer = create_image(allocator, queue.queue_family_index());
-        let dx_dz = create_image(allocator, queue.queue_family_index());
-        let dy_dxz = create_image(allocator, queue.queue_family_index());
-        let dyx_dyz = create_image(allocator, queue.queue_family_index());
-        let dxx_dzz = create_image(allocator, queue.queue_family_index());
-
-        let init_spec_pipeline = create_pipeline(
-            device.clone(),
-            init_spec_shader::load(device.clone()).expect("Failed to load init compute shader"),
-        );
-        let conj_spec_pipeline = create_pipeline(
-            device.clone(),
-            conj_spec_shader::load(device.clone()).expect("Failed to load conj compute shader"),
+        // The FFT runs log2(size) butterfly passes and every dispatch covers the maps in
+        // 8x8 workgroups, anything else would leave texels out
+        assert!(
+            texture_size.is_power_of_two() && texture_size >= 8,
+            "Simulation texture size must be a power of two and at least 8, got {}",
+            texture_size
         );
-        let time_spec_pipeline = create_pipeline(
-            device.clone(),
-            time_spec_shader::load(device.clone()).expect("Failed to load time compute shader"),
+        assert!(
+            (1..=MAX_CASCADES).contains(&length_scales.len()),
+            "Need between 1 and MAX_CASCADES cascades"
         );
+        let layers = length_scales.len() as u32;
+        let allocator = &context.memory_allocator;
+        let queue = &context.queue;
+        let device = &context.device;
 
-        let fft_init_pipeline = create_pipeline(
-            device.clone(),
-            fft_init_shader::load(device.clone()).expect("Failed to load fft compute shader"),
-        );
-        let fft_pipeline = create_pipeline(
-            device.clone(),
-            fft_shader::load(device.clone()).expect("Failed to load fft compute shader"),
-        );
+        // Filled in by init, uploaded or generated by the noise compute shader
+        let noise_image = Self::create_upload_image(allocator, queue, texture_size);
+        // Always a concrete seed, so share codes can reproduce an unseeded ocean too
+        let noise_seed = noise_seed.unwrap_or_else(rand::random);
+        let family = queue.queue_family_index();
+        let spec_hk = create_image(allocator, family, texture_size);
 
-        let texture_merger_pipeline = create_pipeline(
-            device.clone(),
-            texture_merger_shader::load(device.clone())
-                .expect("Failed to load texture merger compute shader"),
-        );
+        let displacement_image = create_layered_image(allocator, family, texture_size, layers);
+        let derivatives_image = create_layered_image(allocator, family, texture_size, layers);
+        let turbulence_image = create_layered_image(allocator, family, texture_size, layers);
+        let cascades: Vec<_> = length_scales
+            .iter()
+            .enumerate()
+            .map(|(i, &length_scale)| Cascade {
+                length_scale,
+                foam_threshold: None,
+                spec_h0: create_image(allocator, family, texture_size),
+                waves_data: create_image(allocator, family, texture_size),
+                displacement: layer_view(&displacement_image, i as u32),
+                derivatives: layer_view(&derivatives_image, i as u32),
+                turbulence: layer_view(&turbulence_image, i as u32),
+            })
+            .collect();
+        let foam_map = create_image(allocator, family, texture_size);
+        let flow_map =
+            ImageView::new_default(Self::create_upload_image(allocator, queue, FLOW_MAP_SIZE))
+                .unwrap();
+
+        let precomputed_data = create_image(allocator, family, texture_size);
+        let buffer = create_image(allocator, family, texture_size);
+        let dx_dz = create_image(allocator, family, texture_size);
+        let dy_dxz = create_image(allocator, family, texture_size);
+        let dyx_dyz = create_image(allocator, family, texture_size);
+        let dxx_dzz = create_image(allocator, family, texture_size);
 
-        Simulation {
+        let pipelines = create_compute_pipelines(device, queue);
+        let disturbance_buffer = CpuAccessibleBuffer::from_iter(
+            allocator,
+            BufferUsage {
+                storage_buffer: true,
+                ..BufferUsage::empty()
+            },
+            false,
+            std::iter::repeat_n(Disturbance::default(), MAX_DISTURBANCES),
+        )
+        .unwrap();
+
+        let mut simulation = Simulation {
             noise_image: ImageView::new_default(noise_image).unwrap(),
-            waves_data,
             spec_hk,
-            spec_h0,
 
-            displacement_map,
-            derivatives_map,
-            turbulence_map,
-            camera_depth_map,
+            displacement_map: array_view(&displacement_image),
+            derivatives_map: array_view(&derivatives_image),
+            turbulence_map: array_view(&turbulence_image),
             foam_map,
+            flow_map,
 
             precomputed_data,
             buffer,
@@ -241,18 +829,447 @@ impl Simulation {
             dyx_dyz,
             dxx_dzz,
 
-            fft_init_pipeline,
-            fft_pipeline,
+            active_cascades: cascades.len(),
+            cascades,
+            cascade_sets: Vec::new(),
 
-            init_spec_pipeline,
-            conj_spec_pipeline,
-            time_spec_pipeline,
-            texture_merger_pipeline,
+            pipelines,
+            disturbance_buffer,
+            disturbance_count: 0,
+            ripples: Vec::new(),
+            sanity_buffer: None,
+            output_sane: true,
+            noise_source,
+            noise_seed,
+            noise_dirty: false,
+            frame_readback: None,
+            displacement_query: None,
+            gpu_timer: create_gpu_timer(device, queue),
+            last_timings: None,
+            memory_allocator: allocator.clone(),
+            readback_allocator: StandardCommandBufferAllocator::new(
+                device.clone(),
+                Default::default(),
+            ),
+            descriptor_set_allocator: StandardDescriptorSetAllocator::new(device.clone()),
+            queue: queue.clone(),
+            sampler: sampler.clone(),
+
+            texture_size,
+            workgroup_size: [texture_size / 8, texture_size / 8, 1],
+            spectrum_params: SpectrumParams::default(),
+            max_displacement: 0.0,
+            choppiness: 1.0,
+            foam_decay: 1.0,
+            foam_threshold: 0.0,
+            foam_injection: 0.0,
+            last_run_time: 0.0,
+            phase_offset: 0.0,
+            crest_stretch: 1.0,
+            crossing_angle: 0.0,
+            secondary_strength: 0.0,
+            secondary_swell: SwellParams::default(),
+            h0_changed_at: None,
+            target_wind_angle: SpectrumParams::default().angle,
+            h0_wind_angle: SpectrumParams::default().angle,
+            time_scale: 1.0,
+            paused: false,
+            foam_accumulator: 0.0,
 
             time: 0.0,
+        };
+
+        simulation.cascade_sets = simulation.create_cascade_sets();
+        // No flow until someone sets a map
+        let still = vec![[0.0; 2]; (FLOW_MAP_SIZE * FLOW_MAP_SIZE) as usize];
+        simulation.set_flow_map(&still);
+        simulation
+    }
+
+    // Has to be called again if any image bound in here gets replaced
+    fn create_cascade_sets(&self) -> Vec<CascadeSets> {
+        let Some(pipelines) = &self.pipelines else {
+            return Vec::new();
+        };
+        let set = |pipeline: &Arc<ComputePipeline>, bindings| {
+            PersistentDescriptorSet::new(
+                &self.descriptor_set_allocator,
+                pipeline.layout().set_layouts()[0].clone(),
+                bindings,
+            )
+            .unwrap()
+        };
+
+        self.cascades
+            .iter()
+            .map(|cascade| CascadeSets {
+                init_spec: set(
+                    &pipelines.init_spec,
+                    vec![
+                        WriteDescriptorSet::image_view(0, cascade.waves_data.clone()),
+                        WriteDescriptorSet::image_view(1, self.spec_hk.clone()),
+                        WriteDescriptorSet::image_view_sampler(
+                            2,
+                            self.noise_image.clone(),
+                            self.sampler.clone(),
+                        ),
+                    ],
+                ),
+                conj_spec: set(
+                    &pipelines.conj_spec,
+                    vec![
+                        WriteDescriptorSet::image_view(0, self.spec_hk.clone()),
+                        WriteDescriptorSet::image_view(1, cascade.spec_h0.clone()),
+                    ],
+                ),
+                time_spec: set(
+                    &pipelines.time_spec,
+                    vec![
+                        WriteDescriptorSet::image_view(0, cascade.waves_data.clone()),
+                        WriteDescriptorSet::image_view(1, cascade.spec_h0.clone()),
+                        // Displacement
+                        WriteDescriptorSet::image_view(2, self.dx_dz.clone()),
+                        WriteDescriptorSet::image_view(3, self.dy_dxz.clone()),
+                        WriteDescriptorSet::image_view(4, self.dyx_dyz.clone()),
+                        WriteDescriptorSet::image_view(5, self.dxx_dzz.clone()),
+                    ],
+                ),
+            })
+            .collect()
+    }
+
+    // Replaces the active disturbances, they stay applied every run until replaced again
+    // (pass an empty slice to clear). All of them go through a single dispatch, the count
+    // is a push constant so only the used part of the buffer gets read.
+    pub fn set_disturbances(&mut self, disturbances: &[Disturbance]) {
+        let count = disturbances.len().min(MAX_DISTURBANCES);
+        if count < disturbances.len() {
+            warn!(
+                "Too many disturbances ({}), only the first {} are used",
+                disturbances.len(),
+                MAX_DISTURBANCES
+            );
+        }
+
+        // run waits for the GPU, so nothing is reading the buffer between runs
+        let mut buffer = self.disturbance_buffer.write().unwrap();
+        buffer[..count].copy_from_slice(&disturbances[..count]);
+        self.disturbance_count = count as u32;
+    }
+
+    // Gaussian bump of strength meters (negative pushes the surface down) at x/z meters on
+    // the simulation plane, like Disturbance::position. Unlike set_disturbances it goes away
+    // by itself: every run it gets wider and lower until it's too small to see.
+    pub fn add_ripple(&mut self, world_x: f32, world_z: f32, strength: f32) {
+        self.ripples.push(Ripple {
+            position: [world_x, world_z],
+            strength,
+            age: 0.0,
+        });
+    }
+
+    // Ages the ripples by dlt, drops the faded ones and writes the rest after the
+    // set_disturbances ones. Returns how many disturbances the dispatch has to read.
+    fn update_ripples(&mut self, dlt: f32) -> u32 {
+        for ripple in &mut self.ripples {
+            ripple.age += dlt;
+        }
+        self.ripples
+            .retain(|ripple| ripple.disturbance().strength.abs() >= RIPPLE_MIN_STRENGTH);
+
+        // Whatever doesn't fit is the oldest, and the closest to gone anyway
+        let start = self.disturbance_count as usize;
+        let room = MAX_DISTURBANCES - start;
+        if self.ripples.len() > room {
+            let excess = self.ripples.len() - room;
+            self.ripples.drain(..excess);
+        }
+        let mut buffer = self.disturbance_buffer.write().unwrap();
+        for (slot, ripple) in buffer[start..].iter_mut().zip(&self.ripples) {
+            *slot = ripple.disturbance();
+        }
+        (start + self.ripples.len()) as u32
+    }
+
+    // Row-major FLOW_MAP_SIZE^2 field of flow velocities (m/s, world x/z) over the
+    // FLOW_MAP_EXTENT square of the water body, the edge texels carry on beyond it. The water
+    // shader scrolls its map lookups along the local flow, zero means open ocean.
+    pub fn set_flow_map(&mut self, data: &[[f32; 2]]) {
+        assert_eq!(
+            data.len(),
+            (FLOW_MAP_SIZE * FLOW_MAP_SIZE) as usize,
+            "Flow map must be FLOW_MAP_SIZE x FLOW_MAP_SIZE"
+        );
+
+        let staging_buffer = CpuAccessibleBuffer::from_iter(
+            &self.memory_allocator,
+            BufferUsage {
+                transfer_src: true,
+                ..BufferUsage::empty()
+            },
+            false,
+            data.iter().map(|flow| [flow[0], flow[1], 0.0, 0.0]),
+        )
+        .unwrap();
+
+        let mut builder = AutoCommandBufferBuilder::primary(
+            &self.readback_allocator,
+            self.queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+        builder
+            .copy_buffer_to_image(CopyBufferToImageInfo::buffer_image(
+                staging_buffer,
+                self.flow_map.image().clone(),
+            ))
+            .unwrap();
+        builder
+            .build()
+            .unwrap()
+            .execute(self.queue.clone())
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+    }
+
+    // Debug pass that scans the displacement map for NaN/Inf after every run. Costs one extra
+    // dispatch per frame, meant for tuning parameters live, see check_output_sane.
+    pub fn set_output_validation(&mut self, enabled: bool) {
+        self.sanity_buffer = enabled.then(|| {
+            CpuAccessibleBuffer::from_data(
+                &self.memory_allocator,
+                BufferUsage {
+                    storage_buffer: true,
+                    ..BufferUsage::empty()
+                },
+                false,
+                0u32,
+            )
+            .unwrap()
+        });
+        self.output_sane = true;
+    }
+
+    // False when the last validated run produced NaN/Inf displacement, which shows up as a
+    // black or frozen surface (zero wind speed, bad cutoffs, ...). Always true with
+    // validation off.
+    pub fn check_output_sane(&self) -> bool {
+        self.output_sane
+    }
+
+    // Registers a callback that gets the selected maps copied back to the CPU after every run.
+    // All copies are recorded into the merger submission, so there is one extra transfer per
+    // map per frame (texture_size^2 * 16 bytes per cascade), only pick the maps you need.
+    // Since run waits for the GPU the data belongs to the frame that was just simulated, once
+    // the submission stops blocking it will lag one frame behind.
+    pub fn on_frame_data(&mut self, maps: &[SimMap], callback: FrameDataCallback) {
+        let buffers = maps
+            .iter()
+            .map(|&map| {
+                let buffer = CpuAccessibleBuffer::from_iter(
+                    &self.memory_allocator,
+                    BufferUsage {
+                        transfer_dst: true,
+                        ..BufferUsage::empty()
+                    },
+                    false,
+                    (0..self.texels_per_map()).map(|_| [0.0f32; 4]),
+                )
+                .unwrap();
+                (map, buffer)
+            })
+            .collect();
+
+        self.frame_readback = Some(FrameReadback { buffers, callback });
+    }
+
+    pub fn clear_frame_data(&mut self) {
+        self.frame_readback = None;
+    }
+
+    // Keeps a CPU copy of the displacement for sample_displacement, downloaded once per run.
+    // That's texture_size^2 * 16 bytes per cascade every step, so it's off by default.
+    pub fn set_displacement_queries(&mut self, enabled: bool) {
+        if !enabled {
+            self.displacement_query = None;
+            return;
         }
+        if self.displacement_query.is_some() {
+            return;
+        }
+
+        let texels = self.texels_per_map();
+        let buffer = CpuAccessibleBuffer::from_iter(
+            &self.memory_allocator,
+            BufferUsage {
+                transfer_dst: true,
+                ..BufferUsage::empty()
+            },
+            false,
+            (0..texels).map(|_| [0.0f32; 4]),
+        )
+        .unwrap();
+        self.displacement_query = Some(DisplacementQuery {
+            buffer,
+            texels: vec![[0.0; 4]; texels as usize],
+        });
+    }
+
+    // Displacement [x, y, z] of the surface point that sits over world x/z (simulation space),
+    // y being the water height there. Includes every cascade, and the horizontal displacement
+    // is taken into account. Patches wrap, so any x/z works. Comes from the copy of the last
+    // run, all zero until set_displacement_queries is on and a run has happened.
+    pub fn sample_displacement(&self, x: f32, z: f32) -> [f32; 3] {
+        let Some(query) = &self.displacement_query else {
+            return [0.0; 3];
+        };
+        let d = surface_displacement(&query.texels, &self.cascade_mappings(), x, z);
+        [d[0], d[1], d[2]]
+    }
+
+    // Blocking copy of one map to the CPU, texels row-major, one layer per cascade
+    pub fn read_map(&self, map: SimMap) -> Vec<[f32; 4]> {
+        let buffer = CpuAccessibleBuffer::from_iter(
+            &self.memory_allocator,
+            BufferUsage {
+                transfer_dst: true,
+                ..BufferUsage::empty()
+            },
+            false,
+            (0..self.texels_per_map()).map(|_| [0.0f32; 4]),
+        )
+        .unwrap();
+
+        let mut cmd = AutoCommandBufferBuilder::primary(
+            &self.readback_allocator,
+            self.queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+        cmd.copy_image_to_buffer(CopyImageToBufferInfo::image_buffer(
+            self.sim_map(map).image().clone(),
+            buffer.clone(),
+        ))
+        .unwrap();
+        cmd.build()
+            .unwrap()
+            .execute(self.queue.clone())
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+
+        buffer.read().unwrap().to_vec()
     }
 
+    fn texels_per_map(&self) -> u32 {
+        self.texture_size * self.texture_size * self.cascades.len() as u32
+    }
+
+    // Writes "distance,height" rows for evenly spaced samples along a world space XZ line.
+    // Height is the vertical displacement at each point, horizontal displacement is ignored
+    // so this is the heightfield the FFT produced, good for checking wavelengths/amplitudes.
+    pub fn dump_profile(
+        &self,
+        line: (Vec2, Vec2),
+        samples: usize,
+        path: impl AsRef<Path>,
+    ) -> io::Result<()> {
+        let displacement = self.read_map(SimMap::Displacement);
+        let mappings = self.cascade_mappings();
+        let (start, end) = line;
+        let length = (end - start).norm();
+
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "distance,height")?;
+        for i in 0..samples {
+            let t = if samples > 1 {
+                i as f32 / (samples - 1) as f32
+            } else {
+                0.0
+            };
+            let point = start + (end - start) * t;
+            let height = sample_cascades(&displacement, &mappings, point.x, point.y)[1];
+            writeln!(out, "{},{}", t * length, height)?;
+        }
+        out.flush()
+    }
+
+    // Distance along the ray to the first hit with the displaced surface, both in simulation
+    // space (Y-up). Reads the displacement back from the GPU so don't call it every frame
+    // for many rays.
+    pub fn nearest_crest(&self, from: Vec3, dir: Vec3) -> Option<f32> {
+        const MAX_DISTANCE: f32 = 1000.0;
+        const MIN_STEP: f32 = 0.05;
+
+        let dir = dir.try_normalize(f32::EPSILON)?;
+        let displacement = self.read_map(SimMap::Displacement);
+        let mappings = self.cascade_mappings();
+        let height_above = |t: f32| {
+            let p = from + dir * t;
+            p.y - surface_height(&displacement, &mappings, p.x, p.z)
+        };
+
+        let mut prev_t = 0.0;
+        let mut prev_h = height_above(0.0);
+        if prev_h <= 0.0 {
+            return Some(0.0);
+        }
+
+        let mut t = 0.0;
+        while t < MAX_DISTANCE {
+            // Steps shrink as the ray gets closer to the surface, half the vertical gap
+            // keeps it from jumping through steep crests
+            t += (prev_h * 0.5).max(MIN_STEP);
+            let h = height_above(t);
+            if h <= 0.0 {
+                // Crossed the surface, bisect down to the actual hit
+                let (mut lo, mut hi) = (prev_t, t);
+                for _ in 0..16 {
+                    let mid = 0.5 * (lo + hi);
+                    if height_above(mid) > 0.0 {
+                        lo = mid;
+                    } else {
+                        hi = mid;
+                    }
+                }
+                return Some(0.5 * (lo + hi));
+            }
+            prev_t = t;
+            prev_h = h;
+        }
+
+        None
+    }
+
+    fn sim_map(&self, map: SimMap) -> &Arc<ImageView<StorageImage>> {
+        match map {
+            SimMap::Displacement => &self.displacement_map,
+            SimMap::Derivatives => &self.derivatives_map,
+            SimMap::Turbulence => &self.turbulence_map,
+        }
+    }
+
+    // Every image goes through the descriptor set bound here, so the AutoCommandBufferBuilder
+    // knows which images a dispatch reads and writes. When two dispatches in the same command
+    // buffer touch the same image with at least one write it inserts the compute -> compute
+    // pipeline barrier and the layout transition itself. vulkano 0.32 doesn't expose manual
+    // barriers on the auto builder, so chaining dispatches is safe as long as images are only
+    // ever bound this way (or used by the builder's own copy commands) and never written
+    // behind its back. The barriers a run ends up with:
+    // - init_spec -> conj_spec on spec_hk, and the next cascade's init_spec waits for the
+    //   conj_spec before it to stop reading it
+    // - conj_spec -> time_spec on spec_h0, only in runs that regenerate h0
+    // - time_spec -> the first FFT pass on the four IFFT inputs
+    // - each FFT pass -> the next on input/buffer (ping-pong), then the copy back, permute and
+    //   scale after the last one. The four IFFTs share buffer, so each waits for the previous.
+    // - the IFFTs -> merger on the four inputs, merger -> the next cascade's time_spec, which
+    //   overwrites what the merger read
+    // - merger -> disturbance, sanity check and readback copies on the map arrays
     pub fn run_compute_shader(
         &self,
         command_buffer: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
@@ -261,162 +1278,692 @@ impl Simulation {
         bindings: Vec<WriteDescriptorSet>,
         push_constants: impl BufferContents,
     ) {
-        let pipeline_layout = pipeline.layout();
-        let descriptor_set_layout = pipeline_layout.set_layouts().get(0).unwrap();
+        let descriptor_set_layout = pipeline.layout().set_layouts().first().unwrap();
         let descriptor_set = PersistentDescriptorSet::new(
             descriptor_set_allocator,
             descriptor_set_layout.clone(),
             bindings,
         )
         .expect("Failed to create descriptor set");
+        self.dispatch_with_set(command_buffer, pipeline, descriptor_set, push_constants);
+    }
+
+    // run_compute_shader with a set that was built ahead of time
+    fn dispatch_with_set(
+        &self,
+        command_buffer: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        pipeline: Arc<ComputePipeline>,
+        descriptor_set: Arc<PersistentDescriptorSet>,
+        push_constants: impl BufferContents,
+    ) {
+        let pipeline_layout = pipeline.layout();
+        command_buffer
+            .bind_pipeline_compute(pipeline.clone())
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                pipeline_layout.clone(),
+                0,
+                descriptor_set,
+            )
+            .push_constants(pipeline_layout.clone(), 0, push_constants)
+            .dispatch(self.workgroup_size)
+            .expect("Failed to dispatch compute shader");
+    }
+
+    // First thing in the run command buffer, the queries can't be written before a reset
+    fn reset_timing(
+        &self,
+        command_buffer: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+    ) {
+        if let Some(timer) = &self.gpu_timer {
+            // The previous run was waited on, nothing uses the queries anymore
+            unsafe {
+                command_buffer
+                    .reset_query_pool(timer.pool.clone(), 0..2 * TIMING_PAIRS)
+                    .unwrap();
+            }
+        }
+    }
+
+    // Both ends are BottomOfPipe, the start gets written once everything recorded before it
+    // has finished
+    fn begin_timing(
+        &self,
+        command_buffer: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        pair: u32,
+    ) {
+        if let Some(timer) = &self.gpu_timer {
+            unsafe {
+                command_buffer
+                    .write_timestamp(timer.pool.clone(), 2 * pair, PipelineStage::BottomOfPipe)
+                    .unwrap();
+            }
+        }
+    }
+
+    fn end_timing(
+        &self,
+        command_buffer: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        pair: u32,
+    ) {
+        if let Some(timer) = &self.gpu_timer {
+            unsafe {
+                command_buffer
+                    .write_timestamp(
+                        timer.pool.clone(),
+                        2 * pair + 1,
+                        PipelineStage::BottomOfPipe,
+                    )
+                    .unwrap();
+            }
+        }
+    }
+
+    // Milliseconds between the two timestamps of pair. Both have to have been written by a
+    // command buffer that finished, the read waits for them otherwise. 0 without a timer.
+    fn timed_ms(&self, pair: u32) -> f32 {
+        let Some(timer) = &self.gpu_timer else {
+            return 0.0;
+        };
+        let mut ticks = [0u64; 2];
+        timer
+            .pool
+            .queries_range(2 * pair..2 * pair + 2)
+            .unwrap()
+            .get_results(
+                &mut ticks,
+                QueryResultFlags {
+                    wait: true,
+                    ..QueryResultFlags::empty()
+                },
+            )
+            .unwrap();
+        (ticks[1].wrapping_sub(ticks[0]) & timer.mask) as f32 * timer.period / 1_000_000.0
+    }
+
+    // Per stage GPU time of the last run. None before the first run, or when the device
+    // can't time the simulation (no timestamp support on the queue, zero timestamp period).
+    pub fn last_timings(&self) -> Option<SimTimings> {
+        self.last_timings.clone()
+    }
+
+    // False when the compute pipelines couldn't be created. init then only clears the maps
+    // to a flat sea and run does nothing, the renderer moves the surface itself.
+    pub fn has_compute(&self) -> bool {
+        self.pipelines.is_some()
+    }
+
+    fn pipelines(&self) -> &ComputePipelines {
+        self.pipelines
+            .as_ref()
+            .expect("Simulation has no compute pipelines")
+    }
+
+    // Displacement and derivatives to 0, turbulence to 1 (no foam), transfer only
+    fn clear_maps(&self, cmd_alloc: &StandardCommandBufferAllocator, queue: Arc<Queue>) {
+        let mut cmd = AutoCommandBufferBuilder::primary(
+            cmd_alloc,
+            queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+        for (map, value) in [
+            (&self.displacement_map, 0.0),
+            (&self.derivatives_map, 0.0),
+            (&self.turbulence_map, 1.0),
+        ] {
+            cmd.clear_color_image(ClearColorImageInfo {
+                clear_value: ClearColorValue::Float([value; 4]),
+                ..ClearColorImageInfo::image(map.image().clone())
+            })
+            .unwrap();
+        }
+        cmd.build()
+            .unwrap()
+            .execute(queue)
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+    }
+
+    pub fn init(
+        &self,
+        cmd_alloc: &StandardCommandBufferAllocator,
+        descriptor_set_allocator: &StandardDescriptorSetAllocator,
+        queue: Arc<Queue>,
+    ) {
+        if self.pipelines.is_none() {
+            self.clear_maps(cmd_alloc, queue);
+            return;
+        }
+
+        let mut cmd0 = AutoCommandBufferBuilder::primary(
+            cmd_alloc,
+            queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+
+        self.record_noise(&mut cmd0, descriptor_set_allocator);
+        self.run_compute_shader(
+            &mut cmd0,
+            descriptor_set_allocator,
+            self.pipelines().fft_init.clone(),
+            vec![WriteDescriptorSet::image_view(
+                0,
+                self.precomputed_data.clone(),
+            )],
+            fft_init_shader::ty::PushConstants {
+                size: self.texture_size,
+            },
+        );
+        self.generate_h0_spectrum(&mut cmd0);
+        cmd0.build()
+            .unwrap()
+            .execute(queue)
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+    }
+
+    // init plus one run, so the maps hold a real frame before anything gets drawn.
+    // h0 is generated from the current parameters, pending debounced changes are included.
+    pub fn warmup(
+        &mut self,
+        cmd_alloc: &StandardCommandBufferAllocator,
+        descriptor_set_allocator: &StandardDescriptorSetAllocator,
+        queue: Arc<Queue>,
+    ) {
+        self.init(cmd_alloc, descriptor_set_allocator, queue.clone());
+        self.h0_changed_at = None;
+        self.run(cmd_alloc, descriptor_set_allocator, queue);
+    }
+
+    // Crossing angle is relative to the primary wind direction, strength is the scale of the
+    // secondary spectrum (0 turns it off). The h0 spectrum is only regenerated once the values
+    // stop changing for H0_DEBOUNCE, so dragging a slider doesn't recompute it every frame.
+    pub fn set_crossing_sea(&mut self, crossing_angle: f32, secondary_strength: f32) {
+        self.crossing_angle = crossing_angle;
+        self.secondary_strength = secondary_strength.max(0.0);
+        self.h0_changed_at = Some(Instant::now());
+    }
+
+    pub fn crossing_sea(&self) -> (f32, f32) {
+        (self.crossing_angle, self.secondary_strength)
+    }
+
+    // Shape of the secondary spectrum, e.g. a long swell (low wind speed, long fetch, high
+    // swell) under the local chop. Only shows once set_crossing_sea gives it a strength.
+    // Both spectra get independent noise, so their energies add: two identical spectra at
+    // strength 1 come out sqrt(2) times as high as one, not twice. Debounced like the rest.
+    pub fn set_secondary_swell(&mut self, params: SwellParams) {
+        if params != self.secondary_swell {
+            self.secondary_swell = params;
+            self.h0_changed_at = Some(Instant::now());
+        }
+    }
+
+    // Seed of the gaussian noise under h0, the same seed and spectrum give the same ocean.
+    // Not debounced, the noise and h0 are regenerated on the next run.
+    pub fn set_noise_seed(&mut self, seed: u64) {
+        if seed != self.noise_seed {
+            self.noise_seed = seed;
+            self.noise_dirty = true;
+        }
+    }
+
+    pub fn noise_seed(&self) -> u64 {
+        self.noise_seed
+    }
+
+    // Caps the horizontal displacement length in meters, 0 turns the clamp off
+    pub fn set_max_displacement(&mut self, max_displacement: f32) {
+        self.max_displacement = max_displacement.max(0.0);
+    }
+
+    // Scales the horizontal displacement that pinches the crests, 0 leaves a plain heightfield
+    // and 1 is the physical amount. A single wave of amplitude a and wavenumber k folds over
+    // (jacobian below 0) once choppiness * a * k passes 1, past that the mesh self-intersects
+    // on the steepest crests. set_max_displacement limits the damage, foam marks where it is.
+    pub fn set_choppiness(&mut self, choppiness: f32) {
+        self.choppiness = choppiness.max(0.0);
+    }
+
+    // How fast foam fades in 1/s, stored turbulence moves 1 - e^-decay of the way back to the
+    // jacobian each second. How much of it shows is Renderer::set_foam_bias.
+    pub fn set_foam_decay(&mut self, decay: f32) {
+        self.foam_decay = decay.max(0.0);
+    }
+
+    // Where the jacobian drops below threshold (0 is where the surface starts folding) foam
+    // accumulates at injection per second per unit below it, then fades with the decay from
+    // set_foam_decay. Injection 0 leaves only the jacobian itself driving the foam.
+    pub fn set_foam_generation(&mut self, threshold: f32, injection: f32) {
+        self.foam_threshold = threshold;
+        self.foam_injection = injection.max(0.0);
+    }
+
+    pub fn foam_generation(&self) -> (f32, f32) {
+        (self.foam_threshold, self.foam_injection)
+    }
+
+    // Threshold of one cascade instead of the one from set_foam_generation, None goes back to
+    // it. Each cascade's jacobian only covers its own band of waves, so small chop can foam
+    // at a threshold the swell never reaches.
+    pub fn set_cascade_foam_threshold(&mut self, cascade: usize, threshold: Option<f32>) {
+        self.cascades[cascade].foam_threshold = threshold;
+    }
+
+    // Wind speed, fetch, swell, choppiness and foam of the preset in one go. Wind direction,
+    // depth and the secondary spectrum are kept, h0 is rebuilt after the usual debounce.
+    pub fn apply_preset(&mut self, preset: SpectrumPreset) {
+        // wind speed, fetch, swell, choppiness, foam threshold, foam injection, foam decay
+        let (wind_speed, fetch, swell, choppiness, threshold, injection, decay) = match preset {
+            SpectrumPreset::Calm => (0.5, 100000.0, 0.198, 1.0, 0.0, 0.0, 1.0),
+            SpectrumPreset::Breeze => (3.0, 100000.0, 0.3, 1.0, 0.1, 0.5, 0.8),
+            SpectrumPreset::Choppy => (7.0, 150000.0, 0.2, 1.3, 0.2, 1.5, 0.5),
+            SpectrumPreset::Storm => (12.0, 200000.0, 0.5, 1.6, 0.35, 3.0, 0.3),
+        };
+        self.set_spectrum_params(SpectrumParams {
+            wind_speed,
+            fetch,
+            swell,
+            ..self.spectrum_params
+        });
+        self.set_choppiness(choppiness);
+        self.set_foam_generation(threshold, injection);
+        self.set_foam_decay(decay);
+    }
+
+    // Direction the primary waves travel in, radians in the simulation XZ plane
+    pub fn wind_angle(&self) -> f32 {
+        self.spectrum_params.angle
+    }
+
+    // h0 only gets regenerated when the parameters actually changed (debounced like the rest).
+    // A new angle is taken as is, cancelling any turn_wind_to in progress.
+    pub fn set_spectrum_params(&mut self, params: SpectrumParams) {
+        if params.angle != self.spectrum_params.angle {
+            self.target_wind_angle = params.angle;
+        }
+        if params != self.spectrum_params {
+            self.spectrum_params = params;
+            self.h0_changed_at = Some(Instant::now());
+        }
+    }
+
+    // Swings the wind round to angle (radians, the short way) over a few seconds of simulation
+    // time instead of snapping, h0 follows every WIND_ANGLE_EPSILON of the way
+    pub fn turn_wind_to(&mut self, angle: f32) {
+        self.target_wind_angle = angle;
+    }
+
+    pub fn target_wind_angle(&self) -> f32 {
+        self.target_wind_angle
+    }
+
+    // One run's worth of turning towards target_wind_angle, true when h0 needs rebuilding
+    fn turn_wind(&mut self, dlt: f32) -> bool {
+        let angle = self.spectrum_params.angle;
+        if angle == self.target_wind_angle {
+            return false;
+        }
+        let remaining = wrap_angle(self.target_wind_angle - angle);
+        if remaining.abs() <= WIND_ANGLE_EPSILON {
+            // The final angle always gets its own h0
+            self.spectrum_params.angle = self.target_wind_angle;
+            return true;
+        }
+        self.spectrum_params.angle = angle + remaining * (1.0 - (-dlt / WIND_TURN_TIME).exp());
+        wrap_angle(self.spectrum_params.angle - self.h0_wind_angle).abs() > WIND_ANGLE_EPSILON
+    }
+
+    pub fn spectrum_params(&self) -> SpectrumParams {
+        self.spectrum_params
+    }
+
+    // Water depth in meters for the dispersion relation, shallow water slows the long waves
+    // down and makes them steeper. Recomputes h0 like any other spectrum change.
+    pub fn set_depth(&mut self, depth: f32) {
+        self.set_spectrum_params(SpectrumParams {
+            depth: depth.max(MIN_DEPTH),
+            ..self.spectrum_params
+        });
+    }
+
+    pub fn depth(&self) -> f32 {
+        self.spectrum_params.depth
+    }
+
+    // Stretches the spectrum along/across the wind, > 1 gives long crested swell, < 1 short
+    // crested wind sea. Different from spreadBlend which widens the angle. The directional
+    // spread isn't renormalized, so the wave height shifts a little with it as well.
+    pub fn set_crest_stretch(&mut self, crest_stretch: f32) {
+        let crest_stretch = crest_stretch.max(0.01);
+        if crest_stretch != self.crest_stretch {
+            self.crest_stretch = crest_stretch;
+            self.h0_changed_at = Some(Instant::now());
+        }
+    }
+
+    // The spectrum is evaluated at absolute time, so jumping anywhere (even backwards) is fine
+    pub fn set_time(&mut self, time: f32) {
+        self.time = time;
+    }
+
+    // Multiplier for the time advance moves by, 0 pauses, below 1 is slow motion
+    pub fn set_time_scale(&mut self, time_scale: f32) {
+        self.time_scale = time_scale.max(0.0);
+    }
+
+    pub fn time_scale(&self) -> f32 {
+        self.time_scale
+    }
+
+    // While paused advance doesn't move time or dispatch anything, the maps keep the last step.
+    // Time spent paused is never made up for, unpausing carries on from the frozen time.
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    // Seconds added to the time the spectrum is evaluated at, so bodies sharing a
+    // spectrum don't move in lockstep. time itself is left alone.
+    pub fn set_phase_offset(&mut self, phase_offset: f32) {
+        self.phase_offset = phase_offset;
+    }
+
+    // Mapping of the first (largest) cascade
+    pub fn world_mapping(&self) -> WorldMapping {
+        self.cascade_mapping(0)
+    }
+
+    pub fn cascade_mapping(&self, cascade: usize) -> WorldMapping {
+        WorldMapping {
+            meters_per_patch: self.cascades[cascade].length_scale,
+            texture_size: self.texture_size,
+        }
+    }
+
+    fn cascade_mappings(&self) -> Vec<WorldMapping> {
+        (0..self.active_cascades)
+            .map(|i| self.cascade_mapping(i))
+            .collect()
+    }
+
+    pub fn cascade_count(&self) -> usize {
+        self.cascades.len()
+    }
+
+    // Only the first count cascades get run and read back, the smallest patches drop first.
+    // Their maps keep the last frame, so OceanParams.cascadeCount has to stop the shaders at
+    // the same count. Clamped to 1..=cascade_count.
+    pub fn set_active_cascades(&mut self, count: usize) {
+        self.active_cascades = count.clamp(1, self.cascades.len());
+    }
+
+    // Patch size of the first (largest) cascade, see set_cascade_length_scale. Warns when the
+    // spectrum peak doesn't fit the patch, the main waves would come out the wrong size.
+    pub fn set_world_scale(&mut self, meters_per_patch: f32) {
+        self.set_cascade_length_scale(0, meters_per_patch);
+        let mapping = self.world_mapping();
+        let peak = self.peak_wavelength();
+        if !(mapping.min_wavelength()..=mapping.max_wavelength()).contains(&peak) {
+            warn!(
+                "Peak wavelength {:.1}m doesn't fit the {:.0}m patch ({:.2}m to {:.0}m)",
+                peak,
+                meters_per_patch,
+                mapping.min_wavelength(),
+                mapping.max_wavelength()
+            );
+        }
+    }
+
+    // Deep water wavelength in meters at the peak of the wind spectrum, the size of the
+    // waves that dominate the look
+    pub fn peak_wavelength(&self) -> f32 {
+        let g = 9.81;
+        let spectrum = self.spectrum_params;
+        let (_, peak_omega) = calculate_spectrum_params(spectrum.wind_speed, spectrum.fetch, g);
+        TAU * g / (peak_omega * peak_omega)
+    }
 
-        command_buffer
-            .bind_pipeline_compute(pipeline.clone())
-            .bind_descriptor_sets(
-                PipelineBindPoint::Compute,
-                pipeline_layout.clone(),
-                0,
-                descriptor_set,
-            )
-            .push_constants(pipeline_layout.clone(), 0, push_constants)
-            .dispatch(WORKGROUP_SIZE)
-            .expect("Failed to dispatch compute shader");
+    // The spectrum depends on the patch size so h0 gets regenerated, the renderer has to
+    // update OceanParams.lengthScales to match (Renderer::set_world_scale for the first one).
+    // Keep the cascades ordered from largest to smallest, the frequency bands are split
+    // between neighbours in that order.
+    pub fn set_cascade_length_scale(&mut self, cascade: usize, length_scale: f32) {
+        self.cascades[cascade].length_scale = length_scale;
+        self.h0_changed_at = Some(Instant::now());
     }
 
-    pub fn init(
+    fn generate_h0_spectrum(
         &self,
-        cmd_alloc: &StandardCommandBufferAllocator,
-        descriptor_set_allocator: &StandardDescriptorSetAllocator,
-        queue: Arc<Queue>,
-        sampler: Arc<Sampler>,
+        command_buffer: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
     ) {
-        let mut cmd0 = AutoCommandBufferBuilder::primary(
-            cmd_alloc,
-            queue.queue_family_index(),
-            CommandBufferUsage::OneTimeSubmit,
-        )
-        .unwrap();
+        // Wavenumber where cascade i takes over from cascade i - 1
+        let boundary = |i: usize| {
+            2.0 * std::f32::consts::PI / self.cascades[i].length_scale * CASCADE_BOUNDARY
+        };
+
+        for (i, (cascade, sets)) in self.cascades.iter().zip(&self.cascade_sets).enumerate() {
+            let cutoff_low = if i == 0 { 0.0001 } else { boundary(i) };
+            let cutoff_high = if i + 1 < self.cascades.len() {
+                boundary(i + 1)
+            } else {
+                9999.0
+            };
+            self.generate_cascade_h0(command_buffer, cascade, sets, (cutoff_low, cutoff_high));
+        }
+    }
 
-        let wind_speed = 0.5;
-        let (alpha, peak_omega) = calculate_spectrum_params(wind_speed, 100000.0, 9.81);
+    fn generate_cascade_h0(
+        &self,
+        command_buffer: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        cascade: &Cascade,
+        sets: &CascadeSets,
+        (cutoff_low, cutoff_high): (f32, f32),
+    ) {
+        let spectrum = self.spectrum_params;
+        let (alpha, peak_omega) =
+            calculate_spectrum_params(spectrum.wind_speed, spectrum.fetch, 9.81);
+        let angle1 = spectrum.angle;
+        let swell = self.secondary_swell;
+        let (alpha2, peak_omega2) = calculate_spectrum_params(swell.wind_speed, swell.fetch, 9.81);
 
-        self.run_compute_shader(
-            &mut cmd0,
-            descriptor_set_allocator,
-            self.init_spec_pipeline.clone(),
-            vec![
-                WriteDescriptorSet::image_view(0, self.waves_data.clone()),
-                WriteDescriptorSet::image_view(1, self.spec_hk.clone()),
-                WriteDescriptorSet::image_view_sampler(
-                    2,
-                    self.noise_image.clone(),
-                    sampler.clone(),
-                ),
-            ],
+        self.dispatch_with_set(
+            command_buffer,
+            self.pipelines().init_spec.clone(),
+            sets.init_spec.clone(),
             init_spec_shader::ty::PushConstants {
-                size: TEXTURE_SIZE,
-                lengthScale: 100.0,
-                cutoffHigh: 9999.0,
-                cutoffLow: 0.0001,
+                size: self.texture_size,
+                lengthScale: cascade.length_scale,
+                cutoffHigh: cutoff_high,
+                cutoffLow: cutoff_low,
                 gravityAcceleration: 9.81,
-                depth: 500.0,
+                depth: spectrum.depth,
 
                 scale1: 1.0,
-                angle1: (-29.81_f32).to_radians(),
+                angle1,
                 spreadBlend1: 1.0,
-                swell1: 0.198,
+                swell1: spectrum.swell,
                 alpha1: alpha,
                 peakOmega1: peak_omega,
-                gamma1: 3.3,
-                shortWavesFade1: 0.01,
+                gamma1: spectrum.gamma,
+                shortWavesFade1: spectrum.short_waves_fade,
 
-                // This is disabled
-                scale2: 0.0,
-                angle2: 0.0,
+                // Disabled unless set_crossing_sea gave it a strength
+                scale2: self.secondary_strength,
+                angle2: angle1 + self.crossing_angle,
                 spreadBlend2: 1.0,
-                swell2: 1.0,
-                alpha2: 0.0081,
-                peakOmega2: 0.831,
-                gamma2: 3.3,
-                shortWavesFade2: 0.01,
+                swell2: swell.swell,
+                alpha2,
+                peakOmega2: peak_omega2,
+                gamma2: swell.gamma,
+                shortWavesFade2: swell.short_waves_fade,
+
+                crestStretch: self.crest_stretch,
             },
         );
-        self.run_compute_shader(
-            &mut cmd0,
-            descriptor_set_allocator,
-            self.fft_init_pipeline.clone(),
-            vec![WriteDescriptorSet::image_view(
-                0,
-                self.precomputed_data.clone(),
-            )],
-            fft_init_shader::ty::PushConstants { size: TEXTURE_SIZE },
+        self.dispatch_with_set(
+            command_buffer,
+            self.pipelines().conj_spec.clone(),
+            sets.conj_spec.clone(),
+            conj_spec_shader::ty::PushConstants {
+                size: self.texture_size,
+            },
         );
-        cmd0.build()
-            .unwrap()
-            .execute(queue.clone())
-            .unwrap()
-            .then_signal_fence_and_flush()
-            .unwrap()
-            .wait(None)
-            .unwrap();
+    }
 
-        let mut cmd1 = AutoCommandBufferBuilder::primary(
-            cmd_alloc,
-            queue.queue_family_index(),
-            CommandBufferUsage::OneTimeSubmit,
-        )
-        .unwrap();
-        self.run_compute_shader(
-            &mut cmd1,
-            descriptor_set_allocator,
-            self.conj_spec_pipeline.clone(),
-            vec![
-                WriteDescriptorSet::image_view(0, self.spec_hk.clone()),
-                WriteDescriptorSet::image_view(1, self.spec_h0.clone()),
-            ],
-            conj_spec_shader::ty::PushConstants { size: TEXTURE_SIZE },
-        );
-        cmd1.build()
-            .unwrap()
-            .execute(queue.clone())
-            .unwrap()
-            .then_signal_fence_and_flush()
-            .unwrap()
-            .wait(None)
-            .unwrap();
+    // Advances time by delta_time * time_scale and runs the simulation once. The waves are
+    // evaluated at that continuous time, so they move smoothly at any framerate and the
+    // displacement for a given total time doesn't depend on how it was split into frames.
+    // Only the foam integration moves in whole FIXED_TIMESTEP steps, see run.
+    pub fn advance(
+        &mut self,
+        delta_time: f32,
+        cmd_alloc: &StandardCommandBufferAllocator,
+        descriptor_set_allocator: &StandardDescriptorSetAllocator,
+        queue: Arc<Queue>,
+    ) {
+        if self.paused {
+            return;
+        }
+        self.time += delta_time.max(0.0) * self.time_scale;
+        self.run(cmd_alloc, descriptor_set_allocator, queue);
     }
 
     pub fn run(
-        &self,
+        &mut self,
         cmd_alloc: &StandardCommandBufferAllocator,
         descriptor_set_allocator: &StandardDescriptorSetAllocator,
         queue: Arc<Queue>,
     ) {
-        let mut cmd0 = AutoCommandBufferBuilder::primary(
+        if self.pipelines.is_none() {
+            return;
+        }
+
+        // The whole step goes into one command buffer and a single wait. Every dispatch binds
+        // its images through a descriptor set, so the builder puts the barriers in between
+        // (see run_compute_shader) and the stages still run in the order they're recorded.
+        let mut cmd = AutoCommandBufferBuilder::primary(
             cmd_alloc,
             queue.queue_family_index(),
             CommandBufferUsage::OneTimeSubmit,
         )
         .unwrap();
-        self.run_compute_shader(
-            &mut cmd0,
-            descriptor_set_allocator,
-            self.time_spec_pipeline.clone(),
-            vec![
-                WriteDescriptorSet::image_view(0, self.waves_data.clone()),
-                WriteDescriptorSet::image_view(1, self.spec_h0.clone()),
-                // Displacement
-                WriteDescriptorSet::image_view(2, self.dx_dz.clone()),
-                WriteDescriptorSet::image_view(3, self.dy_dxz.clone()),
-                WriteDescriptorSet::image_view(4, self.dyx_dyz.clone()),
-                WriteDescriptorSet::image_view(5, self.dxx_dzz.clone()),
-            ],
-            time_spec_shader::ty::PushConstants {
-                size: TEXTURE_SIZE,
-                time: self.time,
-            },
-        );
-        cmd0.build()
+        self.reset_timing(&mut cmd);
+
+        // Scrubbing backwards doesn't bring foam back
+        let dlt = (self.time - self.last_run_time).max(0.0);
+        self.last_run_time = self.time;
+        // Foam integrates in whole FIXED_TIMESTEP steps, the rest carries over to the next run
+        self.foam_accumulator += dlt;
+        let foam_steps = (self.foam_accumulator / FIXED_TIMESTEP) as u32;
+        self.foam_accumulator -= foam_steps as f32 * FIXED_TIMESTEP;
+        if foam_steps > MAX_FOAM_STEPS_PER_RUN {
+            debug!(
+                "Foam fell behind by {} steps, dropping them",
+                foam_steps - MAX_FOAM_STEPS_PER_RUN
+            );
+        }
+        let foam_steps = foam_steps.min(MAX_FOAM_STEPS_PER_RUN);
+
+        let wind_turned = self.turn_wind(dlt);
+        let disturbance_count = self.update_ripples(dlt);
+        let new_noise = std::mem::take(&mut self.noise_dirty);
+        if new_noise {
+            self.record_noise(&mut cmd, descriptor_set_allocator);
+        }
+        let regenerate_h0 = wind_turned
+            || new_noise
+            || self
+                .h0_changed_at
+                .is_some_and(|changed| changed.elapsed() >= H0_DEBOUNCE);
+        if regenerate_h0 {
+            self.begin_timing(&mut cmd, INIT_TIMING);
+            self.generate_h0_spectrum(&mut cmd);
+            self.end_timing(&mut cmd, INIT_TIMING);
+            self.h0_changed_at = None;
+            self.h0_wind_angle = self.spectrum_params.angle;
+        }
+        let active = self
+            .cascades
+            .iter()
+            .zip(&self.cascade_sets)
+            .take(self.active_cascades);
+        for (i, (cascade, sets)) in active.enumerate() {
+            self.run_cascade(
+                &mut cmd,
+                descriptor_set_allocator,
+                cascade,
+                sets,
+                cascade_timing(i),
+                foam_steps,
+            );
+        }
+
+        // Everything below works on the finished maps of all cascades
+        // Disturbances go on the largest cascade, its patch repeats the least
+        if disturbance_count > 0 {
+            self.run_compute_shader(
+                &mut cmd,
+                descriptor_set_allocator,
+                self.pipelines().disturbance.clone(),
+                vec![
+                    WriteDescriptorSet::image_view(0, self.cascades[0].displacement.clone()),
+                    WriteDescriptorSet::image_view(1, self.cascades[0].derivatives.clone()),
+                    WriteDescriptorSet::buffer(2, self.disturbance_buffer.clone()),
+                ],
+                disturbance_shader::ty::PushConstants {
+                    size: self.texture_size,
+                    count: disturbance_count,
+                    lengthScale: self.cascades[0].length_scale,
+                },
+            );
+        }
+        if let Some(sanity_buffer) = &self.sanity_buffer {
+            // run waits for the GPU, so the previous frame is done with the flag
+            *sanity_buffer.write().unwrap() = 0;
+            for cascade in &self.cascades[..self.active_cascades] {
+                self.run_compute_shader(
+                    &mut cmd,
+                    descriptor_set_allocator,
+                    self.pipelines().sanity_check.clone(),
+                    vec![
+                        WriteDescriptorSet::image_view(0, cascade.displacement.clone()),
+                        WriteDescriptorSet::buffer(1, sanity_buffer.clone()),
+                    ],
+                    sanity_check_shader::ty::PushConstants {
+                        size: self.texture_size,
+                    },
+                );
+            }
+        }
+        if let Some(readback) = &self.frame_readback {
+            for (map, buffer) in &readback.buffers {
+                cmd.copy_image_to_buffer(CopyImageToBufferInfo::image_buffer(
+                    self.sim_map(*map).image().clone(),
+                    buffer.clone(),
+                ))
+                .unwrap();
+            }
+        }
+        if let Some(query) = &self.displacement_query {
+            cmd.copy_image_to_buffer(CopyImageToBufferInfo::image_buffer(
+                self.displacement_map.image().clone(),
+                query.buffer.clone(),
+            ))
+            .unwrap();
+        }
+        cmd.build()
             .unwrap()
             .execute(queue.clone())
             .unwrap()
@@ -425,147 +1972,176 @@ impl Simulation {
             .wait(None)
             .unwrap();
 
-        self.run_ifft_2d(
-            cmd_alloc,
-            descriptor_set_allocator,
-            queue.clone(),
-            true,
-            false,
-            true,
-            self.dx_dz.clone(),
-            self.buffer.clone(),
-        );
-        self.run_ifft_2d(
-            cmd_alloc,
-            descriptor_set_allocator,
-            queue.clone(),
-            true,
-            false,
-            true,
-            self.dy_dxz.clone(),
-            self.buffer.clone(),
-        );
-        self.run_ifft_2d(
-            cmd_alloc,
-            descriptor_set_allocator,
-            queue.clone(),
-            true,
-            false,
-            true,
-            self.dyx_dyz.clone(),
-            self.buffer.clone(),
-        );
-        self.run_ifft_2d(
-            cmd_alloc,
-            descriptor_set_allocator,
-            queue.clone(),
-            true,
-            false,
-            true,
-            self.dxx_dzz.clone(),
-            self.buffer.clone(),
-        );
+        let stage_ms = |stage: u32| -> Vec<f32> {
+            (0..self.active_cascades)
+                .map(|i| self.timed_ms(cascade_timing(i) + stage))
+                .collect()
+        };
+        self.last_timings = self.gpu_timer.is_some().then(|| SimTimings {
+            init: if regenerate_h0 {
+                self.timed_ms(INIT_TIMING)
+            } else {
+                0.0
+            },
+            time_spec: stage_ms(0).iter().sum(),
+            ifft: stage_ms(1),
+            merger: stage_ms(2).iter().sum(),
+        });
 
-        let mut cmd1 = AutoCommandBufferBuilder::primary(
-            cmd_alloc,
-            queue.queue_family_index(),
-            CommandBufferUsage::OneTimeSubmit,
-        )
-        .unwrap();
-        self.run_compute_shader(
-            &mut cmd1,
-            descriptor_set_allocator,
-            self.texture_merger_pipeline.clone(),
-            vec![
-                WriteDescriptorSet::image_view(0, self.displacement_map.clone()),
-                WriteDescriptorSet::image_view(1, self.derivatives_map.clone()),
-                WriteDescriptorSet::image_view(2, self.turbulence_map.clone()),
-                // Displacement
-                WriteDescriptorSet::image_view(3, self.dx_dz.clone()),
-                WriteDescriptorSet::image_view(4, self.dy_dxz.clone()),
-                WriteDescriptorSet::image_view(5, self.dyx_dyz.clone()),
-                WriteDescriptorSet::image_view(6, self.dxx_dzz.clone()),
-            ],
-            texture_merger_shader::ty::PushConstants {
-                size: TEXTURE_SIZE,
-                dlt: self.time,
+        if let Some(sanity_buffer) = &self.sanity_buffer {
+            let sane = *sanity_buffer.read().unwrap() == 0;
+            if self.output_sane && !sane {
+                warn!(
+                    "Displacement has NaN/Inf at t = {:.2}s, check the spectrum parameters",
+                    self.time
+                );
+            }
+            self.output_sane = sane;
+        }
+
+        if let Some(query) = &mut self.displacement_query {
+            query.texels.copy_from_slice(&query.buffer.read().unwrap());
+        }
+
+        if let Some(readback) = &mut self.frame_readback {
+            let guards: Vec<_> = readback
+                .buffers
+                .iter()
+                .map(|(map, buffer)| (*map, buffer.read().unwrap()))
+                .collect();
+            let frame = FrameData {
+                time: self.time,
+                size: self.texture_size,
+                cascades: self.cascades.len() as u32,
+                maps: guards.iter().map(|(map, data)| (*map, &data[..])).collect(),
+            };
+            (readback.callback)(&frame);
+        }
+    }
+
+    // time_spec -> IFFTs -> merger for one cascade, recorded into command_buffer. The merger
+    // runs once per foam step on the same IFFT output, once with no foam change for 0 steps.
+    // The FFT images are shared between cascades, the builder's barriers keep one cascade's
+    // merger ahead of the next one's time_spec. timing is the first of the cascade's three
+    // timestamp pairs.
+    fn run_cascade(
+        &self,
+        command_buffer: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        descriptor_set_allocator: &StandardDescriptorSetAllocator,
+        cascade: &Cascade,
+        sets: &CascadeSets,
+        timing: u32,
+        foam_steps: u32,
+    ) {
+        self.begin_timing(command_buffer, timing);
+        self.dispatch_with_set(
+            command_buffer,
+            self.pipelines().time_spec.clone(),
+            sets.time_spec.clone(),
+            time_spec_shader::ty::PushConstants {
+                size: self.texture_size,
+                time: self.time + self.phase_offset,
             },
         );
-        cmd1.build()
-            .unwrap()
-            .execute(queue.clone())
-            .unwrap()
-            .then_signal_fence_and_flush()
-            .unwrap()
-            .wait(None)
-            .unwrap();
+        self.end_timing(command_buffer, timing);
+
+        self.begin_timing(command_buffer, timing + 1);
+        for input in [&self.dx_dz, &self.dy_dxz, &self.dyx_dyz, &self.dxx_dzz] {
+            self.run_ifft_2d(
+                command_buffer,
+                descriptor_set_allocator,
+                true,
+                false,
+                true,
+                input.clone(),
+            );
+        }
+        self.end_timing(command_buffer, timing + 1);
+
+        self.begin_timing(command_buffer, timing + 2);
+        let dlt = if foam_steps == 0 { 0.0 } else { FIXED_TIMESTEP };
+        for _ in 0..foam_steps.max(1) {
+            self.run_compute_shader(
+                command_buffer,
+                descriptor_set_allocator,
+                self.pipelines().texture_merger.clone(),
+                vec![
+                    WriteDescriptorSet::image_view(0, cascade.displacement.clone()),
+                    WriteDescriptorSet::image_view(1, cascade.derivatives.clone()),
+                    WriteDescriptorSet::image_view(2, cascade.turbulence.clone()),
+                    // Displacement
+                    WriteDescriptorSet::image_view(3, self.dx_dz.clone()),
+                    WriteDescriptorSet::image_view(4, self.dy_dxz.clone()),
+                    WriteDescriptorSet::image_view(5, self.dyx_dyz.clone()),
+                    WriteDescriptorSet::image_view(6, self.dxx_dzz.clone()),
+                ],
+                texture_merger_shader::ty::PushConstants {
+                    size: self.texture_size,
+                    dlt,
+                    maxDisplacement: self.max_displacement,
+                    foamDecay: self.foam_decay,
+                    choppiness: self.choppiness,
+                    foamThreshold: cascade.foam_threshold.unwrap_or(self.foam_threshold),
+                    foamInjection: self.foam_injection,
+                },
+            );
+        }
+        self.end_timing(command_buffer, timing + 2);
     }
 
     fn run_ifft_2d(
         &self,
-        cmd_alloc: &StandardCommandBufferAllocator,
+        commands: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
         descriptor_set_allocator: &StandardDescriptorSetAllocator,
-        queue: Arc<Queue>,
         output_to_input: bool,
         scale: bool,
         permute: bool,
         input: Arc<ImageView<StorageImage>>,
-        buffer: Arc<ImageView<StorageImage>>,
     ) {
-        let log_size = (TEXTURE_SIZE as f32).log2() as u32;
+        let buffer = &self.buffer;
+        let log_size = self.texture_size.ilog2();
         let mut ping_pong = 0;
 
-        let mut commands = AutoCommandBufferBuilder::primary(
-            cmd_alloc,
-            queue.queue_family_index(),
-            CommandBufferUsage::OneTimeSubmit,
-        )
-        .unwrap();
-
         for i in 0..log_size {
             ping_pong ^= 1;
 
             self.run_compute_shader(
-                &mut commands,
+                commands,
                 descriptor_set_allocator,
-                self.fft_pipeline.clone(),
+                self.pipelines().fft.clone(),
                 vec![
                     WriteDescriptorSet::image_view(0, self.precomputed_data.clone()),
                     WriteDescriptorSet::image_view(1, input.clone()),
                     WriteDescriptorSet::image_view(2, buffer.clone()),
                 ],
                 fft_shader::ty::PushConstants {
-                    size: TEXTURE_SIZE,
+                    size: self.texture_size,
                     stage: i,
                     ping_pong,
                     mode: 2, // Inverse Horizontal pass
                 },
             );
-
-            commands.dispatch(WORKGROUP_SIZE).unwrap();
         }
 
         for i in 0..log_size {
             ping_pong ^= 1;
 
             self.run_compute_shader(
-                &mut commands,
+                commands,
                 descriptor_set_allocator,
-                self.fft_pipeline.clone(),
+                self.pipelines().fft.clone(),
                 vec![
                     WriteDescriptorSet::image_view(0, self.precomputed_data.clone()),
                     WriteDescriptorSet::image_view(1, input.clone()),
                     WriteDescriptorSet::image_view(2, buffer.clone()),
                 ],
                 fft_shader::ty::PushConstants {
-                    size: TEXTURE_SIZE,
+                    size: self.texture_size,
                     stage: i,
                     ping_pong,
                     mode: 3, // Inverse Vertical pass
                 },
             );
-
-            commands.dispatch(WORKGROUP_SIZE).unwrap();
         }
 
         if ping_pong == 1 && output_to_input {
@@ -587,16 +2163,16 @@ impl Simulation {
 
         if permute {
             self.run_compute_shader(
-                &mut commands,
+                commands,
                 descriptor_set_allocator,
-                self.fft_pipeline.clone(),
+                self.pipelines().fft.clone(),
                 vec![
                     WriteDescriptorSet::image_view(0, self.precomputed_data.clone()),
                     WriteDescriptorSet::image_view(1, input.clone()),
                     WriteDescriptorSet::image_view(2, buffer.clone()),
                 ],
                 fft_shader::ty::PushConstants {
-                    size: TEXTURE_SIZE,
+                    size: self.texture_size,
                     stage: 0,
                     ping_pong,
                     mode: 5, // Permute pass
@@ -605,46 +2181,74 @@ impl Simulation {
         }
         if scale {
             self.run_compute_shader(
-                &mut commands,
+                commands,
                 descriptor_set_allocator,
-                self.fft_pipeline.clone(),
+                self.pipelines().fft.clone(),
                 vec![
                     WriteDescriptorSet::image_view(0, self.precomputed_data.clone()),
                     WriteDescriptorSet::image_view(1, input.clone()),
                     WriteDescriptorSet::image_view(2, buffer.clone()),
                 ],
                 fft_shader::ty::PushConstants {
-                    size: TEXTURE_SIZE,
+                    size: self.texture_size,
                     stage: 0,
                     ping_pong,
                     mode: 4, // Scale pass
                 },
             );
         }
+    }
 
-        commands
-            .build()
-            .unwrap()
-            .execute(queue.clone())
-            .unwrap()
-            .then_signal_fence_and_flush()
-            .unwrap()
-            .wait(None)
-            .unwrap();
+    // Fills noise_image for noise_seed, from the CPU rng or the noise compute shader.
+    // h0 has to be regenerated after it for the waves to change.
+    fn record_noise(
+        &self,
+        cmd: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        descriptor_set_allocator: &StandardDescriptorSetAllocator,
+    ) {
+        match self.noise_source {
+            NoiseSource::Cpu => {
+                let staging_buffer = CpuAccessibleBuffer::from_iter(
+                    &self.memory_allocator,
+                    BufferUsage {
+                        transfer_src: true,
+                        ..BufferUsage::empty()
+                    },
+                    false,
+                    generate_gaussian_noise(self.texture_size, self.noise_seed),
+                )
+                .unwrap();
+                cmd.copy_buffer_to_image(CopyBufferToImageInfo::buffer_image(
+                    staging_buffer,
+                    self.noise_image.image().clone(),
+                ))
+                .unwrap();
+            }
+            NoiseSource::Gpu => self.run_compute_shader(
+                cmd,
+                descriptor_set_allocator,
+                self.pipelines().noise_gen.clone(),
+                vec![WriteDescriptorSet::image_view(0, self.noise_image.clone())],
+                noise_gen_shader::ty::PushConstants {
+                    size: self.texture_size,
+                    // The shader hash only takes 32 bits, fold the seed down
+                    seed: (self.noise_seed ^ (self.noise_seed >> 32)) as u32,
+                },
+            ),
+        }
     }
 
-    fn generate_noise_texture(
+    // Storage image that can also be filled from a staging buffer
+    fn create_upload_image(
         memory_allocator: &StandardMemoryAllocator,
         queue: &Arc<Queue>,
-        command_buffer_allocator: &StandardCommandBufferAllocator,
+        size: u32,
     ) -> Arc<StorageImage> {
-        let noise_data = generate_gaussian_noise(TEXTURE_SIZE);
-
-        let noise_image = StorageImage::with_usage(
+        StorageImage::with_usage(
             memory_allocator,
             ImageDimensions::Dim2d {
-                width: TEXTURE_SIZE,
-                height: TEXTURE_SIZE,
+                width: size,
+                height: size,
                 array_layers: 1,
             },
             Format::R32G32B32A32_SFLOAT,
@@ -657,43 +2261,205 @@ impl Simulation {
             vulkano::image::ImageCreateFlags::empty(),
             [queue.queue_family_index()],
         )
+        .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+    use rustfft::{FftPlanner, num_complex::Complex};
+    use vulkano::{
+        descriptor_set::allocator::StandardDescriptorSetAllocator, sampler::SamplerCreateInfo,
+    };
+
+    use super::*;
+    use crate::gpu_context::ValidationConfig;
+
+    fn mapping() -> WorldMapping {
+        WorldMapping {
+            meters_per_patch: 100.0,
+            texture_size: 256,
+        }
+    }
+
+    #[test]
+    fn meters_per_texel_splits_the_patch() {
+        assert_eq!(mapping().meters_per_texel(), 100.0 / 256.0);
+    }
+
+    #[test]
+    fn wavelength_to_texels_round_trips() {
+        let mapping = mapping();
+        assert_eq!(mapping.wavelength_to_texels(100.0), 256.0);
+        assert_eq!(mapping.texels_to_wavelength(256.0), 100.0);
+        let texels = mapping.wavelength_to_texels(12.5);
+        assert!((mapping.texels_to_wavelength(texels) - 12.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn wavelength_range_is_two_texels_to_the_patch() {
+        let mapping = mapping();
+        assert_eq!(mapping.wavelength_to_texels(mapping.min_wavelength()), 2.0);
+        assert_eq!(mapping.max_wavelength(), 100.0);
+    }
+
+    #[test]
+    fn gaussian_noise_follows_the_seed() {
+        let noise = generate_gaussian_noise(64, 7);
+        assert_eq!(noise.len(), 64 * 64);
+        assert_eq!(noise, generate_gaussian_noise(64, 7));
+        assert_ne!(noise, generate_gaussian_noise(64, 8));
+    }
+
+    // run_ifft_2d the way run_cascade calls it, on a random spectrum, against rustfft. The
+    // permute pass flips the sign of every other texel, for spectra centered on size / 2.
+    #[test]
+    fn gpu_ifft_matches_rustfft() {
+        let context = match GpuContext::headless(ValidationConfig::default()) {
+            Ok(context) => context,
+            Err(e) => {
+                eprintln!("Skipping, no Vulkan device: {}", e);
+                return;
+            }
+        };
+        let size = 16;
+        let sampler = Sampler::new(
+            context.device.clone(),
+            SamplerCreateInfo::simple_repeat_linear(),
+        )
         .unwrap();
+        let descriptor_set_allocator = StandardDescriptorSetAllocator::new(context.device.clone());
+        let simulation = Simulation::new(
+            &context,
+            &sampler,
+            NoiseSource::Cpu,
+            Some(1),
+            &DEFAULT_CASCADE_LENGTH_SCALES[..1],
+            size,
+        );
+        simulation.init(
+            &context.command_buffer_allocator,
+            &descriptor_set_allocator,
+            context.queue.clone(),
+        );
 
-        let staging_buffer = CpuAccessibleBuffer::from_iter(
-            memory_allocator,
-            BufferUsage {
-                transfer_src: true,
-                ..BufferUsage::empty()
-            },
+        let mut rng = StdRng::seed_from_u64(3);
+        let spectrum: Vec<Complex<f32>> = (0..size * size)
+            .map(|_| Complex::new(rng.random_range(-1.0..1.0), rng.random_range(-1.0..1.0)))
+            .collect();
+        let buffer_usage = BufferUsage {
+            transfer_src: true,
+            transfer_dst: true,
+            ..BufferUsage::empty()
+        };
+        let upload = CpuAccessibleBuffer::from_iter(
+            &context.memory_allocator,
+            buffer_usage,
             false,
-            noise_data,
+            spectrum.iter().map(|c| [c.re, c.im, 0.0, 0.0]),
+        )
+        .unwrap();
+        let readback = CpuAccessibleBuffer::from_iter(
+            &context.memory_allocator,
+            buffer_usage,
+            false,
+            spectrum.iter().map(|_| [0.0f32; 4]),
         )
         .unwrap();
 
-        let mut builder = AutoCommandBufferBuilder::primary(
-            command_buffer_allocator,
-            queue.queue_family_index(),
+        let mut cmd = AutoCommandBufferBuilder::primary(
+            &context.command_buffer_allocator,
+            context.queue.queue_family_index(),
             CommandBufferUsage::OneTimeSubmit,
         )
         .unwrap();
-
-        builder
-            .copy_buffer_to_image(CopyBufferToImageInfo::buffer_image(
-                staging_buffer,
-                noise_image.clone(),
-            ))
-            .unwrap();
-
-        let command_buffer = builder.build().unwrap();
-
-        command_buffer
-            .execute(queue.clone())
+        cmd.copy_buffer_to_image(CopyBufferToImageInfo::buffer_image(
+            upload,
+            simulation.dx_dz.image().clone(),
+        ))
+        .unwrap();
+        simulation.run_ifft_2d(
+            &mut cmd,
+            &descriptor_set_allocator,
+            true,
+            false,
+            true,
+            simulation.dx_dz.clone(),
+        );
+        cmd.copy_image_to_buffer(CopyImageToBufferInfo::image_buffer(
+            simulation.dx_dz.image().clone(),
+            readback.clone(),
+        ))
+        .unwrap();
+        cmd.build()
+            .unwrap()
+            .execute(context.queue.clone())
             .unwrap()
             .then_signal_fence_and_flush()
             .unwrap()
             .wait(None)
             .unwrap();
 
-        noise_image
+        // Rows, then columns, unnormalized like the GPU without the scale pass
+        let size = size as usize;
+        let ifft = FftPlanner::new().plan_fft_inverse(size);
+        let mut expected = spectrum;
+        for row in expected.chunks_exact_mut(size) {
+            ifft.process(row);
+        }
+        for x in 0..size {
+            let mut column: Vec<_> = (0..size).map(|y| expected[y * size + x]).collect();
+            ifft.process(&mut column);
+            for (y, value) in column.into_iter().enumerate() {
+                expected[y * size + x] = value;
+            }
+        }
+
+        let texels = readback.read().unwrap();
+        for (i, (texel, expected)) in texels.iter().zip(&expected).enumerate() {
+            let sign = if (i % size + i / size).is_multiple_of(2) {
+                1.0
+            } else {
+                -1.0
+            };
+            let error = (Complex::new(texel[0], texel[1]) - expected * sign).norm();
+            assert!(
+                error < 1e-3,
+                "Texel {} is {:?}, rustfft gives {}",
+                i,
+                &texel[..2],
+                expected * sign
+            );
+        }
+    }
+    #[test]
+    fn set_world_scale_resizes_the_first_cascade() {
+        let context = match GpuContext::headless(ValidationConfig::default()) {
+            Ok(context) => context,
+            Err(e) => {
+                eprintln!("Skipping, no Vulkan device: {}", e);
+                return;
+            }
+        };
+        let sampler = Sampler::new(
+            context.device.clone(),
+            SamplerCreateInfo::simple_repeat_linear(),
+        )
+        .unwrap();
+        let mut simulation = Simulation::new(
+            &context,
+            &sampler,
+            NoiseSource::Cpu,
+            Some(1),
+            &DEFAULT_CASCADE_LENGTH_SCALES,
+            16,
+        );
+        simulation.set_world_scale(40.0);
+        assert_eq!(simulation.world_mapping().meters_per_patch, 40.0);
+        assert_eq!(
+            simulation.cascade_mapping(1).meters_per_patch,
+            DEFAULT_CASCADE_LENGTH_SCALES[1]
+        );
     }
 }
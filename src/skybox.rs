@@ -0,0 +1,519 @@
+use std::{
+    fmt,
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use log::warn;
+use vulkano::{
+    buffer::{BufferUsage, CpuAccessibleBuffer},
+    command_buffer::{
+        AutoCommandBufferBuilder, BlitImageInfo, CommandBufferUsage, CopyBufferToImageInfo,
+        CopyImageInfo, ImageBlit, ImageCopy, PrimaryCommandBufferAbstract,
+        allocator::StandardCommandBufferAllocator,
+    },
+    descriptor_set::{
+        PersistentDescriptorSet, WriteDescriptorSet, allocator::StandardDescriptorSetAllocator,
+    },
+    device::Queue,
+    format::Format,
+    image::{
+        ImageAccess, ImageCreateFlags, ImageDimensions, ImageLayout, ImageSubresourceLayers,
+        ImageUsage, ImmutableImage, MipmapsCount, StorageImage,
+        view::{ImageView, ImageViewCreateInfo, ImageViewType},
+    },
+    memory::allocator::StandardMemoryAllocator,
+    pipeline::{ComputePipeline, Pipeline, PipelineBindPoint},
+    sampler::{Filter, Sampler, SamplerCreateInfo},
+    sync::GpuFuture,
+};
+
+mod env_prefilter_shader {
+    vulkano_shaders::shader! {
+        ty: "compute",
+        path: "src/shaders/env_prefilter.comp",
+        types_meta: {
+            use bytemuck::{Pod, Zeroable};
+
+            #[derive(Clone, Copy, Zeroable, Pod)]
+        },
+    }
+}
+
+// Shown until a skybox gets loaded, the old clear color (sRGB encoded) on every face
+pub const DEFAULT_SKY_COLOR: [u8; 4] = [89, 218, 243, 255];
+
+#[derive(Debug)]
+pub enum SkyboxError {
+    Io(io::Error),
+    Decode(png::DecodingError),
+    // Faces have to be square and all the same size
+    FaceSize { path: PathBuf, size: [u32; 2] },
+}
+
+impl fmt::Display for SkyboxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SkyboxError::Io(e) => write!(f, "{}", e),
+            SkyboxError::Decode(e) => write!(f, "{}", e),
+            SkyboxError::FaceSize { path, size } => write!(
+                f,
+                "{} is {}x{}, faces have to be square and all the same size",
+                path.display(),
+                size[0],
+                size[1]
+            ),
+        }
+    }
+}
+
+impl From<io::Error> for SkyboxError {
+    fn from(e: io::Error) -> Self {
+        SkyboxError::Io(e)
+    }
+}
+
+impl From<png::DecodingError> for SkyboxError {
+    fn from(e: png::DecodingError) -> Self {
+        SkyboxError::Decode(e)
+    }
+}
+
+// Decodes a face into tightly packed RGBA8
+fn load_face(path: &Path) -> Result<([u32; 2], Vec<u8>), SkyboxError> {
+    let mut decoder = png::Decoder::new(File::open(path)?);
+    // Palettes get expanded and 16 bit channels stripped, what's left is 8 bit gray or color
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let mut texels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut texels)?;
+    texels.truncate(info.buffer_size());
+
+    let rgba = match info.color_type {
+        png::ColorType::Rgba => texels,
+        png::ColorType::Rgb => texels
+            .chunks_exact(3)
+            .flat_map(|t| [t[0], t[1], t[2], 255])
+            .collect(),
+        png::ColorType::GrayscaleAlpha => texels
+            .chunks_exact(2)
+            .flat_map(|t| [t[0], t[0], t[0], t[1]])
+            .collect(),
+        png::ColorType::Grayscale => texels.iter().flat_map(|&g| [g, g, g, 255]).collect(),
+        png::ColorType::Indexed => unreachable!("Palettes are expanded while decoding"),
+    };
+    Ok(([info.width, info.height], rgba))
+}
+
+// Edge of the prefiltered mirror level, rough reflections don't need the full sky resolution
+const PREFILTER_SIZE: u32 = 128;
+// Mirror to fully rough, the last level is 4x4 at the default size
+const PREFILTER_LEVELS: u32 = 6;
+
+fn cube_view(image: Arc<ImmutableImage>) -> Arc<ImageView<ImmutableImage>> {
+    let mut create_info = ImageViewCreateInfo::from_image(&image);
+    create_info.view_type = ImageViewType::Cube;
+    ImageView::new(image, create_info).unwrap()
+}
+
+// Cube view over six square faces in Vulkan layer order: +X, -X, +Y, -Y, +Z, -Z.
+// texels holds all of them back to back as sRGB encoded RGBA8.
+// The full mip chain gets blitted down from them for the prefilter.
+fn create_cubemap(
+    allocator: &StandardMemoryAllocator,
+    command_buffer_allocator: &StandardCommandBufferAllocator,
+    queue: &Arc<Queue>,
+    size: u32,
+    texels: Vec<u8>,
+) -> Arc<ImageView<ImmutableImage>> {
+    let dimensions = ImageDimensions::Dim2d {
+        width: size,
+        height: size,
+        array_layers: 6,
+    };
+    let (image, init) = ImmutableImage::uninitialized(
+        allocator,
+        dimensions,
+        Format::R8G8B8A8_SRGB,
+        MipmapsCount::Log2,
+        ImageUsage {
+            transfer_dst: true,
+            transfer_src: true,
+            sampled: true,
+            ..ImageUsage::empty()
+        },
+        ImageCreateFlags {
+            cube_compatible: true,
+            ..ImageCreateFlags::empty()
+        },
+        ImageLayout::ShaderReadOnlyOptimal,
+        [queue.queue_family_index()],
+    )
+    .unwrap();
+
+    let staging_buffer = CpuAccessibleBuffer::from_iter(
+        allocator,
+        BufferUsage {
+            transfer_src: true,
+            ..BufferUsage::empty()
+        },
+        false,
+        texels,
+    )
+    .unwrap();
+
+    let mut builder = AutoCommandBufferBuilder::primary(
+        command_buffer_allocator,
+        queue.queue_family_index(),
+        CommandBufferUsage::OneTimeSubmit,
+    )
+    .unwrap();
+    builder
+        .copy_buffer_to_image(CopyBufferToImageInfo::buffer_image(staging_buffer, init))
+        .unwrap();
+    // Same as ImmutableImage::from_buffer does, which has no array layers
+    for level in 1..image.mip_levels() {
+        let src_size = dimensions.mip_level_dimensions(level - 1).unwrap();
+        let dst_size = dimensions.mip_level_dimensions(level).unwrap();
+        builder
+            .blit_image(BlitImageInfo {
+                regions: [ImageBlit {
+                    src_subresource: ImageSubresourceLayers {
+                        mip_level: level - 1,
+                        ..image.subresource_layers()
+                    },
+                    src_offsets: [[0; 3], src_size.width_height_depth()],
+                    dst_subresource: ImageSubresourceLayers {
+                        mip_level: level,
+                        ..image.subresource_layers()
+                    },
+                    dst_offsets: [[0; 3], dst_size.width_height_depth()],
+                    ..Default::default()
+                }]
+                .into(),
+                filter: Filter::Linear,
+                ..BlitImageInfo::images(image.clone(), image.clone())
+            })
+            .unwrap();
+    }
+    builder
+        .build()
+        .unwrap()
+        .execute(queue.clone())
+        .unwrap()
+        .then_signal_fence_and_flush()
+        .unwrap()
+        .wait(None)
+        .unwrap();
+
+    cube_view(image)
+}
+
+// GGX prefiltered copy of the sky for reflections, mip 0 is the mirror and the last mip fully
+// rough, linear in perceptual roughness in between. Runs once per sky and blocks until done.
+// Without the compute pipeline it hands back the sky itself, box filtered mips are close enough.
+pub fn prefilter(
+    allocator: &StandardMemoryAllocator,
+    command_buffer_allocator: &StandardCommandBufferAllocator,
+    descriptor_set_allocator: &StandardDescriptorSetAllocator,
+    queue: &Arc<Queue>,
+    sky: Arc<ImageView<ImmutableImage>>,
+) -> Arc<ImageView<ImmutableImage>> {
+    let device = queue.device();
+    let pipeline = env_prefilter_shader::load(device.clone())
+        .map_err(|e| format!("{:?}", e))
+        .and_then(|shader| {
+            ComputePipeline::new(
+                device.clone(),
+                shader.entry_point("main").unwrap(),
+                &(),
+                None,
+                |_| {},
+            )
+            .map_err(|e| format!("{:?}", e))
+        });
+    let pipeline = match pipeline {
+        Ok(pipeline) => pipeline,
+        Err(e) => {
+            warn!("Failed to create the environment prefilter pipeline: {}", e);
+            return sky;
+        }
+    };
+
+    // Never upsample a small sky, and keep at least a texel on the roughest level
+    let size = PREFILTER_SIZE.min(sky.image().dimensions().width());
+    let levels = PREFILTER_LEVELS.min(size.ilog2() + 1);
+    let format = Format::R16G16B16A16_SFLOAT;
+    let (image, init) = ImmutableImage::uninitialized(
+        allocator,
+        ImageDimensions::Dim2d {
+            width: size,
+            height: size,
+            array_layers: 6,
+        },
+        format,
+        levels,
+        ImageUsage {
+            transfer_dst: true,
+            transfer_src: true,
+            sampled: true,
+            ..ImageUsage::empty()
+        },
+        ImageCreateFlags {
+            cube_compatible: true,
+            ..ImageCreateFlags::empty()
+        },
+        ImageLayout::ShaderReadOnlyOptimal,
+        [queue.queue_family_index()],
+    )
+    .unwrap();
+    let sampler = Sampler::new(device.clone(), SamplerCreateInfo::simple_repeat_linear()).unwrap();
+
+    let mut builder = AutoCommandBufferBuilder::primary(
+        command_buffer_allocator,
+        queue.queue_family_index(),
+        CommandBufferUsage::OneTimeSubmit,
+    )
+    .unwrap();
+    // Storage images have no mips, each level gets filtered on its own and copied over
+    for level in 0..levels {
+        let level_size = size >> level;
+        let target = StorageImage::with_usage(
+            allocator,
+            ImageDimensions::Dim2d {
+                width: level_size,
+                height: level_size,
+                array_layers: 6,
+            },
+            format,
+            ImageUsage {
+                storage: true,
+                transfer_src: true,
+                ..ImageUsage::empty()
+            },
+            ImageCreateFlags::empty(),
+            [queue.queue_family_index()],
+        )
+        .unwrap();
+        let set = PersistentDescriptorSet::new(
+            descriptor_set_allocator,
+            pipeline.layout().set_layouts()[0].clone(),
+            [
+                WriteDescriptorSet::image_view_sampler(0, sky.clone(), sampler.clone()),
+                WriteDescriptorSet::image_view(1, ImageView::new_default(target.clone()).unwrap()),
+            ],
+        )
+        .unwrap();
+        let roughness = if levels > 1 {
+            level as f32 / (levels - 1) as f32
+        } else {
+            0.0
+        };
+        builder
+            .bind_pipeline_compute(pipeline.clone())
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                pipeline.layout().clone(),
+                0,
+                set,
+            )
+            .push_constants(
+                pipeline.layout().clone(),
+                0,
+                env_prefilter_shader::ty::PushConstants {
+                    size: level_size,
+                    roughness,
+                },
+            )
+            .dispatch([level_size.div_ceil(8), level_size.div_ceil(8), 6])
+            .unwrap()
+            .copy_image(CopyImageInfo {
+                regions: [ImageCopy {
+                    src_subresource: target.subresource_layers(),
+                    dst_subresource: ImageSubresourceLayers {
+                        mip_level: level,
+                        ..init.subresource_layers()
+                    },
+                    extent: [level_size, level_size, 1],
+                    ..Default::default()
+                }]
+                .into(),
+                ..CopyImageInfo::images(target, init.clone())
+            })
+            .unwrap();
+    }
+    builder
+        .build()
+        .unwrap()
+        .execute(queue.clone())
+        .unwrap()
+        .then_signal_fence_and_flush()
+        .unwrap()
+        .wait(None)
+        .unwrap();
+
+    cube_view(image)
+}
+
+// One texel per face, the sky is the same color in every direction
+pub fn solid_cubemap(
+    allocator: &StandardMemoryAllocator,
+    command_buffer_allocator: &StandardCommandBufferAllocator,
+    queue: &Arc<Queue>,
+    color: [u8; 4],
+) -> Arc<ImageView<ImmutableImage>> {
+    create_cubemap(
+        allocator,
+        command_buffer_allocator,
+        queue,
+        1,
+        color.repeat(6),
+    )
+}
+
+// Faces are PNGs in the order +X, -X, +Y, -Y, +Z, -Z with +Y up, whatever the world up axis.
+// The color is taken as sRGB encoded, alpha is ignored.
+pub fn load_cubemap(
+    allocator: &StandardMemoryAllocator,
+    command_buffer_allocator: &StandardCommandBufferAllocator,
+    queue: &Arc<Queue>,
+    paths: &[PathBuf; 6],
+) -> Result<Arc<ImageView<ImmutableImage>>, SkyboxError> {
+    let mut size = None;
+    let mut texels = Vec::new();
+    for path in paths {
+        let (dimensions, face) = load_face(path)?;
+        if dimensions[0] != dimensions[1] || size.is_some_and(|size| size != dimensions[0]) {
+            return Err(SkyboxError::FaceSize {
+                path: path.clone(),
+                size: dimensions,
+            });
+        }
+        size = Some(dimensions[0]);
+        texels.extend_from_slice(&face);
+    }
+
+    Ok(create_cubemap(
+        allocator,
+        command_buffer_allocator,
+        queue,
+        size.unwrap(),
+        texels,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use vulkano::{buffer::BufferContents, command_buffer::CopyImageToBufferInfo};
+
+    use super::*;
+    use crate::gpu_context::{GpuContext, ValidationConfig};
+
+    fn f16_to_f32(bits: u16) -> f32 {
+        let exponent = (bits >> 10) & 0x1f;
+        let mantissa = (bits & 0x3ff) as f32 / 1024.0;
+        let magnitude = match exponent {
+            0 => mantissa * 2f32.powi(-14),
+            _ => (1.0 + mantissa) * 2f32.powi(exponent as i32 - 15),
+        };
+        if bits & 0x8000 != 0 {
+            -magnitude
+        } else {
+            magnitude
+        }
+    }
+
+    // A uniform sky reflects the same at any roughness, whatever the filter does
+    #[test]
+    fn prefiltered_solid_sky_stays_uniform() {
+        let context = match GpuContext::headless(ValidationConfig::default()) {
+            Ok(context) => context,
+            Err(e) => {
+                eprintln!("Skipping, no Vulkan device: {}", e);
+                return;
+            }
+        };
+        let descriptor_set_allocator = StandardDescriptorSetAllocator::new(context.device.clone());
+        let size = 16;
+        let sky = create_cubemap(
+            &context.memory_allocator,
+            &context.command_buffer_allocator,
+            &context.queue,
+            size,
+            DEFAULT_SKY_COLOR.repeat(6 * (size * size) as usize),
+        );
+        let environment = prefilter(
+            &context.memory_allocator,
+            &context.command_buffer_allocator,
+            &descriptor_set_allocator,
+            &context.queue,
+            sky,
+        );
+        let image = environment.image();
+        assert_eq!(image.mip_levels(), 5);
+
+        let mut builder = AutoCommandBufferBuilder::primary(
+            &context.command_buffer_allocator,
+            context.queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+        let readbacks: Vec<_> = (0..image.mip_levels())
+            .map(|level| {
+                let level_size = (size >> level) as usize;
+                let readback = CpuAccessibleBuffer::from_iter(
+                    &context.memory_allocator,
+                    BufferUsage {
+                        transfer_dst: true,
+                        ..BufferUsage::empty()
+                    },
+                    false,
+                    (0..6 * level_size * level_size).map(|_| [0u16; 4]),
+                )
+                .unwrap();
+                let mut copy = CopyImageToBufferInfo::image_buffer(image.clone(), readback.clone());
+                copy.regions[0].image_subresource.mip_level = level;
+                copy.regions[0].image_extent = [level_size as u32, level_size as u32, 1];
+                builder.copy_image_to_buffer(copy).unwrap();
+                readback
+            })
+            .collect();
+        builder
+            .build()
+            .unwrap()
+            .execute(context.queue.clone())
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+
+        // sRGB decoded on sampling, the levels hold linear color
+        let expected = DEFAULT_SKY_COLOR.map(|c| {
+            let c = c as f32 / 255.0;
+            if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            }
+        });
+        for (level, readback) in readbacks.iter().enumerate() {
+            for texel in readback.read().unwrap().as_bytes().chunks_exact(8) {
+                for channel in 0..3 {
+                    let bits = u16::from_ne_bytes([texel[2 * channel], texel[2 * channel + 1]]);
+                    let value = f16_to_f32(bits);
+                    assert!(
+                        (value - expected[channel]).abs() < 1e-2,
+                        "level {} channel {}: {} vs {}",
+                        level,
+                        channel,
+                        value,
+                        expected[channel]
+                    );
+                }
+            }
+        }
+    }
+}
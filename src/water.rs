@@ -1,9 +1,38 @@
-use nalgebra_glm::Vec3;
+use std::{collections::HashMap, sync::Arc};
 
-use crate::instance::{Instance, Mesh};
+use nalgebra_glm::{self as glm, Vec2, Vec3, Vec4};
+
+use crate::{
+    axis::UpAxis,
+    instance::{Instance, Mesh, MeshTopology, PRIMITIVE_RESTART_INDEX, Vertex},
+};
+
+// Edge length of a water tile in meters, the grid spans -0.5..0.5 and gets scaled up to it
+pub const TILE_SIZE: f32 = 200.0;
+// Grid resolution per ring of tiles around the camera, the last one covers all rings further
+// out. Halving each ring keeps every other edge vertex shared with the coarser neighbour.
+pub const LOD_RESOLUTIONS: [u32; 5] = [1024, 512, 256, 128, 64];
+// Rings of tiles around the one under the camera
+pub const DEFAULT_WATER_RINGS: u32 = 3;
+// How far the skirt along every tile edge hangs down, in meters. Neighbours with different
+// resolutions only share every other edge vertex, the skirt covers the cracks in between.
+const SKIRT_DEPTH: f32 = 1.0;
+// Patch meshes have this many times fewer quads along an edge, the tessellator splits them
+// back up where the camera is close (see water.tesc)
+const PATCH_RESOLUTION_DIVISOR: u32 = 16;
+// Meters added around the flat tile on every side for frustum culling, room for tall crests
+// and their sideways push so they don't get cut off at the edge of the screen
+const CULL_PADDING: f32 = 30.0;
+// Most a tile's map sampling gets turned (radians) and shifted (meters) either way, so the same
+// waves don't repeat tile after tile. Along the tile edges the shader cross-fades back to the
+// unmoved samples, so neighbours meet without a seam.
+const UV_JITTER_ANGLE: f32 = 0.05;
+const UV_JITTER_OFFSET: f32 = 10.0;
+// Same as in water_surface.glsl
+const UV_TRANSFORM_FADE: f32 = 0.15;
 
 // Make sure res is power of 2 for best results
-fn create_grid_mesh(res: u32) -> Mesh {
+fn create_grid_mesh(res: u32, up_axis: UpAxis, topology: MeshTopology) -> Mesh {
     let mut vertices = Vec::new();
     let mut indices = Vec::new();
 
@@ -11,13 +40,28 @@ fn create_grid_mesh(res: u32) -> Mesh {
         for x in 0..=res {
             let u = x as f32 / res as f32;
             let v = z as f32 / res as f32;
-            vertices.push(crate::instance::Vertex {
-                position: [u - 0.5, 0.0, v - 0.5], // -0.5 to 0.5
+            let position = up_axis.to_world(Vec3::new(u - 0.5, 0.0, v - 0.5)); // -0.5 to 0.5
+            vertices.push(Vertex {
+                position: position.into(),
                 uv: [u, v],
             });
         }
     }
 
+    match topology {
+        MeshTopology::TriangleList | MeshTopology::Patches => push_list_indices(&mut indices, res),
+        MeshTopology::TriangleStrip => push_strip_indices(&mut indices, res),
+    }
+    push_skirts(&mut vertices, &mut indices, res, up_axis, topology);
+
+    Mesh {
+        vertices,
+        indices,
+        topology,
+    }
+}
+
+fn push_list_indices(indices: &mut Vec<u32>, res: u32) {
     for z in 0..res {
         for x in 0..res {
             let top_left = z * (res + 1) + x;
@@ -36,33 +80,308 @@ fn create_grid_mesh(res: u32) -> Mesh {
             indices.push(bottom_right);
         }
     }
+}
 
-    Mesh { vertices, indices }
+// One strip per row zigzagging top/bottom, which gives the same winding as the list.
+// Rows are split with a restart index instead of degenerate triangles.
+fn push_strip_indices(indices: &mut Vec<u32>, res: u32) {
+    for z in 0..res {
+        if z > 0 {
+            indices.push(PRIMITIVE_RESTART_INDEX);
+        }
+        for x in 0..=res {
+            indices.push(z * (res + 1) + x);
+            indices.push((z + 1) * (res + 1) + x);
+        }
+    }
 }
 
-fn create_instance(pos: Vec3) -> Instance {
+// Copies of the border vertices SKIRT_DEPTH further down, joined to the border. They share
+// the uv of the vertex above so both get displaced the same way.
+fn push_skirts(
+    vertices: &mut Vec<Vertex>,
+    indices: &mut Vec<u32>,
+    res: u32,
+    up_axis: UpAxis,
+    topology: MeshTopology,
+) {
+    let row = res + 1;
+    let edges: [Vec<u32>; 4] = [
+        (0..row).collect(),
+        (0..row).map(|x| res * row + x).collect(),
+        (0..row).map(|z| z * row).collect(),
+        (0..row).map(|z| z * row + res).collect(),
+    ];
+
+    for edge in edges {
+        let first_skirt = vertices.len() as u32;
+        for &i in &edge {
+            let uv = vertices[i as usize].uv;
+            let position = up_axis.to_world(Vec3::new(uv[0] - 0.5, -SKIRT_DEPTH, uv[1] - 0.5));
+            vertices.push(Vertex {
+                position: position.into(),
+                uv,
+            });
+        }
+
+        match topology {
+            MeshTopology::TriangleList | MeshTopology::Patches => {
+                for k in 0..res {
+                    let (top_a, top_b) = (edge[k as usize], edge[k as usize + 1]);
+                    let (skirt_a, skirt_b) = (first_skirt + k, first_skirt + k + 1);
+                    indices.extend_from_slice(&[top_a, skirt_a, top_b, top_b, skirt_a, skirt_b]);
+                }
+            }
+            MeshTopology::TriangleStrip => {
+                indices.push(PRIMITIVE_RESTART_INDEX);
+                for (k, &top) in edge.iter().enumerate() {
+                    indices.push(top);
+                    indices.push(first_skirt + k as u32);
+                }
+            }
+        }
+    }
+}
+
+fn create_instance(pos: Vec3, up_axis: UpAxis, uv_transform: [[f32; 4]; 2]) -> Instance {
     let translation = nalgebra_glm::translation(&pos);
-    let scale = nalgebra_glm::scaling(&Vec3::new(200.0, 1.0, 200.0));
+    let size = Vec3::new(TILE_SIZE, 1.0, TILE_SIZE);
+    let scale = nalgebra_glm::scaling(&glm::abs(&up_axis.to_world(size)));
     let model = translation * scale;
     let normal = nalgebra_glm::inverse_transpose(scale);
     Instance {
         instance_normal: normal.into(),
         instance_model: model.into(),
+        uv_transform,
+    }
+}
+
+// Same tile, same jitter, so tiles don't change when they get rebuilt around the camera
+fn tile_uv_transform(tile: [i32; 2]) -> [[f32; 4]; 2] {
+    let seed =
+        (tile[0] as u32).wrapping_mul(0x9e3779b1) ^ (tile[1] as u32).wrapping_mul(0x85ebca77);
+    let random = |salt: u32| hash(seed ^ salt) as f32 / u32::MAX as f32 * 2.0 - 1.0;
+    let (sin, cos) = (random(1) * UV_JITTER_ANGLE).sin_cos();
+    let offset = [random(2) * UV_JITTER_OFFSET, random(3) * UV_JITTER_OFFSET];
+    let center = [tile[0] as f32 * TILE_SIZE, tile[1] as f32 * TILE_SIZE];
+    [
+        [cos, -sin, offset[0], center[0]],
+        [sin, cos, offset[1], center[1]],
+    ]
+}
+
+// Integer hash (lowbias32)
+fn hash(mut h: u32) -> u32 {
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846ca68b);
+    h ^= h >> 16;
+    h
+}
+
+// Frustum planes (xyz = normal, w = distance) of a view-projection with 0..1 depth,
+// a point is inside where dot(normal, p) + w >= 0 for all six
+fn frustum_planes(view_proj: &[[f32; 4]; 4]) -> [Vec4; 6] {
+    let m = glm::Mat4::from(*view_proj);
+    let row = |i| m.row(i).transpose();
+    [
+        row(3) + row(0),
+        row(3) - row(0),
+        row(3) + row(1),
+        row(3) - row(1),
+        row(2),
+        row(3) - row(2),
+    ]
+}
+
+// World space center and half extents of a tile, padded by CULL_PADDING
+fn tile_bounds(instance: &Instance) -> (Vec3, Vec3) {
+    let model = glm::Mat4::from(instance.instance_model);
+    let center = model.column(3).xyz();
+    let half_extents = glm::abs(&glm::mat4_to_mat3(&model)) * Vec3::repeat(0.5);
+    (center, half_extents.add_scalar(CULL_PADDING))
+}
+
+// Where the water over a point of the simulation plane samples the maps (simulation meters),
+// the CPU side of displaceSurface in water_surface.glsl. The surface there is the plain sample
+// cross-faded to the tile's jittered one by blend.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MapSamples {
+    pub plain: Vec2,
+    pub jittered: Vec2,
+    pub blend: f32,
+}
+
+impl MapSamples {
+    // The one that contributes most, for things that need a single spot like a ripple
+    pub fn dominant(&self) -> Vec2 {
+        if self.blend < 0.5 {
+            self.plain
+        } else {
+            self.jittered
+        }
+    }
+}
+
+// p is on the simulation plane in world meters, tiles are laid out in those. world_uv_scale as
+// in Renderer::set_world_uv_scale.
+pub fn map_samples(p: Vec2, world_uv_scale: f32) -> MapSamples {
+    let tile = [
+        (p.x / TILE_SIZE).round() as i32,
+        (p.y / TILE_SIZE).round() as i32,
+    ];
+    let [row_x, row_z] = tile_uv_transform(tile);
+    let center = glm::vec2(row_x[3], row_z[3]);
+    let uv = (p - center) / TILE_SIZE + glm::vec2(0.5, 0.5);
+
+    let plain = p * world_uv_scale;
+    let local = plain - center * world_uv_scale;
+    let jittered = center * world_uv_scale
+        + glm::vec2(
+            row_x[0] * local.x + row_x[1] * local.y + row_x[2],
+            row_z[0] * local.x + row_z[1] * local.y + row_z[2],
+        );
+
+    let edge = uv.x.min(1.0 - uv.x).min(uv.y).min(1.0 - uv.y);
+    MapSamples {
+        plain,
+        jittered,
+        blend: glm::smoothstep(0.0, UV_TRANSFORM_FADE, edge),
     }
 }
 
+// Deterministic per body animation offset in seconds, the same id always gets the same offset
+pub fn phase_offset_for_body(id: u32) -> f32 {
+    // Mapped to 0..1000 seconds
+    (hash(id) as f32 / u32::MAX as f32) * 1000.0
+}
+
 pub struct Water {
-    pub instances: Vec<Instance>,
-    pub mesh: Mesh,
+    pub phase_offset: f32,
+    up_axis: UpAxis,
+    topology: MeshTopology,
+    // Grid meshes by resolution, every tile of a LOD level shares one
+    meshes: HashMap<u32, Arc<Mesh>>,
 }
 
 impl Water {
-    pub fn new() -> Self {
-        let mesh = create_grid_mesh(2048);
+    pub fn new(up_axis: UpAxis, topology: MeshTopology) -> Self {
+        Water {
+            phase_offset: phase_offset_for_body(0),
+            up_axis,
+            topology,
+            meshes: HashMap::new(),
+        }
+    }
+
+    // Grid of res x res quads plus edge skirts, built on first use and shared with later calls.
+    // Patch meshes are coarser by PATCH_RESOLUTION_DIVISOR, the tessellator makes up the rest.
+    pub fn get_mesh_for_res(&mut self, res: u32) -> Arc<Mesh> {
+        let (up_axis, topology) = (self.up_axis, self.topology);
+        let grid_res = match topology {
+            MeshTopology::Patches => (res / PATCH_RESOLUTION_DIVISOR).max(1),
+            _ => res,
+        };
+        self.meshes
+            .entry(res)
+            .or_insert_with(|| Arc::new(create_grid_mesh(grid_res, up_axis, topology)))
+            .clone()
+    }
+
+    // Drops the cached meshes, the next get_mesh_for_res builds them again.
+    // Meshes handed out before stay alive as long as someone holds them.
+    pub fn clear_mesh_cache(&mut self) {
+        self.meshes.clear();
+    }
+
+    // Tile under a world position, counted in whole tiles from the one centered on the origin
+    pub fn tile_at(&self, position: Vec3) -> [i32; 2] {
+        let p = self.up_axis.to_sim(position) / TILE_SIZE;
+        [p.x.round() as i32, p.z.round() as i32]
+    }
+
+    // The (2 * rings + 1)^2 tiles centered on the camera's tile, each with the resolution of its
+    // ring from LOD_RESOLUTIONS. Grouped by resolution so every group is one instanced draw.
+    // Only changes when the camera moves onto another tile (see tile_at).
+    pub fn tiles_around(&self, camera_pos: Vec3, rings: u32) -> Vec<(u32, Vec<Instance>)> {
+        let [center_x, center_z] = self.tile_at(camera_pos);
+        let rings = rings as i32;
+
+        let mut lods: Vec<(u32, Vec<Instance>)> = Vec::new();
+        for dz in -rings..=rings {
+            for dx in -rings..=rings {
+                let ring = dx.abs().max(dz.abs()) as usize;
+                let res = LOD_RESOLUTIONS[ring.min(LOD_RESOLUTIONS.len() - 1)];
+                let center = Vec3::new(
+                    (center_x + dx) as f32 * TILE_SIZE,
+                    0.0,
+                    (center_z + dz) as f32 * TILE_SIZE,
+                );
+                let instance = create_instance(
+                    self.up_axis.to_world(center),
+                    self.up_axis,
+                    tile_uv_transform([center_x + dx, center_z + dz]),
+                );
+                match lods.iter_mut().find(|(lod_res, _)| *lod_res == res) {
+                    Some((_, instances)) => instances.push(instance),
+                    None => lods.push((res, vec![instance])),
+                }
+            }
+        }
+        lods
+    }
+
+    // The instances whose tile is at least partly inside the frustum of view_proj (projection
+    // times view, as the camera makes them). Tiles are padded so waves near the edge stay.
+    pub fn visible_instances(
+        &self,
+        instances: &[Instance],
+        view_proj: &[[f32; 4]; 4],
+    ) -> Vec<Instance> {
+        let planes = frustum_planes(view_proj);
+        instances
+            .iter()
+            .filter(|instance| {
+                let (center, half_extents) = tile_bounds(instance);
+                // Corner of the box furthest along the normal, outside if even that is behind
+                planes.iter().all(|plane| {
+                    let normal = plane.xyz();
+                    normal.dot(&center) + glm::abs(&normal).dot(&half_extents) + plane.w >= 0.0
+                })
+            })
+            .copied()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tile_edges_sample_without_jitter() {
+        // Just either side of the edge between tiles 0 and 1 along x
+        let edge = TILE_SIZE * 0.5;
+        for p in [glm::vec2(edge - 1e-3, 37.0), glm::vec2(edge + 1e-3, 37.0)] {
+            let samples = map_samples(p, 1.0);
+            assert!(samples.blend < 1e-4, "blend {} at {:?}", samples.blend, p);
+            assert_eq!(samples.dominant(), p);
+        }
+
+        // The tile's middle is all jittered
+        let middle = map_samples(glm::vec2(TILE_SIZE, 0.0), 1.0);
+        assert_eq!(middle.blend, 1.0);
+        assert_eq!(middle.dominant(), middle.jittered);
+    }
 
-        let mut instances = Vec::new();
-        instances.push(create_instance(Vec3::new(0.0, 0.0, 0.0)));
+    #[test]
+    fn cleared_mesh_cache_builds_a_new_mesh() {
+        let mut water = Water::new(UpAxis::Y, MeshTopology::TriangleStrip);
+        let mesh = water.get_mesh_for_res(8);
+        assert!(Arc::ptr_eq(&mesh, &water.get_mesh_for_res(8)));
 
-        Water { instances, mesh }
+        water.clear_mesh_cache();
+        assert!(!Arc::ptr_eq(&mesh, &water.get_mesh_for_res(8)));
     }
 }